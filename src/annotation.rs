@@ -0,0 +1,382 @@
+//! Bookmarks and highlights readers leave in chapters, kept in their own table keyed by id and
+//! indexed by user
+
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    db::{index_key, Backend, Table},
+    entry::{Chapter, Entry},
+    ruby::plain_text,
+    utils::{decode_bincode, encode_bincode},
+};
+
+const ANNOTATION_TABLE: &str = "ANNOTATIONS";
+/// Keys of [`index_key`] of the user and annotation id
+const USER_ANNOTATION_TABLE: &str = "USER_ANNOTATIONS";
+
+/// Characters kept before a passage to tell repeated passages apart
+const PREFIX_LEN: usize = 16;
+/// Characters kept after a bookmark to find its place again
+const BOOKMARK_QUOTE_LEN: usize = 32;
+pub const NOTE_MAX_LEN: usize = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnnotationKind {
+    /// A place in the chapter
+    Bookmark,
+    /// A passage of the chapter
+    Highlight,
+}
+
+/// A range of characters within a paragraph, counted in the text as it is read (without ruby
+/// readings). Bookmarks are empty ranges
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct Anchor {
+    /// Index into the chapter's elements
+    pub paragraph: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Clone, Encode, Decode)]
+pub struct Annotation {
+    #[bincode(with_serde)]
+    pub user: Uuid,
+    #[bincode(with_serde)]
+    pub work: Uuid,
+    #[bincode(with_serde)]
+    pub chapter: Uuid,
+    pub kind: AnnotationKind,
+    pub anchor: Anchor,
+    /// Highlighted text, or the text right after a bookmark. Used to find the place again after
+    /// the chapter is edited
+    pub quote: String,
+    /// Text right before the quote
+    pub prefix: String,
+    pub note: Option<String>,
+    /// Revision of the chapter `anchor` points into
+    pub revision: u32,
+    /// The passage couldn't be found after an edit
+    pub orphaned: bool,
+    pub created: SystemTime,
+}
+
+impl Annotation {
+    /// Anchors a new annotation to the chapter as it is now
+    pub fn new(
+        user: Uuid,
+        work: Uuid,
+        chapter: &Chapter,
+        kind: AnnotationKind,
+        anchor: Anchor,
+        note: Option<String>,
+    ) -> Result<Self> {
+        let Some(text) = paragraphs(chapter).into_iter().nth(anchor.paragraph) else {
+            bail!("That paragraph doesn't exist");
+        };
+        let chars: Vec<char> = text.chars().collect();
+        let Anchor {
+            mut start, mut end, ..
+        } = anchor;
+        match kind {
+            AnnotationKind::Bookmark => {
+                start = start.min(chars.len());
+                end = start;
+            }
+            AnnotationKind::Highlight => {
+                if start >= end || end > chars.len() {
+                    bail!("Select the passage to highlight");
+                }
+            }
+        }
+        if note
+            .as_ref()
+            .is_some_and(|n| n.chars().count() > NOTE_MAX_LEN)
+        {
+            bail!("Notes can't be longer than {NOTE_MAX_LEN} characters");
+        }
+
+        let quote_end = match kind {
+            AnnotationKind::Bookmark => (start + BOOKMARK_QUOTE_LEN).min(chars.len()),
+            AnnotationKind::Highlight => end,
+        };
+        Ok(Self {
+            user,
+            work,
+            chapter: chapter.id,
+            kind,
+            anchor: Anchor {
+                paragraph: anchor.paragraph,
+                start,
+                end,
+            },
+            quote: chars[start..quote_end].iter().collect(),
+            prefix: chars[start.saturating_sub(PREFIX_LEN)..start]
+                .iter()
+                .collect(),
+            note,
+            revision: chapter.revision,
+            orphaned: false,
+            created: SystemTime::now(),
+        })
+    }
+
+    /// Finds the passage again in a newer revision of the chapter. Returns whether anything changed
+    pub fn reanchor(&mut self, chapter: &Chapter) -> bool {
+        if self.revision == chapter.revision {
+            return false;
+        }
+        self.revision = chapter.revision;
+        match find(&paragraphs(chapter), self) {
+            Some(found) => {
+                let len = self.anchor.end - self.anchor.start;
+                self.anchor = Anchor {
+                    end: found.start + len,
+                    ..found
+                };
+                self.orphaned = false;
+            }
+            None => self.orphaned = true,
+        }
+        true
+    }
+}
+
+/// Text of each element of a chapter as it is read. Images have none
+pub fn paragraphs(chapter: &Chapter) -> Vec<String> {
+    chapter
+        .elements
+        .iter()
+        .map(|e| match e {
+            Entry::Paragraph(p) => plain_text(p),
+            Entry::Image(_) => String::new(),
+        })
+        .collect()
+}
+
+/// Where the annotation's quote is now. Occurrences that keep the prefix win, then the ones
+/// closest to where it used to be
+fn find(paragraphs: &[String], annotation: &Annotation) -> Option<Anchor> {
+    let old = annotation.anchor;
+    if annotation.quote.is_empty() {
+        // A bookmark at the end of a paragraph; stays put if the paragraph does
+        return (old.paragraph < paragraphs.len()).then(|| {
+            let len = paragraphs[old.paragraph].chars().count();
+            Anchor {
+                start: old.start.min(len),
+                end: old.start.min(len),
+                ..old
+            }
+        });
+    }
+
+    paragraphs
+        .iter()
+        .enumerate()
+        .flat_map(|(i, text)| {
+            text.match_indices(&annotation.quote).map(move |(byte, _)| {
+                let before = &text[..byte];
+                let start = before.chars().count();
+                let keeps_prefix = before.ends_with(&annotation.prefix);
+                (i, start, keeps_prefix)
+            })
+        })
+        .min_by_key(|&(i, start, keeps_prefix)| {
+            (
+                !keeps_prefix,
+                i.abs_diff(old.paragraph),
+                start.abs_diff(old.start),
+            )
+        })
+        .map(|(paragraph, start, _)| Anchor {
+            paragraph,
+            start,
+            end: start,
+        })
+}
+
+pub struct AnnotationStore<B: Backend> {
+    annotations: <B as Backend>::OutTable,
+    by_user: <B as Backend>::OutTable,
+}
+
+impl<B: Backend> AnnotationStore<B> {
+    pub fn new(db: &B) -> Result<Self> {
+        let annotations = db.get_table(ANNOTATION_TABLE)?;
+        let by_user = db.get_table(USER_ANNOTATION_TABLE)?;
+        Ok(Self {
+            annotations,
+            by_user,
+        })
+    }
+
+    pub fn add(&self, annotation: &Annotation) -> Result<Uuid> {
+        let id = Uuid::now_v7();
+        self.annotations.insert(id, encode_bincode(annotation)?);
+        self.by_user.insert(index_key(annotation.user, id), []);
+        Ok(id)
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Annotation> {
+        let data = self.annotations.get_value(id)?;
+        decode_bincode(data.as_ref()).ok()
+    }
+
+    pub fn update(&self, id: Uuid, annotation: &Annotation) -> Result<()> {
+        self.annotations.insert(id, encode_bincode(annotation)?);
+        Ok(())
+    }
+
+    pub fn remove(&self, id: Uuid) {
+        if let Some(annotation) = self.get(id) {
+            self.by_user.remove(index_key(annotation.user, id));
+        }
+        self.annotations.remove(id);
+    }
+
+    /// Every annotation of `user`, oldest first
+    pub fn for_user(&self, user: Uuid) -> Vec<(Uuid, Annotation)> {
+        self.by_user
+            .indexed(user)
+            .filter_map(|id| Some((id, self.get(id)?)))
+            .collect()
+    }
+
+    /// Annotations of `user` in `chapter`, moved to where their passages are now
+    pub fn in_chapter(&self, user: Uuid, chapter: &Chapter) -> Vec<(Uuid, Annotation)> {
+        let mut res: Vec<_> = self
+            .for_user(user)
+            .into_iter()
+            .filter(|(_, a)| a.chapter == chapter.id)
+            .collect();
+        for (id, annotation) in &mut res {
+            if annotation.reanchor(chapter) {
+                if let Err(e) = self.update(*id, annotation) {
+                    tracing::warn!("Could not save annotation {id}: {e}");
+                }
+            }
+        }
+        res.sort_by_key(|(_, a)| (a.anchor.paragraph, a.anchor.start));
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn store() {
+        let store = AnnotationStore::new(&crate::db::temporary()).unwrap();
+        let ch = chapter(&["一", "二", "三"]);
+        let other = chapter(&["一"]);
+        let (alice, bob) = (Uuid::now_v7(), Uuid::now_v7());
+        let bookmark = |user, chapter, paragraph| {
+            let anchor = Anchor {
+                paragraph,
+                start: 0,
+                end: 0,
+            };
+            let kind = AnnotationKind::Bookmark;
+            Annotation::new(user, Uuid::nil(), chapter, kind, anchor, None).unwrap()
+        };
+        let second = store.add(&bookmark(alice, &ch, 1)).unwrap();
+        let first = store.add(&bookmark(alice, &ch, 0)).unwrap();
+        store.add(&bookmark(alice, &other, 0)).unwrap();
+        store.add(&bookmark(bob, &ch, 2)).unwrap();
+
+        let ids = |list: Vec<(Uuid, Annotation)>| list.into_iter().map(|(id, _)| id).collect();
+        assert_eq!(store.for_user(alice).len(), 3);
+        // In reading order
+        let in_chapter: Vec<_> = ids(store.in_chapter(alice, &ch));
+        assert_eq!(in_chapter, [first, second]);
+
+        store.remove(first);
+        assert!(store.get(first).is_none());
+        let in_chapter: Vec<_> = ids(store.in_chapter(alice, &ch));
+        assert_eq!(in_chapter, [second]);
+        assert_eq!(store.for_user(bob).len(), 1);
+    }
+
+    fn chapter(paragraphs: &[&str]) -> Chapter {
+        let elements = paragraphs
+            .iter()
+            .map(|p| Entry::Paragraph(p.to_string()))
+            .collect();
+        Chapter::new(String::new(), elements)
+    }
+
+    #[test]
+    fn reanchor() {
+        let mut ch = chapter(&["吾輩は猫《ねこ》である。", "名前はまだ無い。猫である。"]);
+        let anchor = Anchor {
+            paragraph: 0,
+            start: 3,
+            end: 4,
+        };
+        let mut hl = Annotation::new(
+            Uuid::nil(),
+            Uuid::nil(),
+            &ch,
+            AnnotationKind::Highlight,
+            anchor,
+            None,
+        )
+        .unwrap();
+        // Offsets skip the reading
+        assert_eq!(hl.quote, "猫");
+
+        // A paragraph inserted before it; the prefix picks the right 猫
+        ch.elements.insert(0, Entry::Paragraph("序".to_string()));
+        ch.revision += 1;
+        assert!(hl.reanchor(&ch));
+        assert_eq!(
+            (hl.anchor, hl.orphaned),
+            (
+                Anchor {
+                    paragraph: 1,
+                    start: 3,
+                    end: 4
+                },
+                false
+            )
+        );
+        assert!(!hl.reanchor(&ch));
+
+        ch.elements = vec![Entry::Paragraph("犬である。".to_string())];
+        ch.revision += 1;
+        assert!(hl.reanchor(&ch));
+        assert!(hl.orphaned);
+
+        let anchor = Anchor {
+            paragraph: 0,
+            start: 4,
+            end: 0,
+        };
+        assert!(Annotation::new(
+            Uuid::nil(),
+            Uuid::nil(),
+            &ch,
+            AnnotationKind::Highlight,
+            anchor,
+            None
+        )
+        .is_err());
+        let bm = Annotation::new(
+            Uuid::nil(),
+            Uuid::nil(),
+            &ch,
+            AnnotationKind::Bookmark,
+            anchor,
+            None,
+        )
+        .unwrap();
+        assert_eq!((bm.anchor.end, bm.quote.as_str()), (4, "。"));
+    }
+}
@@ -1,11 +1,42 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
 use anyhow::{bail, Result};
-use minijinja::{context, Environment, Value};
+use chrono::Days;
+use minijinja::{context, AutoEscape, Environment, Value};
 use uuid::Uuid;
 
 use crate::{
-    db::Backend, library::Library, params, user::MemberCollection, utils::b64_encode_uuid,
+    annotation::AnnotationStore,
+    comment::CommentStore,
+    config::Config,
+    cookies::CookieSettings,
+    csrf,
+    db::Backend,
+    entry::{Chapter, ChapterAccess, Entry, LiteraryWork, WritingMode},
+    epub,
+    error::LibraryError,
+    export, import,
+    library::Library,
+    mail::{self, Mailer},
+    notification::NotificationStore,
+    page_header, params,
+    payment::{self, PaymentProvider},
+    render,
+    review::ReviewStore,
+    shop::{self, OrderStore},
+    stats::{Metric, StatsStore},
+    throttle::Throttle,
+    user::{Capability, Login, MemberCollection, ReaderSettings, UserRef},
+    utils::{b64_encode_uuid, url_encode_segment},
+    validate,
 };
 
+/// Paragraphs shown of a chapter the reader can't open yet
+const TEASER_PARAGRAPHS: usize = 3;
+
 /// Application State:
 /// - User data
 ///   - Users
@@ -16,36 +47,113 @@ use crate::{
 ///   - Statistics (word count, people reading it, rating)
 ///   - Books releasing soon
 pub struct Application<B: Backend> {
-    pub db: B,
     /// Contains library data
     pub lib: Library<B>,
     /// User data
     pub members: MemberCollection<B>,
+    /// Bookmarks and highlights
+    pub annotations: AnnotationStore<B>,
+    /// Ratings and reviews of works
+    pub reviews: ReviewStore<B>,
+    pub comments: CommentStore<B>,
+    /// Follows and the inboxes they fill
+    pub notifications: NotificationStore<B>,
+    /// Every purchase, paid or not
+    pub orders: OrderStore<B>,
+    /// Views of works and chapters
+    pub stats: StatsStore<B>,
+    pub config: Config,
+    pub cookies: CookieSettings,
+    pub(crate) mailer: Box<dyn Mailer>,
+    /// `None` until a provider is set up
+    pub(crate) payments: Option<Box<dyn PaymentProvider>>,
 
-    env: Environment<'static>,
+    /// Failed logins per account
+    account_logins: Throttle,
+    /// Failed logins per IP address
+    ip_logins: Throttle,
+    /// Comments posted per user
+    pub(crate) comment_posts: Throttle,
+    /// Password reset mails asked for per IP address
+    pub(crate) reset_requests: Throttle,
+
+    pub(crate) env: Environment<'static>,
 }
 
 impl<B: Backend> Application<B> {
     /// Opens the db at `uri`
-    pub fn new(p: std::path::PathBuf) -> Result<Self> {
-        let db = B::open(p)?;
+    pub fn new(p: std::path::PathBuf, config: Config) -> Result<Self> {
+        Self::with_db(B::open(p)?, config)
+    }
+
+    fn with_db(db: B, config: Config) -> Result<Self> {
         let lib = Library::new(&db)?;
-        let members = MemberCollection::new(&db)?;
+        let members = MemberCollection::new(&db, &config)?;
+        let annotations = AnnotationStore::new(&db)?;
+        let reviews = ReviewStore::new(&db)?;
+        let comments = CommentStore::new(&db)?;
+        let notifications = NotificationStore::new(&db)?;
+        let orders = OrderStore::new(&db)?;
+        let stats = StatsStore::new(&db)?;
         let mut env = Environment::new();
         env.set_loader(minijinja::path_loader("templates"));
+        // Works and forms contain user-written text
+        env.set_auto_escape_callback(|_| AutoEscape::Html);
+        env.add_function("csrf_token", csrf::token);
+        env.add_function("header", page_header::header);
+
+        let window = Duration::from_secs(config.accounts.login_window_minutes * 60);
+        let account_logins = Throttle::new(config.accounts.login_attempts, window);
+        let ip_logins = Throttle::new(config.accounts.ip_login_attempts, window);
+        let comment_posts = Throttle::new(
+            config.comments.posts,
+            Duration::from_secs(config.comments.window_minutes * 60),
+        );
+        let reset_requests = Throttle::new(
+            config.accounts.ip_reset_requests,
+            Duration::from_secs(config.accounts.reset_window_minutes * 60),
+        );
+        let cookies = CookieSettings::new(&config)?;
+        let mailer = mail::from_config(&config.mail)?;
+        let payments = payment::from_config(config.shop.payments.as_ref());
 
         Ok(Self {
-            db,
             lib,
             members,
+            annotations,
+            reviews,
+            comments,
+            notifications,
+            orders,
+            stats,
+            config,
+            cookies,
+            mailer,
+            payments,
+            account_logins,
+            ip_logins,
+            comment_posts,
+            reset_requests,
             env,
         })
     }
 
+    /// Id of the signed in user
+    pub fn signed_in(&self, sid: Option<Uuid>) -> Option<Uuid> {
+        sid.and_then(|sid| self.members.get_user_for_sid(sid))
+    }
+
     /// Render the homepage
-    pub fn home(&self) -> Result<String> {
+    pub fn home(&self, query: params::HomeQuery) -> Result<String> {
         let template = self.env.get_template("home.jinja")?;
-        let all_works = self.lib.all_works();
+        let all_works = self.lib.all_works_sorted_by(
+            |(_, work)| {
+                let minutes = work.count.reading_minutes();
+                query.min_minutes.is_none_or(|min| minutes >= min)
+                    && query.max_minutes.is_none_or(|max| minutes <= max)
+            },
+            query.sort,
+        );
 
         // // Homepage shown to everyone
         // // Shows recent books, top-rated books, etc.
@@ -62,37 +170,145 @@ impl<B: Backend> Application<B> {
         let iter = all_works.into_iter().map(|(id, work)| {
             let b64_id = b64_encode_uuid(id.as_bytes());
             let title = work.title;
-            context! { title, uuid => b64_id }
+            let minutes = work.count.reading_minutes();
+            context! { title, uuid => b64_id, chars => work.count.chars, minutes }
         });
         let templ_works = Value::from_iter(iter);
 
         // By now, all the data should have been fetched, and so we can render the template
-        let render = template.render(context! { collection => templ_works })?;
+        let render = template.render(context! {
+            collection => templ_works,
+            sort => query.sort,
+            min_minutes => query.min_minutes,
+            max_minutes => query.max_minutes,
+        })?;
         Ok(render)
     }
 
-    pub fn work(&self, params: params::LiteraryWorkParams) -> Result<String> {
+    pub fn work(
+        &self,
+        params: params::LiteraryWorkParams,
+        query: params::ReviewQuery,
+        sid: Option<Uuid>,
+    ) -> Result<String> {
         let template = self.env.get_template("work.jinja")?;
         let work = self.lib.get_work(params.id)?;
+        let user = self.signed_in(sid);
+        let can_edit = user.is_some_and(|user| work.is_creator(user));
+        if !can_edit {
+            self.stats.record(Metric::Views, params.id, None, 1);
+        }
+        let following = user
+            .filter(|_| !can_edit)
+            .map(|user| self.notifications.is_following(user, params.id));
+        let shelves = user.map(|user| self.work_shelves(user, params.id));
+        let can_trash = user
+            .and_then(|user| self.members.get_role(user).ok())
+            .is_some_and(|role| role.can(Capability::ManageTrash));
+        let creators = self.creators(&work);
+        let reviews = self.work_reviews(params.id, &work, user, query.reviews);
+        let lib = user.and_then(|user| self.members.get_library(user).ok());
+        let progress = lib.as_ref().and_then(|lib| lib.progress(params.id));
+        // Only shown to signed in users, who can keep track
+        let is_read = |chapter: Uuid| {
+            lib.is_some()
+                .then(|| progress.is_some_and(|p| p.read_chapters.contains(&chapter)))
+        };
+        let unread = lib.is_some().then(|| {
+            work.chapters
+                .iter()
+                .filter(|c| is_read(c.id) == Some(false))
+                .count()
+        });
+        // Free works have nothing to buy. Creators can't buy their own
+        let purchase = work.price.map(|price| {
+            let owned = lib
+                .as_ref()
+                .is_some_and(|lib| lib.works.contains(&params.id));
+            let in_cart = sid
+                .filter(|_| user.is_some())
+                .is_some_and(|sid| self.members.sessions.cart(sid).contains(&params.id));
+            context! {
+                price => shop::format_price(price.into(), &self.config.shop.currency),
+                owned,
+                in_cart,
+                can_buy => user.is_some() && !can_edit && !owned && !in_cart,
+                signed_in => user.is_some(),
+            }
+        });
+        let resume = progress
+            .and_then(|p| p.last_chapter)
+            .and_then(|last| work.chapters.iter().position(|c| c.id == last));
+
+        let now = SystemTime::now();
+        let campaign = work
+            .free_until
+            .filter(|&end| end > now)
+            .map(|end| (export::time(end) - Days::new(1)).date_naive());
+        let locked: Vec<_> = work
+            .chapters
+            .iter()
+            .map(|c| {
+                let access = work.access_to(c, now);
+                (!self.can_read(user, params.id, &work, access)).then_some(access)
+            })
+            .collect();
 
         // Now, get all the chapters
         let iter = work.chapters.into_iter().enumerate().map(|(id, chapter)| {
             context! {
                 id,
+                locked => locked[id],
+                read => is_read(chapter.id),
                 title => chapter.title,
-                date => chapter.date.date_naive()
+                date => chapter.date.date_naive(),
+                chars => chapter.count.chars,
+                minutes => chapter.count.reading_minutes(),
             }
         });
         let chapters = Value::from_iter(iter);
 
         let uuid = b64_encode_uuid(params.id.as_bytes());
         // By now, all the data should have been fetched, and so we can render the template
-        let render = template.render(context! { uuid, title => work.title, description => work.description, creators => work.creators, chapters => chapters })?;
+        let render = template.render(context! {
+            uuid,
+            title => work.title,
+            description => work.description,
+            creators,
+            chapters => chapters,
+            chars => work.count.chars,
+            minutes => work.count.reading_minutes(),
+            can_edit,
+            can_trash,
+            following,
+            shelves,
+            unread,
+            resume,
+            purchase,
+            campaign,
+            reviews,
+        })?;
         Ok(render)
     }
 
-    /// Render the chapter of a work
-    pub fn chapter(&self, params: params::ChapterParams) -> Result<String> {
+    /// Creators of a work for templates. Those with an account link to their author page
+    pub(crate) fn creators(&self, work: &LiteraryWork) -> Value {
+        Value::from_iter(work.creators.iter().map(|c| {
+            let name = self.members.creator_name(c);
+            let url =
+                c.id.map(|_| format!("/authors/{}", url_encode_segment(&name)));
+            context! { name, url }
+        }))
+    }
+
+    /// Render the chapter of a work. The writing mode is picked from `query`, then the reader's
+    /// settings, then the work's default
+    pub fn chapter(
+        &self,
+        params: params::ChapterParams,
+        query: params::WritingModeQuery,
+        sid: Option<Uuid>,
+    ) -> Result<String> {
         let template = self.env.get_template("chapter.jinja")?;
         let mut work = self.lib.get_work(params.work_params.id)?;
 
@@ -100,46 +316,369 @@ impl<B: Backend> Application<B> {
         if params.chapter_id >= work.chapters.len() {
             bail!("Could not find chapter!");
         }
-        let chapter = work.chapters.remove(params.chapter_id);
+        let prev = params.chapter_id.checked_sub(1);
+        let next = Some(params.chapter_id + 1).filter(|&i| i < work.chapters.len());
+        let user = self.signed_in(sid);
+        let access = work.access_to(&work.chapters[params.chapter_id], SystemTime::now());
+        let locked = !self.can_read(user, params.work_params.id, &work, access);
+        let chapter_ids: Vec<_> = work.chapters.iter().map(|c| c.id).collect();
+        let mut chapter = work.chapters.remove(params.chapter_id);
+        if locked {
+            let teaser = self.chapter_teaser(params, &work, chapter, access, user, sid);
+            return Ok(template.render(context! { prev, next, ..teaser })?);
+        }
 
-        // TODO: Handle images
-        let iter = chapter.elements.into_iter().map(|e| match e {
-            crate::entry::Entry::Paragraph(p) => p,
-            crate::entry::Entry::Image(_) => todo!(),
-        });
-        let entries = Value::from_iter(iter);
+        let work_id = params.work_params.id;
+        // Creators look at their works too often for it to mean anything
+        let counted = !user.is_some_and(|user| work.is_creator(user));
+        if counted {
+            self.stats
+                .record(Metric::Views, work_id, Some(chapter.id), 1);
+            if let Some(user) = user {
+                self.stats.record_reader(work_id, user);
+            }
+        }
+        let annotations = user.map(|user| self.chapter_annotations(user, &chapter));
+        let comments = self.chapter_comments(&work, &chapter, user);
+        let reader_mode = user
+            .and_then(|user| self.members.get_settings(user).ok())
+            .and_then(|settings| settings.writing_mode);
+
+        // Returning to the last chapter scrolls back to where the reader stopped
+        let mut position = None;
+        if let Some(user) = user {
+            position = self
+                .members
+                .get_library(user)?
+                .progress(work_id)
+                .filter(|p| p.last_chapter == Some(chapter.id))
+                .map(|p| p.position);
+            self.update_progress(user, work_id, &chapter_ids, counted, |lib| {
+                lib.open_chapter(work_id, chapter.id)
+            })?;
+        }
+        let mode = query.mode.or(reader_mode).unwrap_or(work.writing_mode);
+        let entries = render_entries(std::mem::take(&mut chapter.elements), mode);
+
+        let uuid = b64_encode_uuid(params.work_params.id.as_bytes());
 
         // By now, all the data should have been fetched, and so we can render the template
-        let render = template.render(context! { work_title => params.work_params.title, chapter_title => chapter.title, entries })?;
+        let render = template.render(context! {
+            work_title => params.work_params.title,
+            uuid,
+            chapter_title => chapter.title,
+            entries,
+            vertical => mode == WritingMode::Vertical,
+            prev,
+            next,
+            signed_in => user.is_some(),
+            position,
+            chapter_id => params.chapter_id,
+            annotations,
+            comments,
+        })?;
         Ok(render)
     }
 
-    pub fn signup(&self) -> Result<String> {
-        let template = self.env.get_template("signup.jinja")?;
+    /// If `user` may read a chapter needing `access`. Creators can always read their works
+    pub(crate) fn can_read(
+        &self,
+        user: Option<Uuid>,
+        work_id: Uuid,
+        work: &LiteraryWork,
+        access: ChapterAccess,
+    ) -> bool {
+        match (access, user) {
+            (ChapterAccess::Free, _) => true,
+            (_, None) => false,
+            (ChapterAccess::Members, Some(_)) => true,
+            (ChapterAccess::Purchase, Some(user)) => {
+                work.is_creator(user)
+                    || self
+                        .members
+                        .get_library(user)
+                        .is_ok_and(|lib| lib.works.contains(&work_id))
+            }
+        }
+    }
+
+    /// Template context with the start of a chapter the user can't read yet, and what it takes
+    /// to read the rest
+    fn chapter_teaser(
+        &self,
+        params: params::ChapterParams,
+        work: &LiteraryWork,
+        chapter: Chapter,
+        access: ChapterAccess,
+        user: Option<Uuid>,
+        sid: Option<Uuid>,
+    ) -> Value {
+        let teaser: Vec<_> = chapter
+            .elements
+            .into_iter()
+            .filter(|e| matches!(e, Entry::Paragraph(_)))
+            .take(TEASER_PARAGRAPHS)
+            .collect();
+        let id = params.work_params.id;
+        let in_cart = sid
+            .filter(|_| user.is_some())
+            .is_some_and(|sid| self.members.sessions.cart(sid).contains(&id));
+        context! {
+            work_title => params.work_params.title,
+            uuid => b64_encode_uuid(id.as_bytes()),
+            chapter_title => chapter.title,
+            entries => render_entries(teaser, work.writing_mode),
+            vertical => work.writing_mode == WritingMode::Vertical,
+            chapter_id => params.chapter_id,
+            locked => context! {
+                purchase => access == ChapterAccess::Purchase,
+                signed_in => user.is_some(),
+                price => work.price.map(|p| shop::format_price(p.into(), &self.config.shop.currency)),
+                in_cart,
+            },
+        }
+    }
+
+    /// EPUB of a work, for users who own it
+    pub fn epub(
+        &self,
+        params: params::LiteraryWorkParams,
+        query: params::WritingModeQuery,
+        sid: Option<Uuid>,
+    ) -> Result<Vec<u8>> {
+        let owned = sid
+            .and_then(|sid| self.members.get_user_for_sid(sid))
+            .and_then(|user| self.members.get_library(user).ok())
+            .is_some_and(|lib| lib.works.contains(&params.id));
+        if !owned {
+            bail!(LibraryError::Forbidden(params.title));
+        }
+
+        let Ok(mut work) = self.lib.get_work(params.id) else {
+            bail!(LibraryError::Missing(params.title));
+        };
+        for c in &mut work.creators {
+            c.name = self.members.creator_name(c);
+        }
+        let mode = query.mode.unwrap_or(work.writing_mode);
+        epub::export(params.id, &work, mode)
+    }
+
+    /// Imports a book file into the library, returning the id and title of the new work. The
+    /// importer becomes its first creator, ahead of the authors named in the file. Imports from the
+    /// command line have no importer
+    pub fn import(
+        &self,
+        importer: Option<Uuid>,
+        file_name: &str,
+        data: &[u8],
+    ) -> Result<(Uuid, String)> {
+        let mut work = import::import(file_name, data)?;
+        if let Some(user) = importer {
+            let creator = UserRef {
+                id: Some(user),
+                name: self.members.get_name(user)?,
+                created: SystemTime::now(),
+            };
+            work.creators.insert(0, creator);
+        }
+        let title = work.title.clone();
+        Ok((self.lib.add_work(work)?, title))
+    }
+
+    pub fn import_page(&self) -> Result<String> {
+        let template = self.env.get_template("import.jinja")?;
         let render = template.render(context! {})?;
         Ok(render)
     }
 
-    pub fn login(&self) -> Result<String> {
+    /// Renders the signup form, keeping the name and showing why the last attempt failed
+    pub fn signup(&self, name: &str, email: &str, error: Option<&str>) -> Result<String> {
+        let template = self.env.get_template("signup.jinja")?;
+        let render = template.render(context! {
+            name,
+            email,
+            email_required => self.config.mail.verify_signups,
+            error,
+            name_max_len => validate::NAME_MAX_LEN,
+            password_min_len => validate::PASSWORD_MIN_LEN,
+        })?;
+        Ok(render)
+    }
+
+    pub fn login(&self, name: &str, error: Option<&str>) -> Result<String> {
         let template = self.env.get_template("login.jinja")?;
-        let render = template.render(context! {})?;
+        let render = template.render(context! { name, error })?;
+        Ok(render)
+    }
+
+    /// Checks the password unless the account or address failed too often. Users who have to
+    /// confirm their email address are sent a new link
+    pub fn log_in(
+        &self,
+        name: String,
+        pswd: String,
+        ip: IpAddr,
+        user_agent: Option<String>,
+    ) -> Result<Login> {
+        let account = validate::skeleton(&name);
+        let ip = ip.to_string();
+        self.check_login_throttles(&account, &ip)?;
+
+        match self.members.login(name, pswd, user_agent) {
+            Ok(Login::Session(sid)) => {
+                self.account_logins.clear(&account);
+                Ok(Login::Session(sid))
+            }
+            Ok(Login::Unverified(id)) => {
+                self.send_verification(id)?;
+                Ok(Login::Unverified(id))
+            }
+            // Failures are only forgotten once the code is right too
+            Ok(login) => Ok(login),
+            Err(e) => {
+                self.account_logins.record(&account);
+                self.ip_logins.record(&ip);
+                Err(e)
+            }
+        }
+    }
+
+    /// Second step of a login with two-factor, throttled like the password. Returns the session
+    pub fn log_in_second_factor(&self, challenge: &str, code: &str, ip: IpAddr) -> Result<Uuid> {
+        let Some(user) = self.members.challenge_user(challenge) else {
+            bail!("The login took too long. Sign in again");
+        };
+        let account = validate::skeleton(&self.members.get_name(user)?);
+        let ip = ip.to_string();
+        self.check_login_throttles(&account, &ip)?;
+
+        match self.members.login_second_factor(challenge, code) {
+            Ok(sid) => {
+                self.account_logins.clear(&account);
+                Ok(sid)
+            }
+            Err(e) => {
+                self.account_logins.record(&account);
+                self.ip_logins.record(&ip);
+                Err(e)
+            }
+        }
+    }
+
+    fn check_login_throttles(&self, account: &str, ip: &str) -> Result<()> {
+        let wait = [(&self.account_logins, account), (&self.ip_logins, ip)]
+            .into_iter()
+            .filter_map(|(throttle, key)| throttle.check(key))
+            .max();
+        if let Some(wait) = wait {
+            let minutes = wait.as_secs().div_ceil(60).max(1);
+            bail!("Too many failed logins. Try again in {minutes} min");
+        }
+        Ok(())
+    }
+
+    pub fn login_code_page(&self, challenge: &str, error: Option<&str>) -> Result<String> {
+        let template = self.env.get_template("login_code.jinja")?;
+        let render = template.render(context! { challenge, error })?;
         Ok(render)
     }
 
+    /// Forgets failed logins and comments that no longer count
+    pub fn sweep_throttles(&self) {
+        self.account_logins.sweep();
+        self.ip_logins.sweep();
+        self.comment_posts.sweep();
+        self.reset_requests.sweep();
+    }
+
     pub fn user_library(&self, sid: Uuid) -> Result<String> {
-        if let Some(name) = self.members.get_user_for_sid(sid) {
-            let lib = self.members.get_library(&name)?;
+        if let Some(user) = self.members.get_user_for_sid(sid) {
+            if let Err(e) = self.reconcile_purchases(user) {
+                tracing::error!("Couldn't add paid works to the library of {user}: {e:#}");
+            }
+            let user = self.members.get_user(user)?;
+            let lib = &user.lib;
 
             // Iterate through the global library to find the user's works' metadata
             let owned_works = self.lib.all_works_by(|(id, _)| lib.works.contains(id));
-            let active_works = self
-                .lib
-                .all_works_by(|(id, _)| lib.active.iter().find(|aw| aw.id == *id).is_some());
+            let continue_reading = self.continue_reading(lib);
             let template = self.env.get_template("userhome.jinja")?;
-            let render = template.render(context! { owned_works, active_works })?;
+            let render = template.render(context! {
+                author_url => format!("/authors/{}", url_encode_segment(&user.name)),
+                name => user.name,
+                can_write => user.role.can(Capability::WriteWorks),
+                can_trash => user.role.can(Capability::ManageTrash),
+                can_manage_users => user.role.can(Capability::ManageUsers),
+                owned_works,
+                continue_reading,
+                settings => user.settings,
+                email => user.email,
+                unverified_email => user.unverified_email,
+            })?;
             Ok(render)
         } else {
             bail!("User does not exist!")
         }
     }
+
+    /// Lists the user's active sessions
+    pub fn sessions_page(&self, user: Uuid, sid: Uuid) -> Result<String> {
+        let format = |t: std::time::SystemTime| {
+            chrono::DateTime::<chrono::Utc>::from(t)
+                .format("%Y-%m-%d %H:%M UTC")
+                .to_string()
+        };
+        let sessions = Value::from_iter(self.members.sessions.for_user(user).into_iter().map(
+            |(id, s)| {
+                context! {
+                    current => id == sid,
+                    created => format(s.created),
+                    last_seen => format(s.last_seen),
+                    expires => format(s.expires),
+                    user_agent => s.user_agent,
+                }
+            },
+        ));
+        let template = self.env.get_template("sessions.jinja")?;
+        let render = template.render(context! { sessions })?;
+        Ok(render)
+    }
+
+    /// Ends every session of the user, including the current one
+    pub fn log_out_everywhere(&self, user: Uuid) {
+        self.members.sessions.revoke_all(user);
+    }
+
+    pub fn rename(&self, sid: Uuid, name: String) -> Result<()> {
+        let Some(user) = self.members.get_user_for_sid(sid) else {
+            bail!("User does not exist!");
+        };
+        self.members.rename(user, name)
+    }
+
+    pub fn set_reader_settings(&self, sid: Uuid, settings: ReaderSettings) -> Result<()> {
+        let Some(user) = self.members.get_user_for_sid(sid) else {
+            bail!("User does not exist!");
+        };
+        self.members.set_settings(user, settings)
+    }
+}
+
+fn render_entries(elements: Vec<Entry>, mode: WritingMode) -> Value {
+    Value::from_iter(elements.into_iter().map(|e| match e {
+        Entry::Paragraph(p) => Value::from_safe_string(render::paragraph(&p, mode)),
+        Entry::Image(data) => Value::from_safe_string(render::image(&data)),
+    }))
+}
+
+#[cfg(test)]
+impl Application<sled::Db> {
+    /// An application on an empty db, which is deleted when it is dropped. Passwords are hashed
+    /// as cheaply as argon2 allows, to keep tests fast
+    pub fn temporary(mut config: Config) -> Self {
+        config.password.memory_cost = 8;
+        config.password.time_cost = 1;
+        Self::with_db(crate::db::temporary(), config).unwrap()
+    }
 }
@@ -0,0 +1,366 @@
+//! Extractors for the signed in user and what they are allowed to do. Scripts can stand in for
+//! a user with an API token, but only where the token's scope allows it
+
+use std::marker::PhantomData;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+use uuid::Uuid;
+
+use crate::{
+    cookies::SessionJar,
+    db::Backend,
+    error::LibraryError,
+    routes::App,
+    token::{self, Scope},
+    user::{Capability, Role},
+};
+
+/// The user signed in with the session cookie. Redirects to the login page if there is none.
+/// Requests with an API token are refused, as they skip the CSRF check
+pub struct CurrentUser {
+    pub id: Uuid,
+    pub sid: Uuid,
+    pub role: Role,
+}
+
+#[async_trait]
+impl<B: Backend + 'static> FromRequestParts<App<B>> for CurrentUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &App<B>) -> Result<Self, Response> {
+        if token::bearer(&parts.headers).is_some() {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "API tokens can't be used for this. Sign in instead",
+            )
+                .into_response());
+        }
+        let Ok(jar) = SessionJar::from_request_parts(parts, state).await;
+        let user = jar.session_id().and_then(|sid| {
+            let id = state.members.get_user_for_sid(sid)?;
+            let role = state.members.get_role(id).ok()?;
+            Some(CurrentUser { id, sid, role })
+        });
+        user.ok_or_else(|| Redirect::to("/login").into_response())
+    }
+}
+
+/// Who a request acts for, signed in or through an API token
+pub struct Caller {
+    pub id: Uuid,
+    pub role: Role,
+}
+
+impl Caller {
+    /// The API token's owner if the request has a Bearer token, so a script is never mistaken
+    /// for the browser session it runs in. Rejects tokens without `scope`
+    fn from_token<B: Backend>(
+        parts: &Parts,
+        state: &App<B>,
+        scope: Option<Scope>,
+    ) -> Option<Result<Self, Response>> {
+        let secret = token::bearer(&parts.headers)?;
+        let Some(token) = state.members.tokens.authenticate(secret) else {
+            return Some(Err((
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                "The API token is invalid or was revoked",
+            )
+                .into_response()));
+        };
+        if !scope.is_some_and(|s| token.scopes.contains(&s)) {
+            return Some(Err((
+                StatusCode::FORBIDDEN,
+                "The API token doesn't have the scope for this",
+            )
+                .into_response()));
+        }
+        let role = match state.members.get_role(token.user) {
+            Ok(role) => role,
+            Err(_) => return Some(Err(StatusCode::UNAUTHORIZED.into_response())),
+        };
+        Some(Ok(Caller {
+            id: token.user,
+            role,
+        }))
+    }
+
+    async fn from_parts<B: Backend + 'static>(
+        parts: &mut Parts,
+        state: &App<B>,
+        scope: Option<Scope>,
+    ) -> Result<Self, Response> {
+        if let Some(caller) = Self::from_token(parts, state, scope) {
+            return caller;
+        }
+        let user = CurrentUser::from_request_parts(parts, state).await?;
+        Ok(Caller {
+            id: user.id,
+            role: user.role,
+        })
+    }
+}
+
+/// Marks a token scope required by [`Scoped`]
+pub trait TokenScope {
+    const SCOPE: Scope;
+}
+
+/// A signed in user, or a script with an API token that has the scope `S`
+pub struct Scoped<S: TokenScope> {
+    pub user: Caller,
+    _scope: PhantomData<S>,
+}
+
+#[async_trait]
+impl<B: Backend + 'static, S: TokenScope> FromRequestParts<App<B>> for Scoped<S> {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &App<B>) -> Result<Self, Response> {
+        let user = Caller::from_parts(parts, state, Some(S::SCOPE)).await?;
+        Ok(Self {
+            user,
+            _scope: PhantomData,
+        })
+    }
+}
+
+/// Marks a capability required by [`Authorized`]
+pub trait Requirement {
+    const CAPABILITY: Capability;
+    /// Shown in errors
+    const ACTION: &'static str;
+    /// The token scope that lets scripts do this, if any
+    const SCOPE: Option<Scope> = None;
+}
+
+/// A signed in user whose role has the capability `C`. Responds with 403 otherwise, and sends
+/// users whose role requires two-factor login to set it up first
+pub struct Authorized<C: Requirement> {
+    pub user: Caller,
+    _capability: PhantomData<C>,
+}
+
+#[async_trait]
+impl<B: Backend + 'static, C: Requirement> FromRequestParts<App<B>> for Authorized<C> {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &App<B>) -> Result<Self, Response> {
+        let user = Caller::from_parts(parts, state, C::SCOPE).await?;
+        if !user.role.can(C::CAPABILITY) {
+            return Err(LibraryError::Denied(C::ACTION.to_string()).into_response());
+        }
+        if state.members.needs_two_factor(user.id).unwrap_or(true) {
+            return Err(Redirect::to("/user/two-factor").into_response());
+        }
+        Ok(Self {
+            user,
+            _capability: PhantomData,
+        })
+    }
+}
+
+/// Markers for [`Authorized`]
+pub mod caps {
+    use super::Requirement;
+    use crate::{token::Scope, user::Capability};
+
+    pub struct WriteWorks;
+    pub struct ManageTrash;
+    pub struct ManageUsers;
+    pub struct Moderate;
+
+    impl Requirement for WriteWorks {
+        const CAPABILITY: Capability = Capability::WriteWorks;
+        const ACTION: &'static str = "Writing works";
+        const SCOPE: Option<Scope> = Some(Scope::AuthorContent);
+    }
+
+    impl Requirement for ManageTrash {
+        const CAPABILITY: Capability = Capability::ManageTrash;
+        const ACTION: &'static str = "Managing the trash";
+    }
+
+    impl Requirement for Moderate {
+        const CAPABILITY: Capability = Capability::Moderate;
+        const ACTION: &'static str = "Moderating comments";
+    }
+
+    impl Requirement for ManageUsers {
+        const CAPABILITY: Capability = Capability::ManageUsers;
+        const ACTION: &'static str = "Managing users";
+    }
+}
+
+/// Markers for [`Scoped`]
+pub mod scopes {
+    use super::TokenScope;
+    use crate::token::Scope;
+
+    pub struct ReadLibrary;
+    pub struct WriteProgress;
+
+    impl TokenScope for ReadLibrary {
+        const SCOPE: Scope = Scope::ReadLibrary;
+    }
+
+    impl TokenScope for WriteProgress {
+        const SCOPE: Scope = Scope::WriteProgress;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use axum::http::Request;
+
+    use super::*;
+    use crate::{application::Application, auth::caps, config::Config};
+
+    pub(crate) fn app() -> App<sled::Db> {
+        let mut config = Config::default();
+        config.cookies.protection = crate::config::CookieProtection::Plain;
+        std::sync::Arc::new(Application::temporary(config))
+    }
+
+    pub(crate) fn parts(headers: &[(header::HeaderName, String)]) -> Parts {
+        let mut req = Request::builder();
+        for (name, value) in headers {
+            req = req.header(name, value);
+        }
+        req.body(()).unwrap().into_parts().0
+    }
+
+    /// Signs `user` in, returning the cookie header
+    pub(crate) fn session(app: &App<sled::Db>, user: Uuid) -> (header::HeaderName, String) {
+        let sid = app.members.sessions.create(user, None).unwrap();
+        (header::COOKIE, format!("{}={sid}", crate::user::SID_COOKIE))
+    }
+
+    async fn write_works(app: &App<sled::Db>, parts: &mut Parts) -> Result<Uuid, StatusCode> {
+        Authorized::<caps::WriteWorks>::from_request_parts(parts, app)
+            .await
+            .map(|a| a.user.id)
+            .map_err(|res| res.status())
+    }
+
+    #[tokio::test]
+    async fn authorized_needs_the_capability() {
+        let app = app();
+        let user = app
+            .members
+            .create_user("reader".into(), "correct horse".into(), Role::default())
+            .unwrap();
+        let cookie = session(&app, user);
+
+        assert_eq!(
+            write_works(&app, &mut parts(&[])).await,
+            Err(StatusCode::SEE_OTHER)
+        );
+        let mut signed_in = parts(&[cookie]);
+        assert_eq!(
+            write_works(&app, &mut signed_in).await,
+            Err(StatusCode::FORBIDDEN)
+        );
+        app.members.set_role(user, Role::Author).unwrap();
+        assert_eq!(write_works(&app, &mut signed_in).await, Ok(user));
+
+        // Admins have to set up two-factor login first
+        app.members.set_role(user, Role::Admin).unwrap();
+        let res = Authorized::<caps::ManageUsers>::from_request_parts(&mut signed_in, &app).await;
+        let res = res.err().unwrap();
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers()[header::LOCATION], "/user/two-factor");
+    }
+
+    fn bearer(secret: &str) -> (header::HeaderName, String) {
+        (header::AUTHORIZATION, format!("Bearer {secret}"))
+    }
+
+    async fn read_library(app: &App<sled::Db>, parts: &mut Parts) -> Result<Uuid, StatusCode> {
+        Scoped::<scopes::ReadLibrary>::from_request_parts(parts, app)
+            .await
+            .map(|s| s.user.id)
+            .map_err(|res| res.status())
+    }
+
+    #[tokio::test]
+    async fn tokens_need_the_scope() {
+        let app = app();
+        let user = app
+            .members
+            .create_user("author".into(), "correct horse".into(), Role::Author)
+            .unwrap();
+        let tokens = &app.members.tokens;
+        let progress = tokens
+            .create(user, "Progress", vec![Scope::WriteProgress])
+            .unwrap();
+        let library = tokens
+            .create(user, "Library", vec![Scope::ReadLibrary])
+            .unwrap();
+        let content = tokens
+            .create(user, "Content", vec![Scope::AuthorContent])
+            .unwrap();
+
+        let with = |secret| parts(&[bearer(secret)]);
+        assert_eq!(
+            read_library(&app, &mut with(&progress)).await,
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(read_library(&app, &mut with(&library)).await, Ok(user));
+        assert_eq!(write_works(&app, &mut with(&content)).await, Ok(user));
+        // Tokens carry the role of their owner
+        app.members.set_role(user, Role::Reader).unwrap();
+        assert_eq!(
+            write_works(&app, &mut with(&content)).await,
+            Err(StatusCode::FORBIDDEN)
+        );
+        // Capabilities without a scope are for people only
+        let res = Authorized::<caps::Moderate>::from_request_parts(&mut with(&content), &app).await;
+        assert_eq!(res.err().unwrap().status(), StatusCode::FORBIDDEN);
+
+        let listed = tokens.for_user(user);
+        let library_token = listed.iter().find(|t| t.name == "Library").unwrap();
+        tokens.revoke(user, library_token.id).unwrap();
+        assert_eq!(
+            read_library(&app, &mut with(&library)).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[tokio::test]
+    async fn sessions_and_tokens_dont_mix() {
+        let app = app();
+        let user = app
+            .members
+            .create_user("reader".into(), "correct horse".into(), Role::Reader)
+            .unwrap();
+        let secret = app
+            .members
+            .tokens
+            .create(user, "Script", vec![Scope::ReadLibrary])
+            .unwrap();
+        let cookie = session(&app, user);
+
+        // A session never rides along with a token
+        let mut both = parts(&[cookie.clone(), bearer(&secret)]);
+        let res = CurrentUser::from_request_parts(&mut both, &app).await;
+        assert_eq!(res.err().unwrap().status(), StatusCode::FORBIDDEN);
+        let mut made_up = parts(&[cookie.clone(), bearer("tsk_made_up")]);
+        assert_eq!(
+            read_library(&app, &mut made_up).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        // The Basic credentials of a proxy aren't a token
+        let basic = (header::AUTHORIZATION, "Basic YTpi".to_string());
+        let mut proxied = parts(&[cookie, basic]);
+        let res = CurrentUser::from_request_parts(&mut proxied, &app).await;
+        assert_eq!(res.ok().map(|u| u.id), Some(user));
+        assert_eq!(read_library(&app, &mut proxied).await, Ok(user));
+    }
+}
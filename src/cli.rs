@@ -0,0 +1,44 @@
+//! Command line interface
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+
+const USAGE: &str = "Usage:
+    toshokan                      Run the server
+    toshokan import <FILE>...     Import EPUB or Aozora Bunko text files into the library
+    toshokan create-admin <NAME>  Create an admin, reading the password from stdin. An existing
+                                  user is promoted instead";
+
+pub enum Command {
+    /// Run the web server
+    Serve,
+    /// Import book files into the library
+    Import(Vec<PathBuf>),
+    /// Create the first admin, or promote an existing user
+    CreateAdmin(String),
+}
+
+impl Command {
+    /// Parses the arguments, excluding the program name
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let cmd = match args.next().as_deref() {
+            None => Command::Serve,
+            Some("import") => {
+                let paths: Vec<_> = args.map(PathBuf::from).collect();
+                if paths.is_empty() {
+                    bail!("No files to import\n\n{USAGE}");
+                }
+                Command::Import(paths)
+            }
+            Some("create-admin") => {
+                let Some(name) = args.next() else {
+                    bail!("No name given\n\n{USAGE}");
+                };
+                Command::CreateAdmin(name)
+            }
+            Some(cmd) => bail!("Unknown command {cmd}\n\n{USAGE}"),
+        };
+        Ok(cmd)
+    }
+}
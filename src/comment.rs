@@ -0,0 +1,250 @@
+//! Comment threads under chapters. Comments are keyed by id, which sorts them by age, and indexed
+//! by chapter and by user
+
+use std::{collections::HashMap, time::SystemTime};
+
+use anyhow::{bail, Result};
+use bincode::{Decode, Encode};
+use uuid::Uuid;
+
+use crate::{
+    db::{index_key, Backend, Table},
+    utils::{decode_bincode, encode_bincode},
+};
+
+const COMMENT_TABLE: &str = "COMMENTS";
+/// Keys of [`index_key`] of the chapter and comment id
+const CHAPTER_COMMENT_TABLE: &str = "CHAPTER_COMMENTS";
+/// Keys of [`index_key`] of the user and comment id
+const USER_COMMENT_TABLE: &str = "USER_COMMENTS";
+/// Chapters whose threads are locked, keyed by chapter id
+const LOCK_TABLE: &str = "COMMENT_LOCKS";
+/// Users banned from commenting, keyed by user id
+const BAN_TABLE: &str = "COMMENT_BANS";
+pub const COMMENT_MAX_LEN: usize = 5000;
+
+#[derive(Clone, Encode, Decode)]
+pub struct Comment {
+    #[bincode(with_serde)]
+    pub user: Uuid,
+    #[bincode(with_serde)]
+    pub work: Uuid,
+    #[bincode(with_serde)]
+    pub chapter: Uuid,
+    /// The comment this replies to
+    #[bincode(with_serde)]
+    pub parent: Option<Uuid>,
+    pub text: String,
+    pub created: SystemTime,
+    pub edited: Option<SystemTime>,
+    /// Deleted by its writer. Kept so replies stay in place
+    pub deleted: bool,
+    /// Hidden by a moderator
+    pub hidden: bool,
+}
+
+/// A comment and the replies to it
+pub struct Thread {
+    pub id: Uuid,
+    pub comment: Comment,
+    pub replies: Vec<Thread>,
+}
+
+/// Arranges the comments of a chapter into threads, oldest first
+pub fn threads(mut comments: Vec<(Uuid, Comment)>) -> Vec<Thread> {
+    comments.sort_by_key(|(id, _)| *id);
+    let ids: Vec<Uuid> = comments.iter().map(|(id, _)| *id).collect();
+    let mut children: HashMap<Option<Uuid>, Vec<(Uuid, Comment)>> = HashMap::new();
+    for (id, comment) in comments {
+        // Replies to comments that are gone are shown on their own
+        let parent = comment.parent.filter(|p| ids.contains(p));
+        children.entry(parent).or_default().push((id, comment));
+    }
+
+    fn build(
+        parent: Option<Uuid>,
+        children: &mut HashMap<Option<Uuid>, Vec<(Uuid, Comment)>>,
+    ) -> Vec<Thread> {
+        let Some(comments) = children.remove(&parent) else {
+            return vec![];
+        };
+        comments
+            .into_iter()
+            .map(|(id, comment)| Thread {
+                id,
+                comment,
+                replies: build(Some(id), children),
+            })
+            .collect()
+    }
+    build(None, &mut children)
+}
+
+/// Checks the text of a new or edited comment, returning it trimmed
+pub fn validate(text: &str) -> Result<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        bail!("Comments can't be empty");
+    }
+    if text.chars().count() > COMMENT_MAX_LEN {
+        bail!("Comments can't be longer than {COMMENT_MAX_LEN} characters");
+    }
+    Ok(text.to_string())
+}
+
+pub struct CommentStore<B: Backend> {
+    comments: <B as Backend>::OutTable,
+    by_chapter: <B as Backend>::OutTable,
+    by_user: <B as Backend>::OutTable,
+    locks: <B as Backend>::OutTable,
+    bans: <B as Backend>::OutTable,
+}
+
+impl<B: Backend> CommentStore<B> {
+    pub fn new(db: &B) -> Result<Self> {
+        Ok(Self {
+            comments: db.get_table(COMMENT_TABLE)?,
+            by_chapter: db.get_table(CHAPTER_COMMENT_TABLE)?,
+            by_user: db.get_table(USER_COMMENT_TABLE)?,
+            locks: db.get_table(LOCK_TABLE)?,
+            bans: db.get_table(BAN_TABLE)?,
+        })
+    }
+
+    pub fn add(&self, comment: &Comment) -> Result<Uuid> {
+        let id = Uuid::now_v7();
+        self.comments.insert(id, encode_bincode(comment)?);
+        self.by_chapter.insert(index_key(comment.chapter, id), []);
+        self.by_user.insert(index_key(comment.user, id), []);
+        Ok(id)
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Comment> {
+        let data = self.comments.get_value(id)?;
+        decode_bincode(data.as_ref()).ok()
+    }
+
+    /// Decodes the comment, applies `f` and writes it back
+    pub fn update(&self, id: Uuid, f: impl FnOnce(&mut Comment)) -> Result<()> {
+        let Some(mut comment) = self.get(id) else {
+            bail!("That comment doesn't exist");
+        };
+        f(&mut comment);
+        self.comments.insert(id, encode_bincode(&comment)?);
+        Ok(())
+    }
+
+    /// Every comment under `chapter`, oldest first
+    pub fn for_chapter(&self, chapter: Uuid) -> Vec<(Uuid, Comment)> {
+        self.indexed(&self.by_chapter, chapter)
+    }
+
+    /// Every comment by `user`, oldest first
+    pub fn for_user(&self, user: Uuid) -> Vec<(Uuid, Comment)> {
+        self.indexed(&self.by_user, user)
+    }
+
+    /// Credits the comments of a deleted account to nobody. They stay so threads still make sense
+    pub fn anonymise(&self, user: Uuid) -> Result<()> {
+        for (id, _) in self.for_user(user) {
+            self.update(id, |c| c.user = Uuid::nil())?;
+            self.by_user.remove(index_key(user, id));
+        }
+        self.set_banned(user, false);
+        Ok(())
+    }
+
+    fn indexed(&self, index: &<B as Backend>::OutTable, owner: Uuid) -> Vec<(Uuid, Comment)> {
+        index
+            .indexed(owner)
+            .filter_map(|id| Some((id, self.get(id)?)))
+            .collect()
+    }
+
+    pub fn is_locked(&self, chapter: Uuid) -> bool {
+        self.locks.get_value(chapter).is_some()
+    }
+
+    pub fn set_locked(&self, chapter: Uuid, locked: bool) {
+        if locked {
+            self.locks.insert(chapter, b"");
+        } else {
+            self.locks.remove(chapter);
+        }
+    }
+
+    pub fn is_banned(&self, user: Uuid) -> bool {
+        self.bans.get_value(user).is_some()
+    }
+
+    pub fn set_banned(&self, user: Uuid, banned: bool) {
+        if banned {
+            self.bans.insert(user, b"");
+        } else {
+            self.bans.remove(user);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn comment(user: Uuid, chapter: Uuid, parent: Option<Uuid>) -> Comment {
+        Comment {
+            user,
+            work: Uuid::nil(),
+            chapter,
+            parent,
+            text: String::new(),
+            created: SystemTime::now(),
+            edited: None,
+            deleted: false,
+            hidden: false,
+        }
+    }
+
+    #[test]
+    fn store() {
+        let store = CommentStore::new(&crate::db::temporary()).unwrap();
+        let (alice, bob) = (Uuid::now_v7(), Uuid::now_v7());
+        let (first, second) = (Uuid::now_v7(), Uuid::now_v7());
+        let a = store.add(&comment(alice, first, None)).unwrap();
+        let b = store.add(&comment(bob, first, Some(a))).unwrap();
+        let c = store.add(&comment(alice, second, None)).unwrap();
+        // Ids made within the same millisecond aren't in order
+        let sorted = |mut ids: Vec<Uuid>| {
+            ids.sort();
+            ids
+        };
+        let ids = |list: Vec<(Uuid, Comment)>| sorted(list.into_iter().map(|(id, _)| id).collect());
+        assert_eq!(ids(store.for_chapter(first)), sorted(vec![a, b]));
+        assert_eq!(ids(store.for_chapter(second)), [c]);
+        assert_eq!(ids(store.for_user(alice)), sorted(vec![a, c]));
+
+        store.anonymise(alice).unwrap();
+        assert!(store.for_user(alice).is_empty());
+        assert_eq!(store.get(a).unwrap().user, Uuid::nil());
+        assert_eq!(store.get(b).unwrap().user, bob);
+    }
+
+    #[test]
+    fn threading() {
+        let comment = |parent| comment(Uuid::nil(), Uuid::nil(), parent);
+        let mut ids: Vec<_> = (0..4).map(|_| Uuid::now_v7()).collect();
+        ids.sort();
+        let comments = vec![
+            (ids[2], comment(Some(ids[0]))),
+            (ids[0], comment(None)),
+            (ids[3], comment(Some(Uuid::nil()))),
+            (ids[1], comment(Some(ids[0]))),
+        ];
+        let threads = threads(comments);
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0].id, ids[0]);
+        let replies: Vec<_> = threads[0].replies.iter().map(|t| t.id).collect();
+        assert_eq!(replies, [ids[1], ids[2]]);
+        // Its parent is gone
+        assert_eq!(threads[1].id, ids[3]);
+    }
+}
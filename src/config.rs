@@ -1,19 +1,273 @@
+use std::path::PathBuf;
+
 use serde::{Deserialize, Serialize};
 
 use anyhow::Result;
 
+use crate::user::Role;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Config {
+    pub password: PasswordConfig,
+    pub session: SessionConfig,
+    pub accounts: AccountConfig,
+    pub cookies: CookieConfig,
+    pub comments: CommentConfig,
+    pub mail: MailConfig,
+    pub shop: ShopConfig,
+    pub stats: StatsConfig,
+}
+
+impl Config {
+    pub fn load(c: String) -> Result<Self> {
+        Ok(toml::from_str(&c)?)
+    }
+
+    /// Loads `toshokan/config.toml` from the user's config directory, or the defaults if it
+    /// doesn't exist
+    pub fn load_default() -> Result<Self> {
+        let path = Self::path()?;
+        match std::fs::read_to_string(path) {
+            Ok(c) => Self::load(c),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn path() -> Result<PathBuf> {
+        use etcetera::BaseStrategy;
+        let strategy = etcetera::choose_base_strategy()?;
+        Ok(strategy.config_dir().join("toshokan").join("config.toml"))
+    }
+}
+
+/// Cost of the argon2id password hash. Raising any of these rehashes passwords as users log in
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
-pub struct Config {}
+pub struct PasswordConfig {
+    /// In KiB
+    pub memory_cost: u32,
+    /// Number of iterations
+    pub time_cost: u32,
+    /// Number of lanes
+    pub parallelism: u32,
+}
 
-impl Default for Config {
+impl Default for PasswordConfig {
     fn default() -> Self {
-        Self {}
+        // The OWASP recommendation, which is also argon2's default
+        Self {
+            memory_cost: 19 * 1024,
+            time_cost: 2,
+            parallelism: 1,
+        }
     }
 }
 
-impl Config {
-    pub fn load(c: String) -> Result<Self> {
-        Ok(toml::from_str(&c)?)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// How long a session lasts without being used
+    pub lifetime_hours: u64,
+    /// How often expired sessions are deleted
+    pub sweep_interval_minutes: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            lifetime_hours: 30 * 24,
+            sweep_interval_minutes: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct AccountConfig {
+    /// Role of users who sign up. Admins can promote them on /admin/users afterwards
+    pub default_role: Role,
+    /// Failed logins allowed per account within the window
+    pub login_attempts: usize,
+    /// Failed logins allowed per IP address within the window
+    pub ip_login_attempts: usize,
+    pub login_window_minutes: u64,
+    /// Password reset mails that can be asked for per IP address within the reset window
+    pub ip_reset_requests: usize,
+    pub reset_window_minutes: u64,
+    /// Users with this role or a higher one have to set up two-factor login before they can use
+    /// what their role allows
+    pub two_factor_role: Role,
+    /// Header a reverse proxy puts the client's address in, e.g. `X-Forwarded-For`. Behind a
+    /// proxy every request comes from the proxy's address, so without this all visitors share
+    /// the per-IP limits and a few failed logins lock everyone out. Leave it unset otherwise,
+    /// or anyone can dodge the limits by sending the header themselves
+    pub client_ip_header: Option<String>,
+}
+
+impl Default for AccountConfig {
+    fn default() -> Self {
+        Self {
+            default_role: Role::default(),
+            login_attempts: 5,
+            ip_login_attempts: 20,
+            login_window_minutes: 15,
+            ip_reset_requests: 5,
+            reset_window_minutes: 60,
+            two_factor_role: Role::Admin,
+            client_ip_header: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct CookieConfig {
+    /// Only send cookies over HTTPS. Turn it off when serving plain HTTP, e.g. locally
+    pub secure: bool,
+    /// Hide cookies from scripts
+    pub http_only: bool,
+    pub same_site: SameSitePolicy,
+    pub protection: CookieProtection,
+    /// Base64 encoded key of at least 64 bytes for signed and private cookies. Without one, a
+    /// random key is made on every start, which signs everyone out
+    pub key: Option<String>,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            secure: true,
+            http_only: true,
+            same_site: SameSitePolicy::Lax,
+            protection: CookieProtection::Plain,
+            key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct CommentConfig {
+    /// Comments a user can post within the window
+    pub posts: usize,
+    pub window_minutes: u64,
+}
+
+impl Default for CommentConfig {
+    fn default() -> Self {
+        Self {
+            posts: 5,
+            window_minutes: 10,
+        }
     }
 }
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    /// Sender of every mail
+    pub from: String,
+    /// Where the site is reached, for links in mail
+    pub base_url: String,
+    /// New accounts need an email address and can't log in until they confirm it
+    pub verify_signups: bool,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            transport: MailTransport::Log,
+            from: "Toshokan <toshokan@localhost>".to_string(),
+            base_url: "http://localhost:3080".to_string(),
+            verify_signups: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "kind", deny_unknown_fields)]
+pub enum MailTransport {
+    /// Only logged, for local testing
+    Log,
+    /// Written to `dir` as `.eml` files, for local testing
+    File { dir: PathBuf },
+    /// Sent over SMTP with STARTTLS, or implicit TLS on port 465
+    Smtp {
+        host: String,
+        port: Option<u16>,
+        username: Option<String>,
+        password: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct ShopConfig {
+    /// ISO 4217 code of the currency prices are in. It needs two decimal places
+    pub currency: String,
+    /// Nothing can be bought until this is set
+    pub payments: Option<PaymentConfig>,
+}
+
+impl Default for ShopConfig {
+    fn default() -> Self {
+        Self {
+            currency: "USD".to_string(),
+            payments: None,
+        }
+    }
+}
+
+/// Who takes the payments
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "kind", deny_unknown_fields)]
+pub enum PaymentConfig {
+    /// Takes test card numbers and never moves money, so paid works are given away. Only for
+    /// local testing
+    Fake,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct StatsConfig {
+    /// How often views counted in memory are written to the db. They are also written on
+    /// shutdown, but lost if the server crashes
+    pub flush_interval_seconds: u64,
+    /// Daily counts older than this are dropped. Weekly and monthly ones keep the totals
+    pub daily_retention_days: u32,
+    /// Weekly counts older than this are dropped. Monthly ones are kept for good
+    pub weekly_retention_weeks: u32,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval_seconds: 60,
+            daily_retention_days: 90,
+            weekly_retention_weeks: 2 * 52,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SameSitePolicy {
+    Strict,
+    Lax,
+    None,
+}
+
+/// How the session cookie is stored in the browser
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CookieProtection {
+    /// As is
+    Plain,
+    /// Signed so it can't be tampered with
+    Signed,
+    /// Encrypted so it can't be read either
+    Private,
+}
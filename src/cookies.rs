@@ -0,0 +1,225 @@
+//! The session cookie, stored as configured in [`CookieConfig`]
+
+use anyhow::{bail, Result};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::request::Parts,
+    response::{IntoResponseParts, ResponseParts},
+};
+use axum_extra::extract::{
+    cookie::{Cookie, Key, SameSite},
+    CookieJar, PrivateCookieJar, SignedCookieJar,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use uuid::Uuid;
+
+use crate::{
+    config::{Config, CookieConfig, CookieProtection, SameSitePolicy},
+    db::Backend,
+    routes::App,
+    user::SID_COOKIE,
+};
+
+pub struct CookieSettings {
+    config: CookieConfig,
+    key: Key,
+    /// Lifetime of the session cookie
+    max_age: time::Duration,
+}
+
+impl CookieSettings {
+    pub fn new(config: &Config) -> Result<Self> {
+        let key = match &config.cookies.key {
+            Some(key) => match Key::try_from(STANDARD.decode(key)?.as_slice()) {
+                Ok(key) => key,
+                Err(_) => bail!("The cookie key must be at least 64 bytes long"),
+            },
+            None => {
+                if config.cookies.protection != CookieProtection::Plain {
+                    tracing::warn!("No cookie key is configured, sessions end on restart");
+                }
+                Key::generate()
+            }
+        };
+        let max_age = time::Duration::hours(config.session.lifetime_hours as i64);
+        Ok(Self {
+            config: config.cookies.clone(),
+            key,
+            max_age,
+        })
+    }
+
+    /// Applies the configured attributes
+    pub fn harden(&self, mut cookie: Cookie<'static>) -> Cookie<'static> {
+        cookie.set_path("/");
+        cookie.set_secure(self.config.secure);
+        cookie.set_http_only(self.config.http_only);
+        cookie.set_same_site(match self.config.same_site {
+            SameSitePolicy::Strict => SameSite::Strict,
+            SameSitePolicy::Lax => SameSite::Lax,
+            SameSitePolicy::None => SameSite::None,
+        });
+        cookie
+    }
+
+    fn session_cookie(&self, sid: Uuid) -> Cookie<'static> {
+        let mut cookie = self.harden(Cookie::new(SID_COOKIE, sid.to_string()));
+        cookie.set_max_age(self.max_age);
+        cookie
+    }
+}
+
+/// Cookies of a request, read and written with the configured protection
+pub enum SessionJar {
+    Plain(CookieJar),
+    Signed(SignedCookieJar),
+    Private(PrivateCookieJar),
+}
+
+impl SessionJar {
+    /// Session id from the cookie, if there is a valid one
+    pub fn session_id(&self) -> Option<Uuid> {
+        let cookie = match self {
+            SessionJar::Plain(jar) => jar.get(SID_COOKIE).cloned(),
+            SessionJar::Signed(jar) => jar.get(SID_COOKIE),
+            SessionJar::Private(jar) => jar.get(SID_COOKIE),
+        }?;
+        Uuid::try_parse(cookie.value_trimmed()).ok()
+    }
+
+    pub fn with_session(self, settings: &CookieSettings, sid: Uuid) -> Self {
+        let cookie = settings.session_cookie(sid);
+        match self {
+            SessionJar::Plain(jar) => SessionJar::Plain(jar.add(cookie)),
+            SessionJar::Signed(jar) => SessionJar::Signed(jar.add(cookie)),
+            SessionJar::Private(jar) => SessionJar::Private(jar.add(cookie)),
+        }
+    }
+
+    pub fn without_session(self) -> Self {
+        let cookie = Cookie::build(SID_COOKIE).path("/");
+        match self {
+            SessionJar::Plain(jar) => SessionJar::Plain(jar.remove(cookie)),
+            SessionJar::Signed(jar) => SessionJar::Signed(jar.remove(cookie)),
+            SessionJar::Private(jar) => SessionJar::Private(jar.remove(cookie)),
+        }
+    }
+}
+
+#[async_trait]
+impl<B: Backend + 'static> FromRequestParts<App<B>> for SessionJar {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &App<B>,
+    ) -> Result<Self, Self::Rejection> {
+        let settings = &state.cookies;
+        let headers = &parts.headers;
+        let jar = match settings.config.protection {
+            CookieProtection::Plain => SessionJar::Plain(CookieJar::from_headers(headers)),
+            CookieProtection::Signed => {
+                SessionJar::Signed(SignedCookieJar::from_headers(headers, settings.key.clone()))
+            }
+            CookieProtection::Private => SessionJar::Private(PrivateCookieJar::from_headers(
+                headers,
+                settings.key.clone(),
+            )),
+        };
+        Ok(jar)
+    }
+}
+
+impl IntoResponseParts for SessionJar {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts(self, res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        match self {
+            SessionJar::Plain(jar) => jar.into_response_parts(res),
+            SessionJar::Signed(jar) => jar.into_response_parts(res),
+            SessionJar::Private(jar) => jar.into_response_parts(res),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        http::{header, HeaderMap, HeaderValue},
+        response::IntoResponse,
+    };
+
+    use super::*;
+
+    fn settings(cookies: CookieConfig) -> CookieSettings {
+        let config = Config {
+            cookies,
+            ..Config::default()
+        };
+        CookieSettings::new(&config).unwrap()
+    }
+
+    #[test]
+    fn harden_applies_the_config() {
+        let cookie = settings(CookieConfig::default()).harden(Cookie::new("a", "b"));
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+
+        let cookie = settings(CookieConfig {
+            secure: false,
+            http_only: false,
+            same_site: SameSitePolicy::Strict,
+            ..CookieConfig::default()
+        })
+        .harden(Cookie::new("a", "b"));
+        assert_eq!(cookie.secure(), Some(false));
+        assert_eq!(cookie.http_only(), Some(false));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+    }
+
+    /// A session cookie set through a jar is read back by a jar with the same protection, and
+    /// only plain jars take a cookie that was made up by the client
+    #[test]
+    fn session_round_trip() {
+        for protection in [
+            CookieProtection::Plain,
+            CookieProtection::Signed,
+            CookieProtection::Private,
+        ] {
+            let settings = settings(CookieConfig {
+                protection,
+                ..CookieConfig::default()
+            });
+            let jar = |cookie: Option<String>| {
+                let mut headers = HeaderMap::new();
+                if let Some(cookie) = cookie {
+                    headers.insert(header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+                }
+                match protection {
+                    CookieProtection::Plain => SessionJar::Plain(CookieJar::from_headers(&headers)),
+                    CookieProtection::Signed => SessionJar::Signed(SignedCookieJar::from_headers(
+                        &headers,
+                        settings.key.clone(),
+                    )),
+                    CookieProtection::Private => SessionJar::Private(
+                        PrivateCookieJar::from_headers(&headers, settings.key.clone()),
+                    ),
+                }
+            };
+
+            let sid = Uuid::now_v7();
+            let res = (jar(None).with_session(&settings, sid), ()).into_response();
+            let set = res.headers()[header::SET_COOKIE].to_str().unwrap();
+            assert!(set.contains("Max-Age="));
+            let sent = set.split(';').next().unwrap().to_string();
+            assert_eq!(jar(Some(sent)).session_id(), Some(sid));
+
+            let forged = format!("{SID_COOKIE}={}", Uuid::now_v7());
+            let accepted = jar(Some(forged)).session_id().is_some();
+            assert_eq!(accepted, protection == CookieProtection::Plain);
+        }
+    }
+}
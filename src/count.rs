@@ -0,0 +1,110 @@
+//! Length of text and how long it takes to read
+
+use std::ops::AddAssign;
+
+use bincode::{Decode, Encode};
+use serde::Serialize;
+
+use crate::{entry::Entry, ruby::plain_text};
+
+/// Average reading speed for Japanese/Chinese text, in characters per minute
+const CJK_CHARS_PER_MINUTE: usize = 500;
+/// Average reading speed for space-delimited text, in words per minute
+const WORDS_PER_MINUTE: usize = 230;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Encode, Decode)]
+pub struct TextCount {
+    /// Every non-whitespace character, the way 文字数 is usually counted
+    pub chars: usize,
+    /// Characters of scripts that aren't separated by spaces (kana, kanji, hangul)
+    pub cjk: usize,
+    /// Words of every other script
+    pub words: usize,
+}
+
+impl TextCount {
+    pub fn of_str(s: &str) -> Self {
+        let mut count = Self::default();
+        let mut in_word = false;
+        for c in s.chars() {
+            if c.is_whitespace() {
+                in_word = false;
+                continue;
+            }
+            count.chars += 1;
+
+            if is_cjk(c) {
+                count.cjk += 1;
+                in_word = false;
+            } else if c.is_alphanumeric() {
+                if !in_word {
+                    count.words += 1;
+                }
+                in_word = true;
+            }
+        }
+        count
+    }
+
+    pub fn of_entries<'a>(entries: impl IntoIterator<Item = &'a Entry>) -> Self {
+        let mut count = Self::default();
+        for e in entries {
+            match e {
+                Entry::Paragraph(p) => count += Self::of_str(&plain_text(p)),
+                Entry::Image(_) => {}
+            }
+        }
+        count
+    }
+
+    /// Estimated time to read, rounded up to the nearest minute
+    pub fn reading_minutes(&self) -> usize {
+        let cjk = self.cjk as f64 / CJK_CHARS_PER_MINUTE as f64;
+        let words = self.words as f64 / WORDS_PER_MINUTE as f64;
+        (cjk + words).ceil() as usize
+    }
+}
+
+impl AddAssign for TextCount {
+    fn add_assign(&mut self, rhs: Self) {
+        self.chars += rhs.chars;
+        self.cjk += rhs.cjk;
+        self.words += rhs.words;
+    }
+}
+
+/// Kana, CJK ideographs, hangul and their punctuation
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{1100}'..='\u{11FF}' // Hangul Jamo
+        | '\u{2E80}'..='\u{2FDF}' // CJK radicals
+        | '\u{3000}'..='\u{30FF}' // CJK punctuation, hiragana, katakana
+        | '\u{3100}'..='\u{31FF}' // Bopomofo, kana extensions
+        | '\u{3400}'..='\u{4DBF}' // CJK Ext A
+        | '\u{4E00}'..='\u{9FFF}' // CJK
+        | '\u{AC00}'..='\u{D7AF}' // Hangul
+        | '\u{F900}'..='\u{FAFF}' // CJK compatibility
+        | '\u{FF00}'..='\u{FFEF}' // Full/half-width forms
+        | '\u{20000}'..='\u{3134F}' // CJK Ext B..G
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_mixed() {
+        let count = TextCount::of_str("吾輩は猫である。 I am a cat.");
+        assert_eq!(
+            count,
+            TextCount {
+                chars: 16,
+                cjk: 8,
+                words: 4
+            }
+        );
+        assert_eq!(count.reading_minutes(), 1);
+        assert_eq!(TextCount::default().reading_minutes(), 0);
+    }
+}
@@ -0,0 +1,241 @@
+//! Protection against forms posted from other sites. Every visitor gets a random token in a
+//! cookie, which has to be sent back with every POST as the `_csrf` form field. Multipart forms
+//! send it in the query instead, so uploads don't have to be buffered. Requests with a valid API
+//! token are left alone: other sites can't make browsers send one, scripts have no cookie to
+//! compare with, and cookie sessions aren't accepted with a token. Other `Authorization` headers,
+//! like the Basic credentials of a proxy, are sent by browsers along with any form, so they are
+//! checked like the rest
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::{cookie::Cookie, CookieJar};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+
+use crate::{db::Backend, routes::App, token};
+
+const CSRF_COOKIE: &str = "csrf";
+const CSRF_FIELD: &str = "_csrf";
+/// Header for scripts, which can't add form fields
+const CSRF_HEADER: &str = "x-csrf-token";
+/// Same as axum's default limit for forms
+const FORM_LIMIT: usize = 2 * 1024 * 1024;
+
+tokio::task_local! {
+    static TOKEN: String;
+}
+
+/// Token of the request being handled, for the templates
+pub fn token() -> String {
+    TOKEN.try_with(Clone::clone).unwrap_or_default()
+}
+
+/// Middleware rejecting POSTs without the right token and handing out tokens to new visitors
+pub async fn protect<B: Backend + 'static>(
+    State(state): State<App<B>>,
+    jar: CookieJar,
+    req: Request,
+    next: Next,
+) -> Response {
+    let existing = jar
+        .get(CSRF_COOKIE)
+        .map(|c| c.value().to_string())
+        .filter(|t| !t.is_empty());
+
+    let scripted = token::bearer(req.headers())
+        .is_some_and(|secret| state.members.tokens.authenticate(secret).is_some());
+    let req = if req.method() == Method::POST && !scripted {
+        let (parts, body) = req.into_parts();
+        let content_type = parts.headers.get(header::CONTENT_TYPE);
+        let is_form = content_type.is_some_and(|t| {
+            t.as_bytes()
+                .starts_with(b"application/x-www-form-urlencoded")
+        });
+        // Anywhere else the token would end up in logs, history and Referer headers
+        let is_multipart =
+            content_type.is_some_and(|t| t.as_bytes().starts_with(b"multipart/form-data"));
+        // Urlencoded forms are buffered to read the field and then put back as they were
+        let (body, form) = if is_form {
+            match to_bytes(body, FORM_LIMIT).await {
+                Ok(bytes) => (Body::from(bytes.clone()), Some(bytes)),
+                Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+            }
+        } else {
+            (body, None)
+        };
+
+        let sent = parts
+            .headers
+            .get(CSRF_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| {
+                let query = parts.uri.query().filter(|_| is_multipart);
+                field(query.unwrap_or_default().as_bytes())
+            })
+            .or_else(|| form.and_then(|form| field(&form)));
+        let valid = match (&existing, &sent) {
+            (Some(expected), Some(sent)) => bool::from(expected.as_bytes().ct_eq(sent.as_bytes())),
+            _ => false,
+        };
+        if !valid {
+            return (
+                StatusCode::FORBIDDEN,
+                "The form has expired. Go back, reload the page and try again",
+            )
+                .into_response();
+        }
+        Request::from_parts(parts, body)
+    } else {
+        req
+    };
+
+    match existing {
+        Some(token) => TOKEN.scope(token, next.run(req)).await,
+        None => {
+            let token = generate();
+            let cookie = state
+                .cookies
+                .harden(Cookie::new(CSRF_COOKIE, token.clone()));
+            let res = TOKEN.scope(token, next.run(req)).await;
+            (jar.add(cookie), res).into_response()
+        }
+    }
+}
+
+fn field(urlencoded: &[u8]) -> Option<String> {
+    url::form_urlencoded::parse(urlencoded)
+        .find(|(k, _)| k == CSRF_FIELD)
+        .map(|(_, v)| v.into_owned())
+}
+
+fn generate() -> String {
+    let mut bytes = [0; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::auth::test::app;
+
+    const TOKEN: &str = "token";
+
+    fn router(app: &App<sled::Db>) -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(|| async { token() }).post(|body: String| async { body }),
+            )
+            .layer(middleware::from_fn_with_state(
+                app.clone(),
+                protect::<sled::Db>,
+            ))
+    }
+
+    async fn post(uri: &str, headers: &[(&str, &str)], body: &str) -> (StatusCode, String) {
+        post_to(&app(), uri, headers, body).await
+    }
+
+    async fn post_to(
+        app: &App<sled::Db>,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> (StatusCode, String) {
+        let mut req = Request::post(uri).header(header::COOKIE, format!("{CSRF_COOKIE}={TOKEN}"));
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let res = router(app)
+            .oneshot(req.body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), FORM_LIMIT).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    const FORM: (&str, &str) = ("content-type", "application/x-www-form-urlencoded");
+
+    #[tokio::test]
+    async fn new_visitors_get_a_token() {
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let res = router(&app()).oneshot(req).await.unwrap();
+        let cookie = res.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = to_bytes(res.into_body(), FORM_LIMIT).await.unwrap();
+        let token = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(token.len(), 43);
+        assert!(cookie.starts_with(&format!("{CSRF_COOKIE}={token};")));
+        assert!(cookie.contains("Path=/"));
+        assert!(cookie.contains("HttpOnly"));
+        assert!(cookie.contains("Secure"));
+    }
+
+    #[tokio::test]
+    async fn forms_need_the_token() {
+        let (status, _) = post("/", &[FORM], "a=1").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = post("/", &[FORM], "a=1&_csrf=wrong").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        // The handler still gets the whole form
+        let (status, body) = post("/", &[FORM], "a=1&_csrf=token").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "a=1&_csrf=token"));
+    }
+
+    #[tokio::test]
+    async fn scripts_send_the_header() {
+        let (status, _) = post("/", &[(CSRF_HEADER, "wrong")], "{}").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = post("/", &[(CSRF_HEADER, TOKEN)], "{}").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn only_uploads_use_the_query() {
+        let (status, _) = post("/?_csrf=token", &[FORM], "a=1").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = post("/?_csrf=token", &[], "{}").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let multipart = ("content-type", "multipart/form-data; boundary=x");
+        let (status, _) = post("/?_csrf=token", &[multipart], "").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn only_valid_api_tokens_skip_the_check() {
+        let app = app();
+        let user = app
+            .members
+            .create_user("reader".into(), "correct horse".into(), Default::default())
+            .unwrap();
+        let secret = app
+            .members
+            .tokens
+            .create(user, "Script", vec![crate::token::Scope::ReadLibrary])
+            .unwrap();
+        let auth = format!("Bearer {secret}");
+        let (status, _) = post_to(&app, "/", &[("authorization", &auth)], "{}").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = post_to(&app, "/", &[("authorization", "Bearer tsk_a")], "{}").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        // Browsers send a proxy's Basic credentials with forms from anywhere
+        let basic = ("authorization", "Basic YTpi");
+        let (status, _) = post_to(&app, "/", &[basic, FORM], "a=1").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
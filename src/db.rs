@@ -1,6 +1,7 @@
 use std::path::Path;
 
 use anyhow::Result;
+use uuid::Uuid;
 
 pub trait Table: Sized + Send + Sync {
     /// Iterator over all keys in the table
@@ -11,19 +12,30 @@ pub trait Table: Sized + Send + Sync {
 
     /// Iterator over all values in the table
     fn values(&self) -> impl Iterator<Item = impl AsRef<[u8]>> {
-        self.keys().map(|k| self.get_value(k)).flatten()
+        self.keys().filter_map(|k| self.get_value(k))
     }
 
     fn iter(&self) -> impl Iterator<Item = (impl AsRef<[u8]>, impl AsRef<[u8]>)> {
         self.keys().zip(self.values())
     }
 
+    /// Iterator over the pairs whose key starts with `prefix`, in key order
+    fn scan_prefix<P: AsRef<[u8]>>(
+        &self,
+        prefix: P,
+    ) -> impl Iterator<Item = (impl AsRef<[u8]>, impl AsRef<[u8]>)> {
+        self.iter()
+            .filter(move |(k, _)| k.as_ref().starts_with(prefix.as_ref()))
+    }
+
     /// If there are any keys in the table
+    #[allow(dead_code)]
     fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
     /// Number of keys in the table
+    #[allow(dead_code)]
     fn len(&self) -> usize {
         self.keys().count()
     }
@@ -33,6 +45,29 @@ pub trait Table: Sized + Send + Sync {
 
     /// Removes a key-value pair if it exists
     fn remove<K: AsRef<[u8]>>(&self, k: K) -> Option<impl AsRef<[u8]>>;
+
+    /// Atomically replaces the value of `k` with `new` if it is still `old`. `None` is no value,
+    /// so `old: None` only inserts a new key and `new: None` removes it. Returns whether it did
+    fn compare_and_swap<K: AsRef<[u8]>>(
+        &self,
+        k: K,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> bool;
+
+    /// Ids filed under `owner` in an index table, in order. See [`index_key`]
+    fn indexed(&self, owner: Uuid) -> impl Iterator<Item = Uuid> {
+        self.scan_prefix(*owner.as_bytes())
+            .filter_map(|(key, _)| Uuid::from_slice(key.as_ref().get(16..)?).ok())
+    }
+}
+
+/// Key filing `id` under `owner` in an index table, e.g. a user's sessions. The values are empty
+pub fn index_key(owner: Uuid, id: Uuid) -> [u8; 32] {
+    let mut key = [0; 32];
+    key[..16].copy_from_slice(owner.as_bytes());
+    key[16..].copy_from_slice(id.as_bytes());
+    key
 }
 
 /// Relational DB abstraction
@@ -46,12 +81,14 @@ pub trait Backend: Sized + Send + Sync {
     fn open(p: impl AsRef<Path>) -> Result<Self>;
 
     /// Iterate over all table names
+    #[allow(dead_code)]
     fn tables(&self) -> Vec<String>;
 
     /// table
     fn get_table(&self, table: &str) -> Result<Self::OutTable>;
 
     /// Permanently erases a table
+    #[allow(dead_code)]
     fn drop_table(&self, table: &str) -> Result<()>;
 }
 
@@ -69,20 +106,36 @@ pub mod sled_backend {
             self.get(k).ok().flatten()
         }
 
+        fn scan_prefix<P: AsRef<[u8]>>(
+            &self,
+            prefix: P,
+        ) -> impl Iterator<Item = (impl AsRef<[u8]>, impl AsRef<[u8]>)> {
+            Tree::scan_prefix(self, prefix).filter_map(|r| r.ok())
+        }
+
         fn is_empty(&self) -> bool {
-            Tree::is_empty(&self)
+            Tree::is_empty(self)
         }
 
         fn len(&self) -> usize {
-            Tree::len(&self)
+            Tree::len(self)
         }
 
         fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, k: K, v: V) -> Option<impl AsRef<[u8]>> {
-            Tree::insert(&self, k, v.as_ref()).ok().flatten()
+            Tree::insert(self, k, v.as_ref()).ok().flatten()
         }
 
         fn remove<K: AsRef<[u8]>>(&self, k: K) -> Option<impl AsRef<[u8]>> {
-            Tree::remove(&self, k).ok().flatten()
+            Tree::remove(self, k).ok().flatten()
+        }
+
+        fn compare_and_swap<K: AsRef<[u8]>>(
+            &self,
+            k: K,
+            old: Option<&[u8]>,
+            new: Option<&[u8]>,
+        ) -> bool {
+            matches!(Tree::compare_and_swap(self, k, old, new), Ok(Ok(())))
         }
     }
 
@@ -106,8 +159,14 @@ pub mod sled_backend {
         }
 
         fn drop_table(&self, table: &str) -> Result<()> {
-            Db::drop_tree(&self, table)?;
+            Db::drop_tree(self, table)?;
             Ok(())
         }
     }
 }
+
+/// An empty db, deleted when it is dropped
+#[cfg(test)]
+pub fn temporary() -> sled::Db {
+    sled::Config::new().temporary(true).open().unwrap()
+}
@@ -0,0 +1,55 @@
+//! Plain text form of a chapter used by the authoring pages
+//!
+//! Every line is a paragraph and blank lines are ignored. Ruby uses the same notation as the stored
+//! text (`｜漢字《かんじ》`). Images can't be typed, so each one is shown as a `［＃画像N］` line which
+//! can be moved or deleted to move or delete the image
+
+use crate::entry::Entry;
+
+const IMAGE_START: &str = "［＃画像";
+const IMAGE_END: &str = "］";
+
+/// Converts the entries of a chapter to editable text
+pub fn to_text(elements: &[Entry]) -> String {
+    let mut image = 0;
+    let lines: Vec<_> = elements
+        .iter()
+        .map(|e| match e {
+            Entry::Paragraph(p) => p.clone(),
+            Entry::Image(_) => {
+                image += 1;
+                format!("{IMAGE_START}{image}{IMAGE_END}")
+            }
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Converts edited text back into entries. `old` are the entries before editing, which the image
+/// placeholders refer to
+pub fn from_text(text: &str, old: &[Entry]) -> Vec<Entry> {
+    let images: Vec<_> = old
+        .iter()
+        .filter_map(|e| match e {
+            Entry::Image(data) => Some(data),
+            Entry::Paragraph(_) => None,
+        })
+        .collect();
+
+    text.lines()
+        .map(|l| l.trim_end())
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let image = l
+                .trim()
+                .strip_prefix(IMAGE_START)
+                .and_then(|l| l.strip_suffix(IMAGE_END))
+                .and_then(|n| n.parse::<usize>().ok())
+                .and_then(|n| images.get(n.checked_sub(1)?));
+            match image {
+                Some(data) => Entry::Image((*data).clone()),
+                None => Entry::Paragraph(l.to_string()),
+            }
+        })
+        .collect()
+}
@@ -6,10 +6,10 @@ use bincode::{Decode, Encode};
 use chrono::{DateTime, Utc};
 use fakedata::{random_name, random_title_desc};
 use rand::Rng;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::{stats::Statistics, user::UserRef};
+use crate::{count::TextCount, stats::Statistics, user::UserRef};
 
 /// A written Work (novel/comic/etc.). It can contain text and images
 // TODO: See if this is even possible w/ borrow checker and sled's db
@@ -22,6 +22,17 @@ pub struct LiteraryWork {
     pub creators: Vec<UserRef>,
     pub tags: Vec<Tag>,
 
+    /// Layout used for the chapters unless the reader overrides it
+    pub writing_mode: WritingMode,
+
+    /// In cents of the shop's currency. Free works have none
+    pub price: Option<u32>,
+    /// Every chapter can be read by anyone until then
+    pub free_until: Option<SystemTime>,
+
+    /// Sum of the counts of every chapter
+    pub count: TextCount,
+
     // Dates
     pub publish: SystemTime,
     pub update: SystemTime,
@@ -29,6 +40,29 @@ pub struct LiteraryWork {
     pub stats: Statistics,
 }
 
+/// Direction in which the text of a work flows
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+#[serde(rename_all = "kebab-case")]
+pub enum WritingMode {
+    /// Left-to-right lines stacked top-to-bottom
+    #[default]
+    Horizontal,
+    /// Top-to-bottom lines stacked right-to-left (tategaki)
+    Vertical,
+}
+
+/// Who may read a chapter
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChapterAccess {
+    #[default]
+    Free,
+    /// Signed in users
+    Members,
+    /// Users who own the work
+    Purchase,
+}
+
 #[derive(Serialize, Encode, Decode)]
 pub enum Tag {
     Genre(String),
@@ -46,6 +80,75 @@ pub struct Chapter {
     pub elements: Vec<Entry>,
     #[bincode(with_serde)]
     pub date: DateTime<Utc>,
+    pub count: TextCount,
+    /// Bumped every time the text is edited, so annotations know when to look for their passage
+    pub revision: u32,
+    pub access: ChapterAccess,
+}
+
+impl LiteraryWork {
+    /// An empty work published now
+    pub fn new(title: String, description: String) -> Self {
+        Self {
+            title,
+            description,
+            chapters: vec![],
+            creators: vec![],
+            tags: vec![],
+            writing_mode: WritingMode::default(),
+            price: None,
+            free_until: None,
+            count: TextCount::default(),
+            publish: SystemTime::now(),
+            update: SystemTime::now(),
+            stats: Statistics::default(),
+        }
+    }
+
+    /// If the user with this id is credited
+    pub fn is_creator(&self, user: Uuid) -> bool {
+        self.creators.iter().any(|c| c.id == Some(user))
+    }
+
+    /// What it takes to read `chapter` at `now`. Campaigns make every chapter free, and chapters
+    /// of works without a price can't be bought so they only need an account
+    pub fn access_to(&self, chapter: &Chapter, now: SystemTime) -> ChapterAccess {
+        if self.free_until.is_some_and(|end| now < end) {
+            return ChapterAccess::Free;
+        }
+        match chapter.access {
+            ChapterAccess::Purchase if self.price.is_none() => ChapterAccess::Members,
+            access => access,
+        }
+    }
+
+    /// Recomputes the counts of every chapter and the work. Must be called after the chapters change
+    pub fn recount(&mut self) {
+        self.count = TextCount::default();
+        for chapter in &mut self.chapters {
+            chapter.recount();
+            self.count += chapter.count;
+        }
+    }
+}
+
+impl Chapter {
+    pub fn new(title: String, elements: Vec<Entry>) -> Self {
+        let count = TextCount::of_entries(&elements);
+        Self {
+            id: Uuid::now_v7(),
+            title,
+            elements,
+            date: Utc::now(),
+            count,
+            revision: 0,
+            access: ChapterAccess::Free,
+        }
+    }
+
+    pub fn recount(&mut self) {
+        self.count = TextCount::of_entries(&self.elements);
+    }
 }
 
 /// Rendered in a sequence; no fancy formatting
@@ -60,12 +163,8 @@ pub fn create_rand_work() -> LiteraryWork {
     let mut rng = rand::thread_rng();
     let (title, description) = random_title_desc();
 
-    // TODO: First need to change User to be keyed by UUID
     let creators: Vec<_> = (1..=rng.gen_range(1..=3))
-        .map(|_| UserRef {
-            name: random_name(),
-            created: SystemTime::now(),
-        })
+        .map(|_| UserRef::external(random_name()))
         .collect();
 
     let chapters = (1..=rng.gen_range(1..100)).map(|i| {
@@ -73,21 +172,51 @@ pub fn create_rand_work() -> LiteraryWork {
             let s = "証ケオヨホ売4面ヨツサリ教家ク供哲目いッご朝育えず頭高イで込月メラロ理新スト木使やむんば日月5創船断おちもき。友ソヤナ表申ひはでろ刊不滅え探剤リて到法ムケナユ率者や障婚んぞれ北7太場レ著保で文提手ワヒヱメ無匹恒めのざほ。討興ネチ元9豊ニカ億張すてぼぜ埋野舗ぼこづは料読キヲマ反8梨ぶ宮吉ぐごょフ爺聞華ヤヱム滋極たクわ一携ヤサワテ供著近種だねど。";
             Entry::Paragraph(s.to_string())
         }).collect();
-        Chapter {
-            id: Uuid::now_v7(),
-            title: format!("Chapter {i}"),
-            elements,
-            date: Utc::now(),
-        }
+        Chapter::new(format!("Chapter {i}"), elements)
     }).collect();
 
-    LiteraryWork {
+    let mut work = LiteraryWork {
         title,
         description,
         chapters,
         creators,
         tags: vec![],
+        writing_mode: if rng.gen_bool(0.5) {
+            WritingMode::Vertical
+        } else {
+            WritingMode::Horizontal
+        },
+        price: None,
+        free_until: None,
+        count: TextCount::default(),
         publish: SystemTime::now(),
         update: SystemTime::now(),
+        stats: Statistics::default(),
+    };
+    work.recount();
+    work
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn campaigns_and_prices_change_access() {
+        let mut work = LiteraryWork::new(String::new(), String::new());
+        let mut chapter = Chapter::new(String::new(), vec![]);
+        chapter.access = ChapterAccess::Purchase;
+        let now = SystemTime::now();
+        assert_eq!(work.access_to(&chapter, now), ChapterAccess::Members);
+
+        work.price = Some(300);
+        assert_eq!(work.access_to(&chapter, now), ChapterAccess::Purchase);
+
+        work.free_until = Some(now + Duration::from_secs(60));
+        assert_eq!(work.access_to(&chapter, now), ChapterAccess::Free);
+        let later = now + Duration::from_secs(120);
+        assert_eq!(work.access_to(&chapter, later), ChapterAccess::Purchase);
     }
 }
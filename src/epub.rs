@@ -0,0 +1,234 @@
+//! EPUB 3 export of a work
+
+use std::{
+    fmt::Write as _,
+    io::{Cursor, Write},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use minijinja::HtmlEscape;
+use uuid::Uuid;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    entry::{Entry, LiteraryWork, Tag, WritingMode},
+    render,
+    utils::image_mime,
+};
+
+const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+const STYLE: &str = r#"ruby rt { font-size: 0.5em; }
+.tcy { text-combine-upright: all; -epub-text-combine: horizontal; }
+.vertical { writing-mode: vertical-rl; -epub-writing-mode: vertical-rl; }
+"#;
+
+/// A file stored in `OEBPS/`
+struct Item {
+    id: String,
+    href: String,
+    media_type: &'static str,
+    data: Vec<u8>,
+}
+
+/// Builds the EPUB archive of `work`. `mode` decides the writing mode and page progression
+pub fn export(id: Uuid, work: &LiteraryWork, mode: WritingMode) -> Result<Vec<u8>> {
+    // Heuristic, since works don't record their language
+    let lang = if work.count.cjk >= work.count.words {
+        "ja"
+    } else {
+        "en"
+    };
+
+    let mut items = vec![Item {
+        id: "style".to_string(),
+        href: "style.css".to_string(),
+        media_type: "text/css",
+        data: STYLE.as_bytes().to_vec(),
+    }];
+    let mut spine = Vec::with_capacity(work.chapters.len());
+    let mut toc = String::new();
+
+    for (i, chapter) in work.chapters.iter().enumerate() {
+        let mut body = String::new();
+        for (j, e) in chapter.elements.iter().enumerate() {
+            match e {
+                Entry::Paragraph(p) => {
+                    writeln!(body, "<p>{}</p>", render::paragraph(p, mode))?;
+                }
+                Entry::Image(data) => {
+                    // Anything a reading system can't display is left out
+                    let Some(media_type) = image_mime(data) else {
+                        continue;
+                    };
+                    let ext = media_type.trim_start_matches("image/");
+                    let href = format!("images/{i}-{j}.{ext}");
+                    writeln!(body, r#"<p><img src="{href}" alt=""/></p>"#)?;
+                    items.push(Item {
+                        id: format!("image-{i}-{j}"),
+                        href,
+                        media_type,
+                        data: data.clone(),
+                    });
+                }
+            }
+        }
+
+        let href = format!("chapter-{i}.xhtml");
+        let title = HtmlEscape(&chapter.title);
+        writeln!(toc, r#"<li><a href="{href}">{title}</a></li>"#)?;
+        items.push(Item {
+            id: format!("chapter-{i}"),
+            href,
+            media_type: "application/xhtml+xml",
+            data: xhtml(
+                lang,
+                &chapter.title,
+                mode,
+                &format!("<h2>{title}</h2>\n{body}"),
+            )
+            .into_bytes(),
+        });
+        spine.push(format!("chapter-{i}"));
+    }
+
+    let nav = format!(
+        "<nav epub:type=\"toc\" id=\"toc\">\n<h1>{}</h1>\n<ol>\n{toc}</ol>\n</nav>",
+        HtmlEscape(&work.title)
+    );
+    items.push(Item {
+        id: "nav".to_string(),
+        href: "nav.xhtml".to_string(),
+        media_type: "application/xhtml+xml",
+        data: xhtml(lang, &work.title, mode, &nav).into_bytes(),
+    });
+
+    let opf = package(id, work, lang, mode, &items, &spine);
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    // The mimetype must come first and be stored uncompressed
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"application/epub+zip")?;
+
+    let deflated = SimpleFileOptions::default();
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(CONTAINER.as_bytes())?;
+    zip.start_file("OEBPS/content.opf", deflated)?;
+    zip.write_all(opf.as_bytes())?;
+    for item in items {
+        zip.start_file(format!("OEBPS/{}", item.href), deflated)?;
+        zip.write_all(&item.data)?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+fn xhtml(lang: &str, title: &str, mode: WritingMode, body: &str) -> String {
+    let class = match mode {
+        WritingMode::Horizontal => "horizontal",
+        WritingMode::Vertical => "vertical",
+    };
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xml:lang="{lang}" lang="{lang}" class="{class}">
+<head>
+<meta charset="UTF-8"/>
+<title>{}</title>
+<link rel="stylesheet" type="text/css" href="style.css"/>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        HtmlEscape(title)
+    )
+}
+
+/// The OPF package document
+fn package(
+    id: Uuid,
+    work: &LiteraryWork,
+    lang: &str,
+    mode: WritingMode,
+    items: &[Item],
+    spine: &[String],
+) -> String {
+    let modified: DateTime<Utc> = work.update.into();
+
+    let mut metadata = String::new();
+    writeln!(
+        metadata,
+        r#"<dc:identifier id="uid">urn:uuid:{id}</dc:identifier>"#
+    )
+    .unwrap();
+    writeln!(metadata, "<dc:title>{}</dc:title>", HtmlEscape(&work.title)).unwrap();
+    writeln!(metadata, "<dc:language>{lang}</dc:language>").unwrap();
+    for c in &work.creators {
+        writeln!(metadata, "<dc:creator>{}</dc:creator>", HtmlEscape(&c.name)).unwrap();
+    }
+    for tag in &work.tags {
+        let (Tag::Genre(t) | Tag::Other(t)) = tag;
+        writeln!(metadata, "<dc:subject>{}</dc:subject>", HtmlEscape(t)).unwrap();
+    }
+    if !work.description.is_empty() {
+        writeln!(
+            metadata,
+            "<dc:description>{}</dc:description>",
+            HtmlEscape(&work.description)
+        )
+        .unwrap();
+    }
+    writeln!(
+        metadata,
+        r#"<meta property="dcterms:modified">{}</meta>"#,
+        modified.format("%Y-%m-%dT%H:%M:%SZ")
+    )
+    .unwrap();
+
+    let mut manifest = String::new();
+    for item in items {
+        let properties = if item.id == "nav" {
+            r#" properties="nav""#
+        } else {
+            ""
+        };
+        writeln!(
+            manifest,
+            r#"<item id="{}" href="{}" media-type="{}"{properties}/>"#,
+            item.id, item.href, item.media_type
+        )
+        .unwrap();
+    }
+
+    let itemrefs: String = spine
+        .iter()
+        .map(|id| format!("<itemref idref=\"{id}\"/>\n"))
+        .collect();
+    let progression = match mode {
+        WritingMode::Horizontal => "ltr",
+        WritingMode::Vertical => "rtl",
+    };
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid" xml:lang="{lang}">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+{metadata}</metadata>
+<manifest>
+{manifest}</manifest>
+<spine page-progression-direction="{progression}">
+{itemrefs}</spine>
+</package>
+"#
+    )
+}
@@ -4,6 +4,10 @@ use axum::{http::StatusCode, response::IntoResponse};
 pub enum LibraryError {
     /// If the book does not exist
     Missing(String),
+    /// If the user isn't allowed to access the book
+    Forbidden(String),
+    /// If the user's role doesn't allow an action
+    Denied(String),
 }
 
 impl std::fmt::Display for LibraryError {
@@ -12,6 +16,12 @@ impl std::fmt::Display for LibraryError {
             LibraryError::Missing(title) => {
                 f.write_str(&format!("{} does not exist in the library!", title))
             }
+            LibraryError::Forbidden(title) => {
+                f.write_str(&format!("{} is not available to this user!", title))
+            }
+            LibraryError::Denied(action) => {
+                f.write_str(&format!("{} is not allowed for this user!", action))
+            }
         }
     }
 }
@@ -20,10 +30,16 @@ impl std::error::Error for LibraryError {}
 
 impl IntoResponse for LibraryError {
     fn into_response(self) -> axum::response::Response {
-        (
-            StatusCode::NOT_FOUND,
-            format!("The requested work was not found"),
-        )
-            .into_response()
+        match self {
+            LibraryError::Missing(_) => {
+                (StatusCode::NOT_FOUND, "The requested work was not found").into_response()
+            }
+            LibraryError::Forbidden(_) => {
+                (StatusCode::FORBIDDEN, "You don't have access to this work").into_response()
+            }
+            LibraryError::Denied(_) => {
+                (StatusCode::FORBIDDEN, "You aren't allowed to do that").into_response()
+            }
+        }
     }
 }
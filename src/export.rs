@@ -0,0 +1,125 @@
+//! Archive of everything a user has put into the site, so they can take it elsewhere. It holds
+//! `account.json` and an EPUB of every work they created under `works/`
+
+use std::{
+    io::{Cursor, Write},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+use crate::{
+    annotation::AnnotationKind,
+    notification::FollowKind,
+    shop::{OrderItem, OrderStatus},
+    user::{PreviousName, ReaderSettings, Role, UserLibrary},
+};
+
+#[derive(Serialize)]
+pub struct Account {
+    pub id: Uuid,
+    pub name: String,
+    /// Confirmed address
+    pub email: Option<String>,
+    pub previous_names: Vec<PreviousName>,
+    pub created: DateTime<Utc>,
+    pub role: Role,
+    pub settings: ReaderSettings,
+    /// Owned works, reading progress and shelves
+    pub library: UserLibrary,
+    pub reviews: Vec<Review>,
+    pub comments: Vec<Comment>,
+    pub annotations: Vec<Annotation>,
+    pub following: Vec<Follow>,
+    pub orders: Vec<Order>,
+    pub works: Vec<Work>,
+}
+
+#[derive(Serialize)]
+pub struct Review {
+    pub work: Uuid,
+    pub stars: u8,
+    pub text: String,
+    pub spoiler: bool,
+    pub created: DateTime<Utc>,
+    pub edited: Option<DateTime<Utc>>,
+    pub helpful_votes: usize,
+}
+
+#[derive(Serialize)]
+pub struct Comment {
+    pub id: Uuid,
+    pub work: Uuid,
+    pub chapter: Uuid,
+    pub parent: Option<Uuid>,
+    pub text: String,
+    pub created: DateTime<Utc>,
+    pub edited: Option<DateTime<Utc>>,
+    pub deleted: bool,
+    pub hidden: bool,
+}
+
+#[derive(Serialize)]
+pub struct Annotation {
+    pub work: Uuid,
+    pub chapter: Uuid,
+    pub kind: AnnotationKind,
+    pub quote: String,
+    pub note: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct Follow {
+    pub id: Uuid,
+    pub kind: FollowKind,
+    pub since: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct Order {
+    pub id: Uuid,
+    pub items: Vec<OrderItem>,
+    pub total: u64,
+    pub currency: String,
+    pub status: OrderStatus,
+    pub provider: String,
+    pub reference: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+/// A work the user created, exported as an EPUB
+#[derive(Serialize)]
+pub struct Work {
+    pub id: Uuid,
+    pub title: String,
+    /// Path in the archive
+    pub file: String,
+    #[serde(skip)]
+    pub epub: Vec<u8>,
+}
+
+pub fn time(t: SystemTime) -> DateTime<Utc> {
+    DateTime::from(t)
+}
+
+/// Zips up the account
+pub fn archive(account: &Account) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+    zip.start_file("account.json", options)?;
+    zip.write_all(&serde_json::to_vec_pretty(account)?)?;
+    for work in &account.works {
+        // EPUBs are compressed already
+        zip.start_file(
+            work.file.as_str(),
+            options.compression_method(zip::CompressionMethod::Stored),
+        )?;
+        zip.write_all(&work.epub)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
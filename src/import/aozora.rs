@@ -0,0 +1,196 @@
+//! Aozora Bunko style text files
+//!
+//! The first lines hold the title and author, followed by an optional block of notes between two
+//! rules of dashes. The body uses `《》` for ruby (which is stored as-is) and `［＃…］` for
+//! annotations, of which only headings and page breaks are understood. Everything from `底本：`
+//! onwards is bibliographic information and is dropped.
+
+use anyhow::{bail, Result};
+
+use crate::{
+    entry::{Chapter, Entry, LiteraryWork, WritingMode},
+    user::UserRef,
+};
+
+pub fn import(text: &str) -> Result<LiteraryWork> {
+    let mut lines = text.lines().map(|l| l.trim_end()).peekable();
+
+    // Title, then optionally a subtitle and the author, up to the first blank line or the rule
+    // starting the notes, which is left for the body
+    while lines.next_if(|l| l.is_empty()).is_some() {}
+    let header: Vec<_> =
+        std::iter::from_fn(|| lines.next_if(|l| !l.is_empty() && !is_rule(l))).collect();
+    let Some(title) = header.first() else {
+        bail!("The file is empty");
+    };
+    let title = strip_annotations(title);
+    let creators = match header.last() {
+        Some(author) if header.len() > 1 => vec![UserRef::external(strip_annotations(author))],
+        _ => vec![],
+    };
+
+    let mut body: Vec<_> = lines.collect();
+    // Notes about the notation
+    if let Some(start) = body.iter().position(|l| is_rule(l)) {
+        if let Some(len) = body[start + 1..].iter().position(|l| is_rule(l)) {
+            body.drain(start..=start + 1 + len);
+        }
+    }
+    if let Some(end) = body.iter().position(|l| l.starts_with("底本：")) {
+        body.truncate(end);
+    }
+
+    let mut chapters = vec![];
+    let mut chapter_title: Option<String> = None;
+    let mut elements = vec![];
+    let mut push_chapter = |title: Option<String>, elements: Vec<Entry>| {
+        if title.is_some() || !elements.is_empty() {
+            let n = chapters.len() + 1;
+            let title = title.unwrap_or_else(|| format!("Chapter {n}"));
+            chapters.push(Chapter::new(title, elements));
+        }
+    };
+
+    for line in body {
+        if line.contains("［＃改ページ］") || line.contains("［＃改丁］") {
+            push_chapter(chapter_title.take(), std::mem::take(&mut elements));
+        } else if is_heading(line) {
+            push_chapter(chapter_title.take(), std::mem::take(&mut elements));
+            chapter_title = Some(strip_annotations(line).trim().to_string());
+        } else {
+            let text = strip_annotations(line);
+            if !text.trim().is_empty() {
+                elements.push(Entry::Paragraph(text));
+            }
+        }
+    }
+    push_chapter(chapter_title, elements);
+
+    let mut work = LiteraryWork::new(title, String::new());
+    work.creators = creators;
+    work.chapters = chapters;
+    work.writing_mode = WritingMode::Vertical;
+    Ok(work)
+}
+
+fn is_rule(line: &str) -> bool {
+    line.len() >= 10 && line.chars().all(|c| c == '-')
+}
+
+/// `第一章［＃「第一章」は大見出し］` or `［＃中見出し］第一章［＃中見出し終わり］`
+fn is_heading(line: &str) -> bool {
+    ["大見出し", "中見出し"]
+        .iter()
+        .any(|h| line.contains(&format!("{h}］")))
+}
+
+/// Removes every `［＃…］` annotation
+fn strip_annotations(line: &str) -> String {
+    let mut res = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("［＃") {
+        res.push_str(&rest[..start]);
+        match rest[start..].find('］') {
+            Some(end) => rest = &rest[start + end + '］'.len_utf8()..],
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn paragraphs(chapter: &Chapter) -> Vec<&str> {
+        chapter
+            .elements
+            .iter()
+            .filter_map(|e| match e {
+                Entry::Paragraph(p) => Some(p.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_file() {
+        let text = "\
+羅生門
+芥川龍之介
+
+-------------------------------------------------------
+【テキスト中に現れる記号について】
+《》：ルビ
+-------------------------------------------------------
+
+［＃中見出し］一［＃中見出し終わり］
+ある日の暮方の事である。一人の｜下人《げにん》が、
+雨やみを待っていた。
+［＃改ページ］
+二［＃「二」は大見出し］
+広い門の下には、この男のほかに誰もいない。
+
+底本：「芥川龍之介全集1」ちくま文庫
+";
+        let work = import(text).unwrap();
+        assert_eq!(work.title, "羅生門");
+        assert_eq!(work.creators.len(), 1);
+        assert_eq!(work.creators[0].name, "芥川龍之介");
+        assert_eq!(work.creators[0].id, None);
+        assert_eq!(work.writing_mode, WritingMode::Vertical);
+
+        let titles: Vec<_> = work.chapters.iter().map(|c| c.title.as_str()).collect();
+        assert_eq!(titles, ["一", "二"]);
+        assert_eq!(
+            paragraphs(&work.chapters[0]),
+            [
+                "ある日の暮方の事である。一人の｜下人《げにん》が、",
+                "雨やみを待っていた。"
+            ]
+        );
+        assert_eq!(
+            paragraphs(&work.chapters[1]),
+            ["広い門の下には、この男のほかに誰もいない。"]
+        );
+    }
+
+    #[test]
+    fn notes_right_after_the_header() {
+        let text = "\
+題名
+作者
+-------------------------------------------------------
+【テキスト中に現れる記号について】
+-------------------------------------------------------
+本文。
+";
+        let work = import(text).unwrap();
+        assert_eq!(work.title, "題名");
+        assert_eq!(work.creators[0].name, "作者");
+        assert_eq!(work.chapters.len(), 1);
+        assert_eq!(paragraphs(&work.chapters[0]), ["本文。"]);
+    }
+
+    #[test]
+    fn malformed_notation_is_kept() {
+        let text = "\
+題名
+
+閉じない｜ルビ《るび
+［＃注記が閉じない
+前［＃注記］後
+";
+        let work = import(text).unwrap();
+        assert!(work.creators.is_empty());
+        assert_eq!(
+            paragraphs(&work.chapters[0]),
+            ["閉じない｜ルビ《るび", "［＃注記が閉じない", "前後"]
+        );
+        assert!(import("\n\n").is_err());
+    }
+}
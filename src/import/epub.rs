@@ -0,0 +1,429 @@
+//! EPUB 2/3 files: the OPF metadata, the spine in order and the XHTML bodies
+
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+};
+
+use anyhow::{anyhow, bail, Result};
+use quick_xml::{
+    escape::resolve_html5_entity,
+    events::{BytesStart, Event},
+    Reader,
+};
+use zip::ZipArchive;
+
+use crate::{
+    entry::{Chapter, Entry, LiteraryWork, Tag, WritingMode},
+    user::UserRef,
+};
+
+type Archive<'a> = ZipArchive<Cursor<&'a [u8]>>;
+
+pub fn import(data: &[u8]) -> Result<LiteraryWork> {
+    let mut zip = ZipArchive::new(Cursor::new(data))?;
+
+    let container = read_string(&mut zip, "META-INF/container.xml")?;
+    let opf_path = find_rootfile(&container)?;
+    let opf = read_string(&mut zip, &opf_path)?;
+    let package = Package::parse(&opf)?;
+
+    let base = parent(&opf_path);
+    let mut chapters = vec![];
+    for idref in &package.spine {
+        let Some(item) = package.manifest.get(idref) else {
+            continue;
+        };
+        if !item.media_type.contains("html") {
+            continue;
+        }
+        let path = resolve(base, &item.href);
+        let xhtml = read_string(&mut zip, &path)?;
+        let (title, elements) = parse_body(&xhtml, parent(&path), &mut zip)?;
+
+        // Cover pages and the like
+        if elements.is_empty() {
+            continue;
+        }
+        let title = title.unwrap_or_else(|| format!("Chapter {}", chapters.len() + 1));
+        chapters.push(Chapter::new(title, elements));
+    }
+
+    let Some(title) = package.title else {
+        bail!("The EPUB has no title");
+    };
+    let mut work = LiteraryWork::new(title, package.description.unwrap_or_default());
+    work.creators = package
+        .creators
+        .into_iter()
+        .map(UserRef::external)
+        .collect();
+    work.tags = package.subjects.into_iter().map(Tag::Other).collect();
+    work.chapters = chapters;
+    work.writing_mode = if package.rtl {
+        WritingMode::Vertical
+    } else {
+        WritingMode::Horizontal
+    };
+    Ok(work)
+}
+
+struct ManifestItem {
+    href: String,
+    media_type: String,
+}
+
+/// The parts of the OPF we care about
+#[derive(Default)]
+struct Package {
+    title: Option<String>,
+    description: Option<String>,
+    creators: Vec<String>,
+    subjects: Vec<String>,
+    /// Keyed by id
+    manifest: HashMap<String, ManifestItem>,
+    /// Ids of the manifest items in reading order
+    spine: Vec<String>,
+    /// Pages turn right-to-left, i.e. the book is vertical
+    rtl: bool,
+}
+
+impl Package {
+    fn parse(opf: &str) -> Result<Self> {
+        let mut package = Package::default();
+        let mut reader = Reader::from_str(opf);
+        reader.config_mut().trim_text(true);
+
+        // Name of the metadata element whose text is being read
+        let mut field: Option<Vec<u8>> = None;
+        loop {
+            match reader.read_event()? {
+                Event::Start(e) => match e.local_name().as_ref() {
+                    name @ (b"title" | b"creator" | b"description" | b"subject") => {
+                        field = Some(name.to_vec())
+                    }
+                    b"spine" => package.rtl = attr(&e, b"page-progression-direction")? == "rtl",
+                    _ => {}
+                },
+                Event::Empty(e) => match e.local_name().as_ref() {
+                    b"item" => {
+                        package.manifest.insert(
+                            attr(&e, b"id")?,
+                            ManifestItem {
+                                href: attr(&e, b"href")?,
+                                media_type: attr(&e, b"media-type")?,
+                            },
+                        );
+                    }
+                    b"itemref" => package.spine.push(attr(&e, b"idref")?),
+                    b"spine" => package.rtl = attr(&e, b"page-progression-direction")? == "rtl",
+                    _ => {}
+                },
+                Event::Text(t) => {
+                    let text = t.unescape()?.into_owned();
+                    match field.as_deref() {
+                        Some(b"title") if package.title.is_none() => package.title = Some(text),
+                        Some(b"creator") => package.creators.push(text),
+                        Some(b"description") => package.description = Some(text),
+                        Some(b"subject") => package.subjects.push(text),
+                        _ => {}
+                    }
+                }
+                Event::End(_) => field = None,
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        Ok(package)
+    }
+}
+
+/// Collects the text of a chapter into paragraphs. Ruby is converted to the inline notation and
+/// images are read from the archive. Returns the first heading as the title
+fn parse_body(
+    xhtml: &str,
+    dir: &str,
+    zip: &mut Archive<'_>,
+) -> Result<(Option<String>, Vec<Entry>)> {
+    let mut reader = Reader::from_str(xhtml);
+    let config = reader.config_mut();
+    config.expand_empty_elements = true;
+    config.check_end_names = false;
+
+    let mut title: Option<String> = None;
+    let mut elements = vec![];
+    let mut current = String::new();
+
+    let mut in_body = false;
+    let mut in_heading = false;
+    // Inside <script>/<style>/<rp>
+    let mut skip = 0;
+    // (base, reading) of the ruby being read
+    let mut ruby: Option<(String, String)> = None;
+    let mut in_rt = false;
+
+    let flush = |current: &mut String, elements: &mut Vec<Entry>| {
+        let text = current.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !text.is_empty() {
+            elements.push(Entry::Paragraph(text));
+        }
+        current.clear();
+    };
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"body" => in_body = true,
+                b"script" | b"style" | b"rp" => skip += 1,
+                b"h1" | b"h2" | b"h3" | b"h4" | b"h5" | b"h6" => {
+                    flush(&mut current, &mut elements);
+                    in_heading = true;
+                }
+                b"p" | b"div" | b"li" | b"blockquote" | b"br" => flush(&mut current, &mut elements),
+                b"ruby" => ruby = Some(Default::default()),
+                b"rt" => in_rt = true,
+                b"img" | b"image" => {
+                    let Ok(src) = attr(&e, b"src").or_else(|_| attr(&e, b"xlink:href")) else {
+                        continue;
+                    };
+                    let mut data = vec![];
+                    if let Ok(mut file) = zip.by_name(&resolve(dir, &src)) {
+                        file.read_to_end(&mut data)?;
+                        flush(&mut current, &mut elements);
+                        elements.push(Entry::Image(data));
+                    }
+                }
+                _ => {}
+            },
+            Event::End(e) => match e.local_name().as_ref() {
+                b"body" => in_body = false,
+                b"script" | b"style" | b"rp" => skip -= 1,
+                b"h1" | b"h2" | b"h3" | b"h4" | b"h5" | b"h6" => {
+                    in_heading = false;
+                    let heading = current.split_whitespace().collect::<Vec<_>>().join(" ");
+                    current.clear();
+                    // Later headings are part of the text
+                    if title.is_none() {
+                        title = Some(heading);
+                    } else {
+                        current = heading;
+                        flush(&mut current, &mut elements);
+                    }
+                }
+                b"p" | b"div" | b"li" | b"blockquote" => flush(&mut current, &mut elements),
+                b"rt" => {
+                    in_rt = false;
+                    // Each <rt> closes an annotation, so <ruby>東<rt>とう</rt>京<rt>きょう</rt></ruby> works
+                    if let Some((base, reading)) = ruby.as_mut() {
+                        push_ruby(&mut current, base, reading, in_heading);
+                    }
+                }
+                b"ruby" => {
+                    if let Some((base, _)) = ruby.take() {
+                        current.push_str(&base);
+                    }
+                }
+                _ => {}
+            },
+            Event::Text(t) if in_body && skip == 0 => {
+                // XHTML files often use HTML entities like &nbsp;
+                let text = t.unescape_with(resolve_html5_entity)?;
+                match ruby.as_mut() {
+                    Some((_, reading)) if in_rt => reading.push_str(&text),
+                    Some((base, _)) => base.push_str(&text),
+                    None => current.push_str(&text),
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    flush(&mut current, &mut elements);
+
+    Ok((title.filter(|t| !t.is_empty()), elements))
+}
+
+/// Appends a ruby annotation. Headings become titles, which are shown without ruby
+fn push_ruby(current: &mut String, base: &mut String, reading: &mut String, plain: bool) {
+    if plain || reading.is_empty() {
+        current.push_str(base);
+    } else {
+        current.push_str(&format!("｜{base}《{reading}》"));
+    }
+    base.clear();
+    reading.clear();
+}
+
+fn find_rootfile(container: &str) -> Result<String> {
+    let mut reader = Reader::from_str(container);
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"rootfile" => {
+                return attr(&e, b"full-path");
+            }
+            Event::Eof => bail!("The EPUB has no package document"),
+            _ => {}
+        }
+    }
+}
+
+fn attr(e: &BytesStart<'_>, name: &[u8]) -> Result<String> {
+    let value = e
+        .try_get_attribute(name)?
+        .ok_or_else(|| anyhow!("Missing attribute {}", String::from_utf8_lossy(name)))?;
+    Ok(value.unescape_value()?.into_owned())
+}
+
+fn read_string(zip: &mut Archive<'_>, path: &str) -> Result<String> {
+    let mut s = String::new();
+    zip.by_name(path)?.read_to_string(&mut s)?;
+    Ok(s)
+}
+
+/// Directory of a path inside the archive
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+/// Resolves `href` relative to `dir`, both within the archive
+fn resolve(dir: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let mut parts: Vec<_> = dir.split('/').filter(|p| !p.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            ".." => {
+                parts.pop();
+            }
+            "." | "" => {}
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+    const OPF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>銀河鉄道の夜</dc:title>
+    <dc:creator>宮沢賢治</dc:creator>
+    <dc:subject>童話</dc:subject>
+    <dc:description>A &amp; B</dc:description>
+  </metadata>
+  <manifest>
+    <item id="cover" href="cover.xhtml" media-type="application/xhtml+xml"/>
+    <item id="c1" href="text/one.xhtml" media-type="application/xhtml+xml"/>
+    <item id="c2" href="text/two.xhtml" media-type="application/xhtml+xml"/>
+    <item id="img" href="images/map.png" media-type="image/png"/>
+  </manifest>
+  <spine page-progression-direction="rtl">
+    <itemref idref="cover"/>
+    <itemref idref="c2"/>
+    <itemref idref="c1"/>
+  </spine>
+</package>"#;
+
+    /// Its image isn't in the archive, so nothing is left of it
+    const COVER: &str =
+        r#"<html><body><div><img src="images/cover.png" alt=""/></div></body></html>"#;
+
+    const ONE: &str = r#"<html xmlns="http://www.w3.org/1999/xhtml"><head><title>x</title>
+<style>p { color: red }</style></head>
+<body>
+  <h1><ruby>午后<rt>ごご</rt></ruby>の授業</h1>
+  <p>「ではみなさんは、<ruby>東<rp>(</rp><rt>とう</rt><rp>)</rp>京<rt>きょう</rt></ruby>&nbsp;を」</p>
+  <p><img src="../images/map.png"/></p>
+  <h2>見出し</h2>
+  <p>おわり</p>
+</body></html>"#;
+
+    /// Unclosed tags and a ruby without a reading
+    const TWO: &str = r#"<html><body><p>始まり<ruby>空</ruby><p>閉じない<b>太字</p></body></html>"#;
+
+    fn epub(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn paragraphs(chapter: &Chapter) -> Vec<&str> {
+        chapter
+            .elements
+            .iter()
+            .filter_map(|e| match e {
+                Entry::Paragraph(p) => Some(p.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn book() {
+        let data = epub(&[
+            ("META-INF/container.xml", CONTAINER.as_bytes()),
+            ("OEBPS/content.opf", OPF.as_bytes()),
+            ("OEBPS/cover.xhtml", COVER.as_bytes()),
+            ("OEBPS/text/one.xhtml", ONE.as_bytes()),
+            ("OEBPS/text/two.xhtml", TWO.as_bytes()),
+            ("OEBPS/images/map.png", b"png"),
+        ]);
+        let work = import(&data).unwrap();
+        assert_eq!(work.title, "銀河鉄道の夜");
+        assert_eq!(work.description, "A & B");
+        assert_eq!(work.creators.len(), 1);
+        assert_eq!(work.creators[0].name, "宮沢賢治");
+        assert_eq!(work.creators[0].id, None);
+        assert_eq!(work.writing_mode, WritingMode::Vertical);
+
+        // The empty cover is skipped, and the spine sets the order
+        let titles: Vec<_> = work.chapters.iter().map(|c| c.title.as_str()).collect();
+        assert_eq!(titles, ["Chapter 1", "午后の授業"]);
+        assert_eq!(paragraphs(&work.chapters[0]), ["始まり空", "閉じない太字"]);
+
+        let one = &work.chapters[1];
+        assert_eq!(
+            paragraphs(one),
+            [
+                "「ではみなさんは、｜東《とう》｜京《きょう》 を」",
+                "見出し",
+                "おわり"
+            ]
+        );
+        assert!(one
+            .elements
+            .iter()
+            .any(|e| matches!(e, Entry::Image(data) if data == b"png")));
+    }
+
+    #[test]
+    fn broken_books() {
+        assert!(import(b"PK not a zip").is_err());
+        let no_opf = epub(&[("META-INF/container.xml", CONTAINER.as_bytes())]);
+        assert!(import(&no_opf).is_err());
+        let untitled = epub(&[
+            ("META-INF/container.xml", CONTAINER.as_bytes()),
+            (
+                "OEBPS/content.opf",
+                OPF.replace("dc:title", "dc:x").as_bytes(),
+            ),
+        ]);
+        assert!(import(&untitled).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+//! Turns existing books into `LiteraryWork`s
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::entry::LiteraryWork;
+
+mod aozora;
+mod epub;
+
+/// Imports a book, picking the format from the file name and falling back to the content
+pub fn import(file_name: &str, data: &[u8]) -> Result<LiteraryWork> {
+    let ext = Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("epub") => epub::import(data),
+        Some("txt") => aozora::import(&decode_text(data)),
+        // Zip archives start with PK
+        _ if data.starts_with(b"PK") => epub::import(data),
+        _ => aozora::import(&decode_text(data)),
+    }
+}
+
+/// Plain text files are UTF-8 or, for most of Aozora Bunko, Shift_JIS
+fn decode_text(data: &[u8]) -> String {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    match std::str::from_utf8(data) {
+        Ok(s) => s.to_string(),
+        Err(_) => encoding_rs::SHIFT_JIS.decode(data).0.into_owned(),
+    }
+}
@@ -6,13 +6,27 @@ use crate::{
 
 use anyhow::{bail, Result};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use unicode_collate::collate;
 use uuid::Uuid;
 
 /// Name of the table within the DB
-const WORKS_TABLE: &'static str = "WORKS";
+const WORKS_TABLE: &str = "WORKS";
 /// Trash
-const TRASH_TABLE: &'static str = "TRASH";
+const TRASH_TABLE: &str = "TRASH";
+
+/// Order in which works are listed
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkSort {
+    /// Collated by title
+    #[default]
+    Title,
+    /// Shortest first
+    Length,
+    /// Longest first
+    LengthDesc,
+}
 
 /// An abstraction over the backend to do library stuff. This allows for e.g. federated db access
 pub struct Library<B>
@@ -31,21 +45,90 @@ impl<B: Backend> Library<B> {
         Ok(Self { works, trash })
     }
 
-    /// Add a work to the library
-    // TODO: Add an API like HashMap's entry to update or incrementally update a Work (e.g. adding new chapter)
-    pub fn add_work(&self, work: LiteraryWork) -> Result<()> {
+    /// Add a work to the library, returning its id
+    pub fn add_work(&self, mut work: LiteraryWork) -> Result<Uuid> {
         let uuid = Uuid::now_v7();
+        work.recount();
         self.works.insert(uuid, encode_bincode(&work)?.as_slice());
-        Ok(())
+        Ok(uuid)
+    }
+
+    /// Applies `f` to a work and writes it back. Counts are kept up to date
+    pub fn update_work<T>(
+        &self,
+        uuid: Uuid,
+        mut f: impl FnMut(&mut LiteraryWork) -> T,
+    ) -> Result<T> {
+        self.try_update_work(uuid, |work| Ok(f(work)))
+    }
+
+    /// Like [`Library::update_work`], but nothing is written if `f` fails. If the work changed in
+    /// the meantime, `f` is applied again to the new data, so checks made in `f` hold when it is
+    /// written
+    pub fn try_update_work<T>(
+        &self,
+        uuid: Uuid,
+        mut f: impl FnMut(&mut LiteraryWork) -> Result<T>,
+    ) -> Result<T> {
+        loop {
+            let Some(old) = self.works.get_value(uuid) else {
+                bail!("Could not find work!");
+            };
+            let old = old.as_ref();
+            let mut work: LiteraryWork = decode_bincode(old)?;
+            let res = f(&mut work)?;
+            work.recount();
+            let new = encode_bincode(&work)?;
+            if self.works.compare_and_swap(uuid, Some(old), Some(&new)) {
+                return Ok(res);
+            }
+        }
     }
 
     /// Punts a work into the trash. Deleted works are never actually deleted
     // TODO: Allow admin to delete works permanently
     // TODO: Perhaps this can be done in terms of history?
-    pub fn remove_work(&mut self, uuid: Uuid) {
-        if let Some(work) = self.works.remove(uuid) {
-            self.trash.insert(uuid, work);
-        }
+    pub fn remove_work(&self, uuid: Uuid) -> Result<()> {
+        let Some(work) = self.works.remove(uuid) else {
+            bail!("Could not find work!");
+        };
+        self.trash.insert(uuid, work);
+        Ok(())
+    }
+
+    /// Puts a work from the trash back into the library
+    pub fn restore_work(&self, uuid: Uuid) -> Result<()> {
+        let Some(work) = self.trash.remove(uuid) else {
+            bail!("Could not find work in the trash!");
+        };
+        self.works.insert(uuid, work);
+        Ok(())
+    }
+
+    /// Applies `f` to a work in the trash and writes it back
+    pub fn update_trashed_work(&self, uuid: Uuid, f: impl FnOnce(&mut LiteraryWork)) -> Result<()> {
+        let Some(data) = self.trash.get_value(uuid) else {
+            bail!("Could not find work in the trash!");
+        };
+        let mut work: LiteraryWork = decode_bincode(data.as_ref())?;
+        f(&mut work);
+        self.trash.insert(uuid, encode_bincode(&work)?.as_slice());
+        Ok(())
+    }
+
+    /// Works in the trash, sorted by title
+    pub fn trashed_works(&self) -> Vec<(Uuid, LiteraryWork)> {
+        let mut res: Vec<_> = self
+            .trash
+            .iter()
+            .filter_map(|(key, value)| {
+                let id = Uuid::from_slice(key.as_ref()).ok()?;
+                let work: LiteraryWork = decode_bincode(value.as_ref()).ok()?;
+                Some((id, work))
+            })
+            .collect();
+        res.sort_unstable_by(|(_, a), (_, b)| collate(&a.title, &b.title));
+        res
     }
 
     fn iter_works(&self) -> impl Iterator<Item = (Uuid, LiteraryWork)> + '_ {
@@ -57,19 +140,24 @@ impl<B: Backend> Library<B> {
         })
     }
 
-    /// Returns a sorted vector
-    pub fn all_works(&self) -> Vec<(Uuid, LiteraryWork)> {
-        let mut res: Vec<_> = self.iter_works().collect();
-        res.sort_unstable_by(|(_, a), (_, b)| collate(&a.title, &b.title));
-        res
+    pub fn all_works_by(
+        &self,
+        f: impl Fn(&(Uuid, LiteraryWork)) -> bool,
+    ) -> Vec<(Uuid, LiteraryWork)> {
+        self.all_works_sorted_by(f, WorkSort::Title)
     }
 
-    pub fn all_works_by(
+    pub fn all_works_sorted_by(
         &self,
         f: impl Fn(&(Uuid, LiteraryWork)) -> bool,
+        sort: WorkSort,
     ) -> Vec<(Uuid, LiteraryWork)> {
         let mut res: Vec<_> = self.iter_works().filter(f).collect();
-        res.sort_unstable_by(|(_, a), (_, b)| collate(&a.title, &b.title));
+        match sort {
+            WorkSort::Title => res.sort_unstable_by(|(_, a), (_, b)| collate(&a.title, &b.title)),
+            WorkSort::Length => res.sort_by_key(|(_, w)| w.count.chars),
+            WorkSort::LengthDesc => res.sort_by_key(|(_, w)| std::cmp::Reverse(w.count.chars)),
+        }
         res
     }
 
@@ -82,6 +170,7 @@ impl<B: Backend> Library<B> {
     }
 
     // TODO: Move to feature flag
+    #[allow(dead_code)]
     pub fn fill_test_data(&self) {
         let mut rng = rand::thread_rng();
         for _ in 0..rng.gen_range(10..100) {
@@ -0,0 +1,102 @@
+//! Sending mail to users, e.g. password reset links
+
+use std::{fs, path::PathBuf};
+
+use anyhow::Result;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport,
+    Transport,
+};
+use uuid::Uuid;
+
+use crate::config::{MailConfig, MailTransport};
+
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    /// Plain text
+    pub body: String,
+}
+
+pub trait Mailer: Send + Sync {
+    /// Blocks until the mail is handed off
+    fn send(&self, mail: Mail) -> Result<()>;
+}
+
+/// The mailer set up in the config
+pub fn from_config(config: &MailConfig) -> Result<Box<dyn Mailer>> {
+    let from: Mailbox = config.from.parse()?;
+    Ok(match &config.transport {
+        MailTransport::Log => Box::new(LogMailer),
+        MailTransport::File { dir } => {
+            fs::create_dir_all(dir)?;
+            Box::new(FileMailer {
+                from,
+                dir: dir.clone(),
+            })
+        }
+        MailTransport::Smtp {
+            host,
+            port,
+            username,
+            password,
+        } => {
+            let mut builder = match port {
+                Some(465) => SmtpTransport::relay(host)?.port(465),
+                Some(port) => SmtpTransport::starttls_relay(host)?.port(*port),
+                None => SmtpTransport::starttls_relay(host)?,
+            };
+            if let (Some(username), Some(password)) = (username, password) {
+                builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+            Box::new(SmtpMailer {
+                from,
+                transport: builder.build(),
+            })
+        }
+    })
+}
+
+fn message(from: &Mailbox, mail: Mail) -> Result<Message> {
+    Ok(Message::builder()
+        .from(from.clone())
+        .to(mail.to.parse()?)
+        .subject(mail.subject)
+        .body(mail.body)?)
+}
+
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, mail: Mail) -> Result<()> {
+        tracing::info!("Mail to {}: {}\n{}", mail.to, mail.subject, mail.body);
+        Ok(())
+    }
+}
+
+/// Writes every mail to its own file
+pub struct FileMailer {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: Mail) -> Result<()> {
+        let message = message(&self.from, mail)?;
+        let path = self.dir.join(format!("{}.eml", Uuid::now_v7()));
+        fs::write(path, message.formatted())?;
+        Ok(())
+    }
+}
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: Mail) -> Result<()> {
+        self.transport.send(&message(&self.from, mail)?)?;
+        Ok(())
+    }
+}
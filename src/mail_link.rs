@@ -0,0 +1,232 @@
+//! Single-use links sent by mail, to reset a password or confirm an email address. Like API
+//! tokens, only a hash of the link's secret is kept
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bincode::{Decode, Encode};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    db::{Backend, Table},
+    utils::{decode_bincode, encode_bincode},
+};
+
+/// Keyed by the SHA-256 of the secret
+const LINK_TABLE: &str = "MAIL_LINKS";
+const RESET_LIFETIME: Duration = Duration::from_secs(60 * 60);
+const VERIFY_LIFETIME: Duration = Duration::from_secs(2 * 24 * 60 * 60);
+
+#[derive(Clone, PartialEq, Eq, Encode, Decode)]
+pub enum LinkPurpose {
+    ResetPassword,
+    /// Confirms the user can read mail sent to the address
+    VerifyEmail(String),
+}
+
+impl LinkPurpose {
+    fn lifetime(&self) -> Duration {
+        match self {
+            LinkPurpose::ResetPassword => RESET_LIFETIME,
+            LinkPurpose::VerifyEmail(_) => VERIFY_LIFETIME,
+        }
+    }
+
+    fn same_kind(&self, other: &LinkPurpose) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+#[derive(Encode, Decode)]
+struct MailLink {
+    #[bincode(with_serde)]
+    user: Uuid,
+    purpose: LinkPurpose,
+    expires: SystemTime,
+}
+
+pub struct MailLinkStore<B: Backend> {
+    links: <B as Backend>::OutTable,
+}
+
+impl<B: Backend> MailLinkStore<B> {
+    pub fn new(db: &B) -> Result<Self> {
+        Ok(Self {
+            links: db.get_table(LINK_TABLE)?,
+        })
+    }
+
+    /// Makes a link for `user`, returning its secret. Older links for the same purpose stop
+    /// working
+    pub fn create(&self, user: Uuid, purpose: LinkPurpose) -> Result<String> {
+        let older: Vec<_> = self
+            .iter()
+            .filter(|(_, l)| l.user == user && l.purpose.same_kind(&purpose))
+            .collect();
+        for (key, _) in older {
+            self.links.remove(key);
+        }
+
+        let mut bytes = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        let secret = URL_SAFE_NO_PAD.encode(bytes);
+        let link = MailLink {
+            user,
+            expires: SystemTime::now() + purpose.lifetime(),
+            purpose,
+        };
+        self.links.insert(hash(&secret), encode_bincode(&link)?);
+        Ok(secret)
+    }
+
+    /// The user a reset link is for, without using it up, so the form can be shown first
+    pub fn peek_reset(&self, secret: &str) -> Option<Uuid> {
+        let link = self.get(secret)?;
+        (link.purpose == LinkPurpose::ResetPassword).then_some(link.user)
+    }
+
+    /// Uses up a password reset link, returning the user
+    pub fn take_reset(&self, secret: &str) -> Result<Uuid> {
+        let link = self.take(secret, |purpose| *purpose == LinkPurpose::ResetPassword)?;
+        Ok(link.user)
+    }
+
+    /// Uses up an email verification link, returning the user and the address
+    pub fn take_verification(&self, secret: &str) -> Result<(Uuid, String)> {
+        let link = self.take(secret, |purpose| {
+            matches!(purpose, LinkPurpose::VerifyEmail(_))
+        })?;
+        match link.purpose {
+            LinkPurpose::VerifyEmail(email) => Ok((link.user, email)),
+            LinkPurpose::ResetPassword => unreachable!("only verification links are taken"),
+        }
+    }
+
+    /// Removes an unexpired link for `purpose` and returns it. Of two requests using the same
+    /// link at once, only one gets it
+    fn take(&self, secret: &str, purpose: impl Fn(&LinkPurpose) -> bool) -> Result<MailLink> {
+        let key = hash(secret);
+        let taken = self.links.get_value(key).and_then(|data| {
+            let link: MailLink = decode_bincode(data.as_ref()).ok()?;
+            let usable = purpose(&link.purpose) && link.expires > SystemTime::now();
+            (usable && self.links.compare_and_swap(key, Some(data.as_ref()), None)).then_some(link)
+        });
+        match taken {
+            Some(link) => Ok(link),
+            None => bail!("The link has expired or was already used"),
+        }
+    }
+
+    pub fn remove_user(&self, user: Uuid) {
+        let links: Vec<_> = self.iter().filter(|(_, l)| l.user == user).collect();
+        for (key, _) in links {
+            self.links.remove(key);
+        }
+    }
+
+    /// Deletes expired links, returning how many there were
+    pub fn sweep(&self) -> usize {
+        let now = SystemTime::now();
+        let expired: Vec<_> = self.iter().filter(|(_, l)| l.expires <= now).collect();
+        for (key, _) in &expired {
+            self.links.remove(key);
+        }
+        expired.len()
+    }
+
+    /// An unexpired link
+    fn get(&self, secret: &str) -> Option<MailLink> {
+        let link: MailLink = decode_bincode(self.links.get_value(hash(secret))?.as_ref()).ok()?;
+        (link.expires > SystemTime::now()).then_some(link)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Vec<u8>, MailLink)> + '_ {
+        self.links.iter().filter_map(|(key, value)| {
+            let link = decode_bincode(value.as_ref()).ok()?;
+            Some((key.as_ref().to_vec(), link))
+        })
+    }
+}
+
+fn hash(secret: &str) -> [u8; 32] {
+    Sha256::digest(secret.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn store() -> MailLinkStore<sled::Db> {
+        MailLinkStore::new(&crate::db::temporary()).unwrap()
+    }
+
+    #[test]
+    fn links_are_single_use() {
+        let store = store();
+        let user = Uuid::now_v7();
+        let secret = store.create(user, LinkPurpose::ResetPassword).unwrap();
+        // Looking doesn't use it up
+        assert_eq!(store.peek_reset(&secret), Some(user));
+        assert!(store.take_verification(&secret).is_err());
+        assert_eq!(store.take_reset(&secret).unwrap(), user);
+        assert!(store.take_reset(&secret).is_err());
+        assert_eq!(store.peek_reset(&secret), None);
+        assert!(store.take_reset("made up").is_err());
+    }
+
+    #[test]
+    fn new_links_replace_older_ones() {
+        let store = store();
+        let user = Uuid::now_v7();
+        let reset = |store: &MailLinkStore<_>| store.create(user, LinkPurpose::ResetPassword);
+        let verify = |store: &MailLinkStore<_>, email: &str| {
+            store.create(user, LinkPurpose::VerifyEmail(email.to_string()))
+        };
+        let old_reset = reset(&store).unwrap();
+        let old_verify = verify(&store, "old@example.com").unwrap();
+        let new_reset = reset(&store).unwrap();
+        let new_verify = verify(&store, "new@example.com").unwrap();
+        let other_user = store
+            .create(Uuid::now_v7(), LinkPurpose::ResetPassword)
+            .unwrap();
+
+        assert!(store.take_reset(&old_reset).is_err());
+        assert!(store.take_verification(&old_verify).is_err());
+        assert_eq!(store.take_reset(&new_reset).unwrap(), user);
+        assert_eq!(
+            store.take_verification(&new_verify).unwrap(),
+            (user, "new@example.com".to_string())
+        );
+        assert!(store.peek_reset(&other_user).is_some());
+
+        store.remove_user(user);
+        let secret = reset(&store).unwrap();
+        store.remove_user(user);
+        assert!(store.peek_reset(&secret).is_none());
+    }
+
+    #[test]
+    fn links_expire() {
+        let store = store();
+        let user = Uuid::now_v7();
+        let live = store.create(user, LinkPurpose::ResetPassword).unwrap();
+        let expired = "expired";
+        let link = MailLink {
+            user,
+            purpose: LinkPurpose::VerifyEmail("a@example.com".to_string()),
+            expires: SystemTime::now() - Duration::from_secs(1),
+        };
+        store
+            .links
+            .insert(hash(expired), encode_bincode(&link).unwrap())
+            .unwrap();
+
+        assert!(store.take_verification(expired).is_err());
+        assert_eq!(store.sweep(), 1);
+        assert_eq!(store.sweep(), 0);
+        assert_eq!(store.peek_reset(&live), Some(user));
+    }
+}
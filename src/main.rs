@@ -1,20 +1,50 @@
+mod auth;
+mod cli;
 mod config;
+mod cookies;
+mod csrf;
 mod db;
 mod error;
+mod page_header;
 mod pages;
 mod utils;
 
+mod annotation;
+mod comment;
+mod count;
+mod editor;
 mod entry;
+mod epub;
+mod export;
+mod import;
 mod library;
+mod mail;
+mod mail_link;
+mod notification;
+mod password;
+mod payment;
+mod review;
+mod ruby;
+mod session;
+mod shelf;
+mod shop;
 mod stats;
+mod throttle;
+mod token;
+mod two_factor;
 mod user;
+mod validate;
 
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use application::Application;
+use cli::Command;
+use config::Config;
+use user::Role;
 
 mod application;
 mod params;
+mod render;
 mod routes;
 
 #[tokio::main]
@@ -23,15 +53,132 @@ async fn main() -> anyhow::Result<()> {
     //     "{:x?}",
     //     unicode_collate::sort_key("\u{0627}\u{0591}\u{0655}\u{0061}")
     // );
-    let state = Arc::new(Application::<sled::Db>::new("dev".into())?);
+    // Set up first so warnings from loading the config and opening the db are shown
+    tracing_subscriber::fmt::init();
+
+    let cmd = Command::parse(std::env::args().skip(1))?;
+    let state = Arc::new(Application::<sled::Db>::new(
+        "dev".into(),
+        Config::load_default()?,
+    )?);
     // db::Backend::drop_table(&state.db, "WORKS")?;
     // db::Backend::drop_table(&state.db, "USERS")?;
     // state.lib.fill_test_data();
 
-    tracing_subscriber::fmt::init();
+    match cmd {
+        Command::Serve => {}
+        Command::Import(paths) => {
+            for p in paths {
+                let data = std::fs::read(&p)?;
+                let file_name = p.file_name().unwrap_or_default().to_string_lossy();
+                let (id, title) = state.import(None, &file_name, &data)?;
+                println!("Imported {title} ({id})");
+            }
+            return Ok(());
+        }
+        Command::CreateAdmin(name) => {
+            match state.members.find_by_name(&name) {
+                Some(id) => {
+                    state.members.set_role(id, Role::Admin)?;
+                    println!("Promoted {name} to admin");
+                }
+                None => {
+                    let mut pswd = String::new();
+                    std::io::stdin().read_line(&mut pswd)?;
+                    let pswd = pswd.trim_end_matches(['\r', '\n']).to_string();
+                    if pswd.is_empty() {
+                        anyhow::bail!("No password given");
+                    }
+                    let id = state.members.create_user(name.clone(), pswd, Role::Admin)?;
+                    println!("Created admin {name} ({id})");
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    // Deletes expired sessions, login challenges, mail links, old failed logins and daily stats
+    // past retention
+    let sweeper = state.clone();
+    let interval = Duration::from_secs(state.config.session.sweep_interval_minutes.max(1) * 60);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let state = sweeper.clone();
+            let sweep = move || {
+                state.sweep_throttles();
+                state.members.challenges.sweep();
+                state.members.links.sweep();
+                if let Err(e) = state.stats.compact(&state.config.stats) {
+                    tracing::error!("Compacting stats failed: {e}");
+                }
+                state.members.sessions.sweep()
+            };
+            match tokio::task::spawn_blocking(sweep).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Deleted {n} expired sessions"),
+                Err(e) => tracing::error!("Session sweep failed: {e}"),
+            }
+        }
+    });
 
-    let app = routes::AppRoutes::register(state);
+    // Writes the views counted in memory to the db
+    let flusher = state.clone();
+    let interval = Duration::from_secs(state.config.stats.flush_interval_seconds.max(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let state = flusher.clone();
+            match tokio::task::spawn_blocking(move || state.stats.flush()).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => tracing::error!("Writing views failed: {e}"),
+                Err(e) => tracing::error!("Writing views failed: {e}"),
+            }
+        }
+    });
+
+    let app = routes::AppRoutes::register(state.clone());
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3080").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // The client's address is used to throttle logins
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+
+    // Views counted since the last flush would be lost otherwise
+    tokio::task::spawn_blocking(move || state.stats.flush()).await??;
     Ok(())
 }
+
+/// Resolves on Ctrl+C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Listening for Ctrl+C failed: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Listening for SIGTERM failed: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    tracing::info!("Shutting down");
+}
@@ -0,0 +1,191 @@
+//! Following works and authors, and the inbox of what happened to them
+
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use bincode::{Decode, Encode};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    db::{Backend, Table},
+    utils::{decode_bincode, encode_bincode},
+};
+
+/// Keyed by the followed work or author followed by the follower
+const FOLLOW_TABLE: &str = "FOLLOWS";
+/// Index of [`FOLLOW_TABLE`] keyed by the follower followed by what they follow
+const FOLLOWING_TABLE: &str = "FOLLOWING";
+/// Keyed by the user followed by the notification id, which sorts them by age
+const NOTIFICATION_TABLE: &str = "NOTIFICATIONS";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Encode, Decode)]
+#[serde(rename_all = "kebab-case")]
+pub enum FollowKind {
+    Work,
+    Author,
+}
+
+#[derive(Clone, Encode, Decode)]
+pub struct Follow {
+    pub kind: FollowKind,
+    pub since: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum NotificationKind {
+    /// A followed work got a chapter
+    NewChapter,
+    /// A followed author started a work
+    NewWork,
+}
+
+/// Titles are kept as they were, so the notification reads the same if the work is gone
+#[derive(Clone, Encode, Decode)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    #[bincode(with_serde)]
+    pub work: Uuid,
+    pub work_title: String,
+    #[bincode(with_serde)]
+    pub chapter: Option<Uuid>,
+    pub chapter_title: Option<String>,
+    /// The followed author, for new works
+    #[bincode(with_serde)]
+    pub author: Option<Uuid>,
+    pub created: SystemTime,
+    pub read: bool,
+}
+
+pub struct NotificationStore<B: Backend> {
+    follows: <B as Backend>::OutTable,
+    following: <B as Backend>::OutTable,
+    notifications: <B as Backend>::OutTable,
+}
+
+impl<B: Backend> NotificationStore<B> {
+    pub fn new(db: &B) -> Result<Self> {
+        Ok(Self {
+            follows: db.get_table(FOLLOW_TABLE)?,
+            following: db.get_table(FOLLOWING_TABLE)?,
+            notifications: db.get_table(NOTIFICATION_TABLE)?,
+        })
+    }
+
+    pub fn follow(&self, user: Uuid, target: Uuid, kind: FollowKind) -> Result<()> {
+        let follow = Follow {
+            kind,
+            since: SystemTime::now(),
+        };
+        self.follows
+            .insert(key(target, user), encode_bincode(&follow)?);
+        self.following.insert(key(user, target), []);
+        Ok(())
+    }
+
+    pub fn unfollow(&self, user: Uuid, target: Uuid) {
+        self.follows.remove(key(target, user));
+        self.following.remove(key(user, target));
+    }
+
+    pub fn is_following(&self, user: Uuid, target: Uuid) -> bool {
+        self.follows.get_value(key(target, user)).is_some()
+    }
+
+    /// Users following a work or author
+    pub fn followers(&self, target: Uuid) -> Vec<Uuid> {
+        self.follows
+            .scan_prefix(target.as_bytes())
+            .filter_map(|(key, _)| Uuid::from_slice(&key.as_ref()[16..]).ok())
+            .collect()
+    }
+
+    /// Works and authors `user` follows, oldest first
+    pub fn following(&self, user: Uuid) -> Vec<(Uuid, Follow)> {
+        let mut res: Vec<_> = self
+            .following
+            .scan_prefix(user.as_bytes())
+            .filter_map(|(key, _)| {
+                let target = Uuid::from_slice(&key.as_ref()[16..]).ok()?;
+                let data = self.follows.get_value(self::key(target, user))?;
+                let follow: Follow = decode_bincode(data.as_ref()).ok()?;
+                Some((target, follow))
+            })
+            .collect();
+        res.sort_by_key(|(_, f)| f.since);
+        res
+    }
+
+    pub fn notify(&self, user: Uuid, notification: &Notification) -> Result<()> {
+        let id = Uuid::now_v7();
+        self.notifications
+            .insert(key(user, id), encode_bincode(notification)?);
+        Ok(())
+    }
+
+    /// Notifications of `user`, newest first
+    pub fn for_user(&self, user: Uuid) -> Vec<(Uuid, Notification)> {
+        let mut res: Vec<_> = self
+            .notifications
+            .scan_prefix(user.as_bytes())
+            .filter_map(|(key, value)| {
+                let id = Uuid::from_slice(&key.as_ref()[16..]).ok()?;
+                let notification = decode_bincode(value.as_ref()).ok()?;
+                Some((id, notification))
+            })
+            .collect();
+        res.reverse();
+        res
+    }
+
+    pub fn unread(&self, user: Uuid) -> usize {
+        self.notifications
+            .scan_prefix(user.as_bytes())
+            .filter_map(|(_, value)| decode_bincode::<Notification>(value.as_ref()).ok())
+            .filter(|n| !n.read)
+            .count()
+    }
+
+    /// Marks a notification read, returning it
+    pub fn mark_read(&self, user: Uuid, id: Uuid) -> Result<Notification> {
+        let Some(data) = self.notifications.get_value(key(user, id)) else {
+            bail!("That notification doesn't exist");
+        };
+        let mut notification: Notification = decode_bincode(data.as_ref())?;
+        notification.read = true;
+        self.notifications
+            .insert(key(user, id), encode_bincode(&notification)?);
+        Ok(notification)
+    }
+
+    /// Forgets what `user` follows, who follows them and their inbox
+    pub fn remove_user(&self, user: Uuid) {
+        for (target, _) in self.following(user) {
+            self.unfollow(user, target);
+        }
+        for follower in self.followers(user) {
+            self.unfollow(follower, user);
+        }
+        for (id, _) in self.for_user(user) {
+            self.notifications.remove(key(user, id));
+        }
+    }
+
+    pub fn mark_all_read(&self, user: Uuid) -> Result<()> {
+        for (id, mut notification) in self.for_user(user) {
+            if !notification.read {
+                notification.read = true;
+                self.notifications
+                    .insert(key(user, id), encode_bincode(&notification)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn key(first: Uuid, second: Uuid) -> [u8; 32] {
+    let mut key = [0; 32];
+    key[..16].copy_from_slice(first.as_bytes());
+    key[16..].copy_from_slice(second.as_bytes());
+    key
+}
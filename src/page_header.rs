@@ -0,0 +1,49 @@
+//! What the header at the top of every page shows about the visitor. It is worked out once per
+//! request, so handlers don't have to pass it to every template
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use minijinja::{context, Value};
+
+use crate::{cookies::SessionJar, db::Backend, routes::App};
+
+#[derive(Clone, Default)]
+struct Header {
+    name: Option<String>,
+    unread: usize,
+}
+
+tokio::task_local! {
+    static HEADER: Header;
+}
+
+/// The header of the request being handled, for the templates
+pub fn header() -> Value {
+    let header = HEADER.try_with(Clone::clone).unwrap_or_default();
+    context! {
+        signed_in => header.name.is_some(),
+        name => header.name,
+        unread => header.unread,
+    }
+}
+
+/// Middleware looking up the signed in user and their unread notifications
+pub async fn load<B: Backend + 'static>(
+    State(state): State<App<B>>,
+    jar: SessionJar,
+    req: Request,
+    next: Next,
+) -> Response {
+    let user = state.signed_in(jar.session_id());
+    let header = match user {
+        Some(user) => Header {
+            name: state.members.get_name(user).ok(),
+            unread: state.notifications.unread(user),
+        },
+        None => Header::default(),
+    };
+    HEADER.scope(header, next.run(req)).await
+}
@@ -0,0 +1,451 @@
+//! Taking one's data out of the site and deleting one's account
+
+use anyhow::{bail, Result};
+use minijinja::context;
+use uuid::Uuid;
+
+use crate::{
+    application::Application,
+    db::Backend,
+    entry::LiteraryWork,
+    epub,
+    export::{self, time},
+    params::{self, WorkDisposal},
+    user::{Capability, UserRef},
+    utils::b64_encode_uuid,
+};
+
+/// Credited instead of deleted accounts
+const DELETED_USER: &str = "Deleted user";
+
+impl<B: Backend> Application<B> {
+    /// Everything the user has put into the site, as a zip archive. Returns it with their name
+    pub fn export_account(&self, user: Uuid) -> Result<(String, Vec<u8>)> {
+        let data = self.members.get_user(user)?;
+        let reviews = self
+            .reviews
+            .for_user(user)
+            .into_iter()
+            .map(|(work, r)| export::Review {
+                work,
+                stars: r.stars,
+                text: r.text,
+                spoiler: r.spoiler,
+                created: time(r.created),
+                edited: r.edited.map(time),
+                helpful_votes: r.helpful.len(),
+            });
+        let comments = self
+            .comments
+            .for_user(user)
+            .into_iter()
+            .map(|(id, c)| export::Comment {
+                id,
+                work: c.work,
+                chapter: c.chapter,
+                parent: c.parent,
+                text: c.text,
+                created: time(c.created),
+                edited: c.edited.map(time),
+                deleted: c.deleted,
+                hidden: c.hidden,
+            });
+        let annotations =
+            self.annotations
+                .for_user(user)
+                .into_iter()
+                .map(|(_, a)| export::Annotation {
+                    work: a.work,
+                    chapter: a.chapter,
+                    kind: a.kind,
+                    quote: a.quote,
+                    note: a.note,
+                    created: time(a.created),
+                });
+        let following = self
+            .notifications
+            .following(user)
+            .into_iter()
+            .map(|(id, f)| export::Follow {
+                id,
+                kind: f.kind,
+                since: time(f.since),
+            });
+        let orders = self
+            .orders
+            .for_user(user)
+            .into_iter()
+            .map(|(id, o)| export::Order {
+                id,
+                total: o.total(),
+                items: o.items,
+                currency: o.currency,
+                status: o.status,
+                provider: o.provider,
+                reference: o.reference,
+                created: time(o.created),
+            });
+        let mut works = vec![];
+        for (id, mut work) in self.lib.all_works_by(|(_, work)| work.is_creator(user)) {
+            for c in &mut work.creators {
+                c.name = self.members.creator_name(c);
+            }
+            works.push(export::Work {
+                id,
+                file: format!("works/{}.epub", b64_encode_uuid(id.as_bytes())),
+                epub: epub::export(id, &work, work.writing_mode)?,
+                title: work.title,
+            });
+        }
+
+        let account = export::Account {
+            id: user,
+            name: data.name.clone(),
+            email: data.email,
+            previous_names: data.previous_names,
+            created: data.created,
+            role: data.role,
+            settings: data.settings,
+            library: data.lib,
+            reviews: reviews.collect(),
+            comments: comments.collect(),
+            annotations: annotations.collect(),
+            following: following.collect(),
+            orders: orders.collect(),
+            works,
+        };
+        Ok((data.name, export::archive(&account)?))
+    }
+
+    pub fn delete_account_page(&self, user: Uuid, error: Option<&str>) -> Result<String> {
+        let works = self.lib.all_works_by(|(_, work)| work.is_creator(user));
+        let template = self.env.get_template("delete_account.jinja")?;
+        let render = template.render(context! {
+            works => works.len(),
+            error,
+        })?;
+        Ok(render)
+    }
+
+    /// Deletes the account after checking the password. Their reviews, votes, annotations and
+    /// follows go with it, comments stay without a name, orders are kept for the books, and works
+    /// only they created are trashed or given to another author
+    pub fn delete_account(&self, user: Uuid, form: params::DeleteAccountParams) -> Result<()> {
+        self.members.check_password(user, &form.pswd)?;
+        let heir = match form.works {
+            WorkDisposal::Trash => None,
+            WorkDisposal::Transfer => {
+                let heir = self.members.find_by_name(&form.heir).filter(|&h| h != user);
+                let Some(heir) = heir else {
+                    bail!("There is no other user called {}", form.heir);
+                };
+                let name = self.members.get_name(heir)?;
+                if !self.members.get_role(heir)?.can(Capability::WriteWorks) {
+                    bail!("{name} can't write works, so they can't take yours");
+                }
+                Some(UserRef {
+                    id: Some(heir),
+                    name,
+                    created: std::time::SystemTime::now(),
+                })
+            }
+        };
+
+        // Everything is checked. Failures from here on are logged and the rest goes ahead, so an
+        // account is never left half deleted
+        for (id, work) in self.lib.all_works_by(|(_, work)| work.is_creator(user)) {
+            if let Err(e) = self.hand_over_work(user, id, &work, heir.clone()) {
+                tracing::warn!("Could not hand over work {id} of deleted user {user}: {e}");
+            }
+        }
+        for (id, work) in self.lib.trashed_works() {
+            if work.is_creator(user) {
+                let res = self
+                    .lib
+                    .update_trashed_work(id, |work| replace_creator(work, user, deleted_user()));
+                if let Err(e) = res {
+                    tracing::warn!("Could not hand over work {id} of deleted user {user}: {e}");
+                }
+            }
+        }
+        for (work, _) in self.reviews.for_user(user) {
+            if let Err(e) = self.unrate(user, work) {
+                tracing::warn!("Could not remove the review of {work} by deleted user {user}: {e}");
+            }
+        }
+        if let Err(e) = self.reviews.remove_votes(user) {
+            tracing::warn!("Could not remove the votes of deleted user {user}: {e}");
+        }
+        for (id, _) in self.annotations.for_user(user) {
+            self.annotations.remove(id);
+        }
+        if let Err(e) = self.comments.anonymise(user) {
+            tracing::warn!("Could not anonymise the comments of deleted user {user}: {e}");
+        }
+        self.notifications.remove_user(user);
+        self.members.delete_user(user)
+    }
+
+    /// Takes the deleted `user` off the creators of a work. Works nobody else wrote go to `heir`,
+    /// or to the trash if there is none
+    fn hand_over_work(
+        &self,
+        user: Uuid,
+        id: Uuid,
+        work: &LiteraryWork,
+        heir: Option<UserRef>,
+    ) -> Result<()> {
+        let shared = work
+            .creators
+            .iter()
+            .any(|c| c.id.is_some_and(|c| c != user));
+        if shared {
+            return self.lib.update_work(id, |work| {
+                work.creators.retain(|c| c.id != Some(user));
+            });
+        }
+        let trash = heir.is_none();
+        self.lib.update_work(id, |work| {
+            replace_creator(work, user, heir.clone().unwrap_or_else(deleted_user));
+        })?;
+        if trash {
+            self.lib.remove_work(id)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the user's review of a work and its stars from the work's ratings
+    fn unrate(&self, user: Uuid, work: Uuid) -> Result<()> {
+        let Some(review) = self.reviews.remove(work, user)? else {
+            return Ok(());
+        };
+        // Works in the trash keep their ratings
+        if self.lib.get_work(work).is_ok() {
+            self.lib
+                .update_work(work, |work| work.stats.rate(Some(review.stars), None))?;
+        }
+        Ok(())
+    }
+}
+
+fn deleted_user() -> UserRef {
+    UserRef::external(DELETED_USER.to_string())
+}
+
+fn replace_creator(work: &mut LiteraryWork, user: Uuid, with: UserRef) {
+    for c in &mut work.creators {
+        if c.id == Some(user) {
+            *c = with.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Read, time::SystemTime};
+
+    use super::*;
+    use crate::{
+        annotation::{Anchor, Annotation, AnnotationKind},
+        comment::Comment,
+        config::Config,
+        entry::Chapter,
+        user::Role,
+    };
+
+    const PSWD: &str = "correct horse battery";
+
+    struct Site {
+        app: Application<sled::Db>,
+        alice: Uuid,
+        bob: Uuid,
+        carol: Uuid,
+        /// Only by alice
+        solo: Uuid,
+        /// By alice and bob
+        shared: Uuid,
+        /// By bob, reviewed by alice and carol
+        reviewed: Uuid,
+        comment: Uuid,
+    }
+
+    fn site() -> Site {
+        let app = Application::temporary(Config::default());
+        let user = |name: &str, role| {
+            app.members
+                .create_user(name.into(), PSWD.into(), role)
+                .unwrap()
+        };
+        let (alice, bob, carol) = (
+            user("alice", Role::Author),
+            user("bob", Role::Author),
+            user("carol", Role::Reader),
+        );
+        let work = |title: &str, creators: &[Uuid]| {
+            let mut work = LiteraryWork::new(title.into(), String::new());
+            for &id in creators {
+                work.creators.push(UserRef {
+                    id: Some(id),
+                    name: app.members.get_name(id).unwrap(),
+                    created: SystemTime::now(),
+                });
+            }
+            work.chapters.push(Chapter::new(
+                String::new(),
+                vec![crate::entry::Entry::Paragraph("一".into())],
+            ));
+            app.lib.add_work(work).unwrap()
+        };
+        let solo = work("Solo", &[alice]);
+        let shared = work("Shared", &[alice, bob]);
+        let reviewed = work("Reviewed", &[bob]);
+
+        let review = |user, stars, text: &str| {
+            let params = params::LiteraryWorkParams {
+                title: String::new(),
+                id: reviewed,
+            };
+            let form = params::ReviewParams {
+                stars,
+                text: text.into(),
+                spoiler: None,
+            };
+            app.review_work(user, params, form).unwrap();
+        };
+        review(alice, 1, "Bad");
+        review(carol, 5, "Good");
+        app.reviews.toggle_helpful(reviewed, carol, alice).unwrap();
+
+        let chapter = app.lib.get_work(reviewed).unwrap().chapters.remove(0);
+        let comment = app
+            .comments
+            .add(&Comment {
+                user: alice,
+                work: reviewed,
+                chapter: chapter.id,
+                parent: None,
+                text: "Hi".into(),
+                created: SystemTime::now(),
+                edited: None,
+                deleted: false,
+                hidden: false,
+            })
+            .unwrap();
+        let anchor = Anchor {
+            paragraph: 0,
+            start: 0,
+            end: 1,
+        };
+        let kind = AnnotationKind::Highlight;
+        let annotation = Annotation::new(alice, reviewed, &chapter, kind, anchor, None).unwrap();
+        app.annotations.add(&annotation).unwrap();
+
+        Site {
+            app,
+            alice,
+            bob,
+            carol,
+            solo,
+            shared,
+            reviewed,
+            comment,
+        }
+    }
+
+    fn delete(site: &Site, pswd: &str, works: WorkDisposal, heir: &str) -> Result<()> {
+        let form = params::DeleteAccountParams {
+            pswd: pswd.into(),
+            works,
+            heir: heir.into(),
+        };
+        site.app.delete_account(site.alice, form)
+    }
+
+    fn creators(app: &Application<sled::Db>, work: &LiteraryWork) -> Vec<String> {
+        work.creators
+            .iter()
+            .map(|c| app.members.creator_name(c))
+            .collect()
+    }
+
+    #[test]
+    fn deleting_with_a_wrong_password_does_nothing() {
+        let site = site();
+        assert!(delete(&site, "wrong", WorkDisposal::Trash, "").is_err());
+        assert!(delete(&site, PSWD, WorkDisposal::Transfer, "carol").is_err());
+        assert!(delete(&site, PSWD, WorkDisposal::Transfer, "alice").is_err());
+        assert!(site.app.members.get_user(site.alice).is_ok());
+        assert!(site.app.lib.get_work(site.solo).is_ok());
+        assert_eq!(site.app.reviews.for_user(site.alice).len(), 1);
+    }
+
+    #[test]
+    fn deleting_hands_works_over() {
+        let site = site();
+        let app = &site.app;
+        delete(&site, PSWD, WorkDisposal::Transfer, "bob").unwrap();
+        assert!(app.members.get_user(site.alice).is_err());
+        assert_eq!(app.members.find_by_name("alice"), None);
+
+        let solo = app.lib.get_work(site.solo).unwrap();
+        assert_eq!(creators(app, &solo), ["bob"]);
+        assert!(solo.is_creator(site.bob));
+        let shared = app.lib.get_work(site.shared).unwrap();
+        assert_eq!(creators(app, &shared), ["bob"]);
+
+        let comment = app.comments.get(site.comment).unwrap();
+        assert_eq!((comment.user, comment.text.as_str()), (Uuid::nil(), "Hi"));
+        assert!(app.reviews.get(site.reviewed, site.alice).is_none());
+        assert!(app
+            .reviews
+            .get(site.reviewed, site.carol)
+            .unwrap()
+            .helpful
+            .is_empty());
+        let ratings = app.lib.get_work(site.reviewed).unwrap().stats.ratings();
+        assert_eq!((ratings.count(), ratings.average()), (1, Some(5.0)));
+        assert!(app.annotations.for_user(site.alice).is_empty());
+    }
+
+    #[test]
+    fn deleting_trashes_works() {
+        let site = site();
+        let app = &site.app;
+        delete(&site, PSWD, WorkDisposal::Trash, "").unwrap();
+        assert!(app.lib.get_work(site.solo).is_err());
+        let trashed = app.lib.trashed_works();
+        let (_, solo) = trashed.iter().find(|(id, _)| *id == site.solo).unwrap();
+        assert_eq!(creators(app, solo), [DELETED_USER]);
+        // Bob still has his share
+        let shared = app.lib.get_work(site.shared).unwrap();
+        assert_eq!(creators(app, &shared), ["bob"]);
+    }
+
+    #[test]
+    fn export() {
+        let site = site();
+        let (name, zip) = site.app.export_account(site.alice).unwrap();
+        assert_eq!(name, "alice");
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(zip)).unwrap();
+        let mut json = String::new();
+        zip.by_name("account.json")
+            .unwrap()
+            .read_to_string(&mut json)
+            .unwrap();
+        let account: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(account["name"], "alice");
+        assert_eq!(account["reviews"][0]["text"], "Bad");
+        assert_eq!(account["comments"][0]["text"], "Hi");
+        assert_eq!(account["annotations"][0]["quote"], "一");
+
+        let works = account["works"].as_array().unwrap();
+        assert_eq!(works.len(), 2);
+        for work in works {
+            let file = work["file"].as_str().unwrap();
+            let epub = zip.by_name(file).unwrap();
+            assert!(epub.size() > 0);
+        }
+        // Only the archive holds the books
+        assert!(works.iter().all(|w| w.get("epub").is_none()));
+    }
+}
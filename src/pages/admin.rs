@@ -0,0 +1,70 @@
+//! Pages for moderators and admins
+
+use anyhow::{bail, Result};
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    application::Application,
+    db::Backend,
+    error::LibraryError,
+    params,
+    user::Role,
+    utils::{b64_encode_uuid, url_encode_segment},
+};
+
+impl<B: Backend> Application<B> {
+    pub fn trash_page(&self) -> Result<String> {
+        let works = Value::from_iter(self.lib.trashed_works().into_iter().map(|(id, work)| {
+            context! {
+                restore => format!(
+                    "/admin/trash/{}/{}/restore",
+                    url_encode_segment(&work.title),
+                    b64_encode_uuid(id.as_bytes())
+                ),
+                title => work.title,
+                creators => self.creators(&work),
+            }
+        }));
+        let template = self.env.get_template("trash.jinja")?;
+        let render = template.render(context! { works })?;
+        Ok(render)
+    }
+
+    pub fn trash_work(&self, params: params::LiteraryWorkParams) -> Result<()> {
+        if self.lib.remove_work(params.id).is_err() {
+            bail!(LibraryError::Missing(params.title));
+        }
+        Ok(())
+    }
+
+    pub fn restore_work(&self, params: params::LiteraryWorkParams) -> Result<()> {
+        if self.lib.restore_work(params.id).is_err() {
+            bail!(LibraryError::Missing(params.title));
+        }
+        Ok(())
+    }
+
+    pub fn users_page(&self, admin: Uuid) -> Result<String> {
+        let users = Value::from_iter(self.members.all_users().into_iter().map(|(id, user)| {
+            context! {
+                id => b64_encode_uuid(id.as_bytes()),
+                name => user.name,
+                role => user.role,
+                joined => user.created.date_naive(),
+                is_self => id == admin,
+            }
+        }));
+        let template = self.env.get_template("users.jinja")?;
+        let render = template.render(context! { users, roles => Role::ALL })?;
+        Ok(render)
+    }
+
+    pub fn set_role(&self, admin: Uuid, form: params::SetRoleParams) -> Result<()> {
+        // Otherwise the last admin could lock everyone out
+        if form.user == admin {
+            bail!("You can't change your own role");
+        }
+        self.members.set_role(form.user, form.role)
+    }
+}
@@ -0,0 +1,134 @@
+//! Bookmarks and highlights of signed in users
+
+use std::{collections::HashMap, time::SystemTime};
+
+use anyhow::{bail, Result};
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    annotation::{Anchor, Annotation, AnnotationKind},
+    application::Application,
+    db::Backend,
+    entry::{Chapter, LiteraryWork},
+    error::LibraryError,
+    params,
+    utils::{b64_encode_uuid, work_path},
+};
+
+impl<B: Backend> Application<B> {
+    /// The user's annotations in a chapter, for the chapter template
+    pub(crate) fn chapter_annotations(&self, user: Uuid, chapter: &Chapter) -> Value {
+        let annotations = self.annotations.in_chapter(user, chapter);
+        Value::from_iter(
+            annotations
+                .into_iter()
+                .map(|(id, a)| annotation_context(id, &a)),
+        )
+    }
+
+    /// Adds a bookmark or highlight, returning the paragraph it is in
+    pub fn add_annotation(
+        &self,
+        user: Uuid,
+        params: params::ChapterParams,
+        form: params::AnnotationParams,
+    ) -> Result<usize> {
+        let Ok(work) = self.lib.get_work(params.work_params.id) else {
+            bail!(LibraryError::Missing(params.work_params.title));
+        };
+        let Some(chapter) = work.chapters.get(params.chapter_id) else {
+            bail!(LibraryError::Missing(params.work_params.title));
+        };
+        let access = work.access_to(chapter, SystemTime::now());
+        if !self.can_read(Some(user), params.work_params.id, &work, access) {
+            bail!(LibraryError::Forbidden(params.work_params.title));
+        }
+        let anchor = Anchor {
+            paragraph: form.paragraph,
+            start: form.start,
+            end: form.end,
+        };
+        let note = Some(form.note.trim().to_string()).filter(|n| !n.is_empty());
+        let annotation = Annotation::new(
+            user,
+            params.work_params.id,
+            chapter,
+            form.kind,
+            anchor,
+            note,
+        )?;
+        self.annotations.add(&annotation)?;
+        Ok(annotation.anchor.paragraph)
+    }
+
+    pub fn set_annotation_note(&self, user: Uuid, id: Uuid, note: String) -> Result<()> {
+        let mut annotation = self.own_annotation(user, id)?;
+        let note = note.trim();
+        annotation.note = Some(note.to_string()).filter(|n| !n.is_empty());
+        self.annotations.update(id, &annotation)
+    }
+
+    pub fn delete_annotation(&self, user: Uuid, id: Uuid) -> Result<()> {
+        self.own_annotation(user, id)?;
+        self.annotations.remove(id);
+        Ok(())
+    }
+
+    /// Every annotation of the user across works, newest first
+    pub fn annotations_page(&self, user: Uuid) -> Result<String> {
+        let mut works: HashMap<Uuid, Option<LiteraryWork>> = HashMap::new();
+        let mut annotations = self.annotations.for_user(user);
+        annotations.reverse();
+
+        let mut list = vec![];
+        for (id, mut a) in annotations {
+            let work = works
+                .entry(a.work)
+                .or_insert_with(|| self.lib.get_work(a.work).ok());
+            // Works in the trash and removed chapters can't be linked to
+            let place = work.as_ref().and_then(|work| {
+                Some((work, work.chapters.iter().position(|c| c.id == a.chapter)?))
+            });
+            let Some((work, i)) = place else {
+                list.push(context! { annotation => annotation_context(id, &a) });
+                continue;
+            };
+            let chapter = &work.chapters[i];
+            if a.reanchor(chapter) {
+                self.annotations.update(id, &a)?;
+            }
+            list.push(context! {
+                annotation => annotation_context(id, &a),
+                url => format!("{}/{i}#p{}", work_path(&work.title, a.work), a.anchor.paragraph),
+                work_title => work.title,
+                chapter_title => chapter.title,
+            });
+        }
+        let annotations = Value::from(list);
+
+        let template = self.env.get_template("annotations.jinja")?;
+        let render = template.render(context! { annotations })?;
+        Ok(render)
+    }
+
+    fn own_annotation(&self, user: Uuid, id: Uuid) -> Result<Annotation> {
+        match self.annotations.get(id) {
+            Some(annotation) if annotation.user == user => Ok(annotation),
+            _ => bail!("That bookmark doesn't exist"),
+        }
+    }
+}
+
+fn annotation_context(id: Uuid, a: &Annotation) -> Value {
+    context! {
+        id => b64_encode_uuid(id.as_bytes()),
+        highlight => a.kind == AnnotationKind::Highlight,
+        paragraph => a.anchor.paragraph,
+        start => a.anchor.start,
+        end => a.anchor.end,
+        quote => a.quote,
+        note => a.note,
+        orphaned => a.orphaned,
+    }
+}
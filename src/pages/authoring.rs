@@ -0,0 +1,297 @@
+//! Pages for creators to write and edit their works
+
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use chrono::{Days, NaiveDate};
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    application::Application,
+    db::Backend,
+    editor,
+    entry::{Chapter, Entry, LiteraryWork, Tag, WritingMode},
+    error::LibraryError,
+    export::time,
+    params::{self, MoveDirection},
+    render, shop,
+    stats::Metric,
+    user::UserRef,
+    utils::b64_encode_uuid,
+};
+
+impl<B: Backend> Application<B> {
+    /// Fetches a work, making sure the user is one of its creators
+    pub(crate) fn editable_work(
+        &self,
+        params: &params::LiteraryWorkParams,
+        user: Uuid,
+    ) -> Result<LiteraryWork> {
+        let Ok(work) = self.lib.get_work(params.id) else {
+            bail!(LibraryError::Missing(params.title.clone()));
+        };
+        if !work.is_creator(user) {
+            bail!(LibraryError::Forbidden(params.title.clone()));
+        }
+        Ok(work)
+    }
+
+    /// Applies `f` to a work of the user and writes it back. That they are a creator is checked
+    /// again on the data being written, in case the creators changed in the meantime
+    fn update_own_work<T>(
+        &self,
+        params: &params::LiteraryWorkParams,
+        user: Uuid,
+        mut f: impl FnMut(&mut LiteraryWork) -> T,
+    ) -> Result<T> {
+        self.editable_work(params, user)?;
+        self.lib.try_update_work(params.id, |work| {
+            if !work.is_creator(user) {
+                bail!(LibraryError::Forbidden(params.title.clone()));
+            }
+            Ok(f(work))
+        })
+    }
+
+    pub fn new_work_page(&self) -> Result<String> {
+        let template = self.env.get_template("work_form.jinja")?;
+        let render = template.render(context! {
+            writing_mode => WritingMode::default(),
+            currency => self.config.shop.currency,
+        })?;
+        Ok(render)
+    }
+
+    /// Creates a work with the user as its sole creator. Returns the id and title
+    pub fn create_work(&self, user: Uuid, form: params::WorkFormParams) -> Result<(Uuid, String)> {
+        let (price, free_until) = check_work_form(&form)?;
+        let mut work = LiteraryWork::new(String::new(), String::new());
+        work.creators = vec![UserRef {
+            id: Some(user),
+            name: self.members.get_name(user)?,
+            created: SystemTime::now(),
+        }];
+        apply_work_form(&mut work, &form, price, free_until);
+        let title = work.title.clone();
+        let id = self.lib.add_work(work)?;
+        self.notify_new_work(id, &self.lib.get_work(id)?);
+        Ok((id, title))
+    }
+
+    pub fn edit_work_page(&self, params: params::LiteraryWorkParams, user: Uuid) -> Result<String> {
+        let work = self.editable_work(&params, user)?;
+        let template = self.env.get_template("work_form.jinja")?;
+
+        let chapters = Value::from_iter(work.chapters.iter().enumerate().map(|(id, c)| {
+            context! {
+                id,
+                title => c.title,
+                chars => c.count.chars,
+                access => c.access,
+                views => self.stats.series(Metric::Views, params.id, Some(c.id)).map_or(0, |s| s.total()),
+            }
+        }));
+        let (genres, tags): (Vec<_>, Vec<_>) =
+            work.tags.iter().partition(|t| matches!(t, Tag::Genre(_)));
+        let join = |tags: Vec<&Tag>| {
+            tags.into_iter()
+                .map(|(Tag::Genre(t) | Tag::Other(t))| t.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        let render = template.render(context! {
+            uuid => b64_encode_uuid(params.id.as_bytes()),
+            title => work.title,
+            description => work.description,
+            genres => join(genres),
+            tags => join(tags),
+            writing_mode => work.writing_mode,
+            views => self.stats.series(Metric::Views, params.id, None).map_or(0, |s| s.total()),
+            price => work.price.map(|p| shop::format_amount(p.into())),
+            // Ended campaigns aren't shown, so saving the form doesn't fail on them
+            free_until => work
+                .free_until
+                .filter(|&end| end > SystemTime::now())
+                .map(|end| (time(end) - Days::new(1)).format("%Y-%m-%d").to_string()),
+            currency => self.config.shop.currency,
+            chapters,
+        })?;
+        Ok(render)
+    }
+
+    pub fn update_work_info(
+        &self,
+        params: params::LiteraryWorkParams,
+        user: Uuid,
+        form: params::WorkFormParams,
+    ) -> Result<String> {
+        let (price, free_until) = check_work_form(&form)?;
+        self.update_own_work(&params, user, |work| {
+            apply_work_form(work, &form, price, free_until);
+            work.title.clone()
+        })
+    }
+
+    pub fn move_chapter(
+        &self,
+        params: params::LiteraryWorkParams,
+        user: Uuid,
+        form: params::MoveChapterParams,
+    ) -> Result<()> {
+        self.update_own_work(&params, user, |work| {
+            let i = form.chapter_id;
+            let j = match form.direction {
+                MoveDirection::Up => i.checked_sub(1),
+                MoveDirection::Down => Some(i + 1),
+            };
+            if let Some(j) = j.filter(|&j| i < work.chapters.len() && j < work.chapters.len()) {
+                work.chapters.swap(i, j);
+            }
+        })
+    }
+
+    /// Renders the chapter editor. Without a form, the saved chapter is shown (or an empty one
+    /// for a new chapter). With one, its text is kept and a preview is shown
+    pub fn chapter_editor(
+        &self,
+        params: params::LiteraryWorkParams,
+        chapter_id: Option<usize>,
+        user: Uuid,
+        form: Option<params::ChapterFormParams>,
+    ) -> Result<String> {
+        let work = self.editable_work(&params, user)?;
+        let chapter = match chapter_id {
+            Some(i) => match work.chapters.get(i) {
+                Some(c) => Some(c),
+                None => bail!(LibraryError::Missing(params.title)),
+            },
+            None => None,
+        };
+        let old = chapter.map_or(&[][..], |c| &c.elements);
+
+        let (title, text, access, preview) = match form {
+            Some(form) => {
+                let preview = editor::from_text(&form.text, old)
+                    .iter()
+                    .map(|e| match e {
+                        Entry::Paragraph(p) => render::paragraph(p, work.writing_mode),
+                        Entry::Image(data) => render::image(data),
+                    })
+                    .map(Value::from_safe_string)
+                    .collect();
+                (form.title, form.text, form.access, Some(preview))
+            }
+            None => (
+                chapter.map(|c| c.title.clone()).unwrap_or_default(),
+                editor::to_text(old),
+                chapter.map(|c| c.access).unwrap_or_default(),
+                None::<Vec<Value>>,
+            ),
+        };
+
+        let template = self.env.get_template("chapter_form.jinja")?;
+        let render = template.render(context! {
+            work_title => work.title,
+            uuid => b64_encode_uuid(params.id.as_bytes()),
+            chapter_id,
+            title,
+            text,
+            access,
+            paid => work.price.is_some(),
+            preview,
+            vertical => work.writing_mode == WritingMode::Vertical,
+        })?;
+        Ok(render)
+    }
+
+    /// Saves the editor's contents. `None` appends a new chapter
+    pub fn save_chapter(
+        &self,
+        params: params::LiteraryWorkParams,
+        chapter_id: Option<usize>,
+        user: Uuid,
+        form: params::ChapterFormParams,
+    ) -> Result<()> {
+        let found = self.update_own_work(&params, user, |work| {
+            match chapter_id {
+                Some(i) => {
+                    let Some(chapter) = work.chapters.get_mut(i) else {
+                        return false;
+                    };
+                    chapter.elements = editor::from_text(&form.text, &chapter.elements);
+                    chapter.title = form.title.clone();
+                    chapter.access = form.access;
+                    chapter.revision += 1;
+                }
+                None => {
+                    let elements = editor::from_text(&form.text, &[]);
+                    let mut chapter = Chapter::new(form.title.clone(), elements);
+                    chapter.access = form.access;
+                    work.chapters.push(chapter);
+                }
+            }
+            work.update = SystemTime::now();
+            true
+        })?;
+        if !found {
+            bail!(LibraryError::Missing(params.title));
+        }
+        if chapter_id.is_none() {
+            let work = self.lib.get_work(params.id)?;
+            self.notify_new_chapter(params.id, &work);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the price in cents and the end of the free campaign
+fn check_work_form(form: &params::WorkFormParams) -> Result<(Option<u32>, Option<SystemTime>)> {
+    if form.title.trim().is_empty() {
+        bail!("The title can't be empty");
+    }
+    let price = shop::parse_price(&form.price)?;
+    let free_until = match form.free_until.trim() {
+        "" => None,
+        day => {
+            let Ok(day) = NaiveDate::parse_from_str(day, "%Y-%m-%d") else {
+                bail!("{day} isn't a date");
+            };
+            // Free through the whole last day
+            let end = day.succ_opt().and_then(|d| d.and_hms_opt(0, 0, 0));
+            let Some(end) = end.map(|end| SystemTime::from(end.and_utc())) else {
+                bail!("{day} isn't a date");
+            };
+            if end <= SystemTime::now() {
+                bail!("The free campaign has to end in the future");
+            }
+            Some(end)
+        }
+    };
+    Ok((price, free_until))
+}
+
+fn apply_work_form(
+    work: &mut LiteraryWork,
+    form: &params::WorkFormParams,
+    price: Option<u32>,
+    free_until: Option<SystemTime>,
+) {
+    let split = |s: &str| {
+        s.split([',', '、'])
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<_>>()
+    };
+    work.title = form.title.trim().to_string();
+    work.description = form.description.clone();
+    work.tags = split(&form.genres)
+        .into_iter()
+        .map(Tag::Genre)
+        .chain(split(&form.tags).into_iter().map(Tag::Other))
+        .collect();
+    work.writing_mode = form.writing_mode;
+    work.price = price;
+    work.free_until = free_until;
+}
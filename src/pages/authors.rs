@@ -0,0 +1,72 @@
+//! Public pages of users who write works
+
+use anyhow::{bail, Result};
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    application::Application,
+    db::Backend,
+    error::LibraryError,
+    utils::{url_encode_segment, work_path},
+};
+
+impl<B: Backend> Application<B> {
+    /// Lists the works credited to the user currently called `name`
+    pub fn author_page(&self, name: &str, sid: Option<Uuid>) -> Result<String> {
+        let Some(id) = self.members.find_by_name(name) else {
+            bail!(LibraryError::Missing(name.to_string()));
+        };
+        let user = self.members.get_user(id)?;
+        // Only shown to other signed in users
+        let following = self
+            .signed_in(sid)
+            .filter(|&viewer| viewer != id)
+            .map(|viewer| self.notifications.is_following(viewer, id));
+
+        let works = self.lib.all_works_by(|(_, work)| work.is_creator(id));
+        let works = Value::from_iter(works.into_iter().map(|(id, work)| {
+            context! {
+                url => work_path(&work.title, id),
+                title => work.title,
+                chars => work.count.chars,
+            }
+        }));
+        let previous_names = Value::from_iter(user.previous_names.iter().map(|n| n.name.clone()));
+
+        let template = self.env.get_template("author.jinja")?;
+        let render = template.render(context! {
+            name => user.name,
+            joined => user.created.date_naive(),
+            previous_names,
+            works,
+            following,
+            shelves => self.public_shelves(id, &user.name),
+            follow_url => format!("/authors/{}/follow", url_encode_segment(&user.name)),
+        })?;
+        Ok(render)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{config::Config, user::Role};
+
+    use super::*;
+
+    #[test]
+    fn found_by_current_name() {
+        let app = Application::temporary(Config::default());
+        let id = app
+            .members
+            .create_user("alice".into(), "correct horse".into(), Role::Author)
+            .unwrap();
+        app.members.rename(id, "alicia".into()).unwrap();
+
+        assert!(app.author_page("alice", None).is_err());
+        // Names are looked up like they are reserved
+        let page = app.author_page("ALICIA", None).unwrap();
+        assert!(page.contains("<h1>alicia</h1>"));
+        assert!(page.contains("Previously known as alice"));
+    }
+}
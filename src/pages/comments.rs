@@ -0,0 +1,319 @@
+//! Comment threads under chapters and the tools to moderate them
+
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    application::Application,
+    comment::{self, Comment, Thread},
+    db::Backend,
+    entry::{Chapter, LiteraryWork},
+    error::LibraryError,
+    params,
+    stats::Metric,
+    user::Capability,
+    utils::{b64_encode_uuid, url_encode_segment, work_path},
+};
+
+impl<B: Backend> Application<B> {
+    /// Comments of a chapter, for the chapter template
+    pub(crate) fn chapter_comments(
+        &self,
+        work: &LiteraryWork,
+        chapter: &Chapter,
+        user: Option<Uuid>,
+    ) -> Value {
+        let moderator = user.is_some_and(|user| self.can_moderate(user));
+        let locked = self.comments.is_locked(chapter.id);
+        let banned = user.is_some_and(|user| self.comments.is_banned(user));
+        let viewer = Viewer {
+            user,
+            moderator,
+            can_reply: user.is_some() && !banned && (!locked || moderator),
+        };
+        let threads = comment::threads(self.comments.for_chapter(chapter.id));
+        context! {
+            threads => Value::from_iter(threads.into_iter().map(|t| self.thread_context(work, t, &viewer))),
+            locked,
+            banned,
+            moderator,
+            can_post => viewer.can_reply,
+        }
+    }
+
+    fn thread_context(&self, work: &LiteraryWork, thread: Thread, viewer: &Viewer) -> Value {
+        let Thread {
+            id,
+            comment: c,
+            replies,
+        } = thread;
+        let name = self.members.get_name(c.user).ok();
+        let mine = viewer.user == Some(c.user);
+        // Moderators can still read hidden comments
+        let text = (!c.deleted && (!c.hidden || viewer.moderator)).then_some(c.text);
+        let replies = replies
+            .into_iter()
+            .map(|t| self.thread_context(work, t, viewer));
+        context! {
+            id => b64_encode_uuid(id.as_bytes()),
+            author_url => name.as_ref().map(|n| format!("/authors/{}", url_encode_segment(n))),
+            name,
+            by_author => work.is_creator(c.user),
+            text,
+            deleted => c.deleted,
+            hidden => c.hidden,
+            date => chrono::DateTime::<chrono::Utc>::from(c.created).format("%Y-%m-%d %H:%M").to_string(),
+            edited => c.edited.is_some(),
+            can_edit => mine && !c.deleted && !c.hidden && viewer.can_reply,
+            can_delete => mine && !c.deleted,
+            can_reply => viewer.can_reply,
+            can_ban => viewer.moderator && !mine && !self.can_moderate(c.user),
+            author_banned => viewer.moderator && self.comments.is_banned(c.user),
+            replies => Value::from_iter(replies),
+        }
+    }
+
+    /// Starts a new thread under a chapter, returning the page to go back to
+    pub fn post_comment(
+        &self,
+        user: Uuid,
+        params: params::ChapterParams,
+        form: params::CommentParams,
+    ) -> Result<String> {
+        let Ok(work) = self.lib.get_work(params.work_params.id) else {
+            bail!(LibraryError::Missing(params.work_params.title));
+        };
+        let Some(chapter) = work.chapters.get(params.chapter_id) else {
+            bail!(LibraryError::Missing(params.work_params.title));
+        };
+        let access = work.access_to(chapter, SystemTime::now());
+        if !self.can_read(Some(user), params.work_params.id, &work, access) {
+            bail!(LibraryError::Forbidden(params.work_params.title));
+        }
+        let comment = Comment {
+            user,
+            work: params.work_params.id,
+            chapter: chapter.id,
+            parent: None,
+            text: form.text,
+            created: std::time::SystemTime::now(),
+            edited: None,
+            deleted: false,
+            hidden: false,
+        };
+        let id = self.add_comment(comment)?;
+        let url = work_path(&params.work_params.title, params.work_params.id);
+        Ok(format!(
+            "{url}/{}#c{}",
+            params.chapter_id,
+            b64_encode_uuid(id.as_bytes())
+        ))
+    }
+
+    pub fn reply_to_comment(&self, user: Uuid, parent: Uuid, text: String) -> Result<String> {
+        let Some(p) = self.comments.get(parent) else {
+            bail!("That comment doesn't exist");
+        };
+        let work = self.lib.get_work(p.work)?;
+        let Some(chapter) = work.chapters.iter().find(|c| c.id == p.chapter) else {
+            bail!(LibraryError::Missing(work.title));
+        };
+        let access = work.access_to(chapter, SystemTime::now());
+        if !self.can_read(Some(user), p.work, &work, access) {
+            bail!(LibraryError::Forbidden(work.title));
+        }
+        let comment = Comment {
+            user,
+            parent: Some(parent),
+            text,
+            created: std::time::SystemTime::now(),
+            edited: None,
+            deleted: false,
+            hidden: false,
+            ..p
+        };
+        let id = self.add_comment(comment)?;
+        self.comment_url(id)
+    }
+
+    /// Checks bans, locks and the rate limit before saving
+    fn add_comment(&self, mut comment: Comment) -> Result<Uuid> {
+        if self.comments.is_banned(comment.user) {
+            bail!("You have been banned from commenting");
+        }
+        let moderator = self.can_moderate(comment.user);
+        if self.comments.is_locked(comment.chapter) && !moderator {
+            bail!("The comments on this chapter are locked");
+        }
+        let key = comment.user.to_string();
+        if let Some(wait) = self.comment_posts.check(&key) {
+            let minutes = wait.as_secs().div_ceil(60).max(1);
+            bail!("You're commenting too quickly. Try again in {minutes} min");
+        }
+        comment.text = comment::validate(&comment.text)?;
+        self.comment_posts.record(&key);
+        let id = self.comments.add(&comment)?;
+        self.stats
+            .record(Metric::Comments, comment.work, Some(comment.chapter), 1);
+        Ok(id)
+    }
+
+    pub fn edit_comment(&self, user: Uuid, id: Uuid, text: String) -> Result<String> {
+        let comment = self.own_comment(user, id)?;
+        if comment.hidden {
+            bail!("Hidden comments can't be edited");
+        }
+        if self.comments.is_banned(user) {
+            bail!("You have been banned from commenting");
+        }
+        if self.comments.is_locked(comment.chapter) && !self.can_moderate(user) {
+            bail!("The comments on this chapter are locked");
+        }
+        let text = comment::validate(&text)?;
+        self.comments.update(id, |c| {
+            c.text = text;
+            c.edited = Some(std::time::SystemTime::now());
+        })?;
+        self.comment_url(id)
+    }
+
+    /// Removes the text but keeps the comment, so replies stay in their thread
+    pub fn delete_comment(&self, user: Uuid, id: Uuid) -> Result<String> {
+        self.own_comment(user, id)?;
+        self.comments.update(id, |c| {
+            c.text.clear();
+            c.deleted = true;
+        })?;
+        self.comment_url(id)
+    }
+
+    /// Hides or shows a comment to everyone but moderators
+    pub fn toggle_hidden(&self, id: Uuid) -> Result<String> {
+        self.comments.update(id, |c| c.hidden = !c.hidden)?;
+        self.comment_url(id)
+    }
+
+    /// Bans or unbans the writer of a comment from commenting anywhere
+    pub fn toggle_comment_ban(&self, moderator: Uuid, id: Uuid) -> Result<String> {
+        let Some(comment) = self.comments.get(id) else {
+            bail!("That comment doesn't exist");
+        };
+        if comment.user == moderator || self.can_moderate(comment.user) {
+            bail!("Moderators can't be banned from commenting");
+        }
+        let banned = self.comments.is_banned(comment.user);
+        self.comments.set_banned(comment.user, !banned);
+        self.comment_url(id)
+    }
+
+    /// Locks or unlocks the comments of a chapter
+    pub fn toggle_comment_lock(&self, params: &params::ChapterParams) -> Result<()> {
+        let Ok(work) = self.lib.get_work(params.work_params.id) else {
+            bail!(LibraryError::Missing(params.work_params.title.clone()));
+        };
+        let Some(chapter) = work.chapters.get(params.chapter_id) else {
+            bail!(LibraryError::Missing(params.work_params.title.clone()));
+        };
+        let locked = self.comments.is_locked(chapter.id);
+        self.comments.set_locked(chapter.id, !locked);
+        Ok(())
+    }
+
+    fn own_comment(&self, user: Uuid, id: Uuid) -> Result<Comment> {
+        match self.comments.get(id) {
+            Some(comment) if comment.user == user && !comment.deleted => Ok(comment),
+            _ => bail!("That comment doesn't exist"),
+        }
+    }
+
+    /// Where a comment is shown
+    fn comment_url(&self, id: Uuid) -> Result<String> {
+        let Some(comment) = self.comments.get(id) else {
+            bail!("That comment doesn't exist");
+        };
+        let work = self.lib.get_work(comment.work)?;
+        let Some(i) = work.chapters.iter().position(|c| c.id == comment.chapter) else {
+            bail!(LibraryError::Missing(work.title));
+        };
+        let url = work_path(&work.title, comment.work);
+        Ok(format!("{url}/{i}#c{}", b64_encode_uuid(id.as_bytes())))
+    }
+
+    fn can_moderate(&self, user: Uuid) -> bool {
+        self.members
+            .get_role(user)
+            .is_ok_and(|role| role.can(Capability::Moderate))
+    }
+}
+
+/// Who is reading the comments
+struct Viewer {
+    user: Option<Uuid>,
+    moderator: bool,
+    /// Not banned and the thread isn't locked to them
+    can_reply: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{config::Config, entry::ChapterAccess, user::Role};
+
+    use super::*;
+
+    #[test]
+    fn replies_need_access_to_the_chapter() {
+        let app = Application::temporary(Config::default());
+        let author = app
+            .members
+            .create_user(
+                "author".into(),
+                "correct horse battery".into(),
+                Role::Reader,
+            )
+            .unwrap();
+        let reader = app
+            .members
+            .create_user(
+                "reader".into(),
+                "staple lantern violet".into(),
+                Role::Reader,
+            )
+            .unwrap();
+
+        let mut work = LiteraryWork::new("Paid".into(), String::new());
+        work.creators.push(crate::user::UserRef {
+            id: Some(author),
+            name: "author".into(),
+            created: SystemTime::now(),
+        });
+        work.price = Some(300);
+        let mut chapter = Chapter::new(String::new(), vec![]);
+        chapter.access = ChapterAccess::Purchase;
+        let chapter_id = chapter.id;
+        work.chapters.push(chapter);
+        let work = app.lib.add_work(work).unwrap();
+        let parent = app
+            .comments
+            .add(&Comment {
+                user: author,
+                work,
+                chapter: chapter_id,
+                parent: None,
+                text: "Thanks for reading".into(),
+                created: SystemTime::now(),
+                edited: None,
+                deleted: false,
+                hidden: false,
+            })
+            .unwrap();
+
+        assert!(app.reply_to_comment(reader, parent, "Hi".into()).is_err());
+        app.members
+            .update_library(reader, |lib| lib.works.push(work))
+            .unwrap();
+        assert!(app.reply_to_comment(reader, parent, "Hi".into()).is_ok());
+    }
+}
@@ -1 +1,15 @@
-
+mod account;
+mod admin;
+mod annotations;
+mod authoring;
+mod authors;
+mod comments;
+mod notifications;
+mod reading;
+mod recovery;
+mod reviews;
+mod shelves;
+mod shop;
+mod stats;
+mod tokens;
+mod two_factor;
@@ -0,0 +1,170 @@
+//! Following works and authors, and the inbox of new chapters and works
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    application::Application,
+    db::Backend,
+    entry::LiteraryWork,
+    error::LibraryError,
+    notification::{FollowKind, Notification, NotificationKind},
+    params,
+    utils::{b64_encode_uuid, url_encode_segment, work_path},
+};
+
+impl<B: Backend> Application<B> {
+    /// The user's notifications and everything they follow
+    pub fn inbox_page(&self, user: Uuid) -> Result<String> {
+        let notifications = self
+            .notifications
+            .for_user(user)
+            .into_iter()
+            .map(|(id, n)| {
+                context! {
+                    id => b64_encode_uuid(id.as_bytes()),
+                    new_work => n.kind == NotificationKind::NewWork,
+                    author => n.author.and_then(|a| self.members.get_name(a).ok()),
+                    work_title => n.work_title,
+                    chapter_title => n.chapter_title,
+                    date => chrono::DateTime::<chrono::Utc>::from(n.created).format("%Y-%m-%d %H:%M").to_string(),
+                    read => n.read,
+                }
+            });
+        let following =
+            self.notifications
+                .following(user)
+                .into_iter()
+                .filter_map(|(target, follow)| match follow.kind {
+                    FollowKind::Work => {
+                        let work = self.lib.get_work(target).ok()?;
+                        Some(context! {
+                            name => work.title,
+                            url => work_path(&work.title, target),
+                        })
+                    }
+                    FollowKind::Author => {
+                        let name = self.members.get_name(target).ok()?;
+                        Some(context! {
+                            url => format!("/authors/{}", url_encode_segment(&name)),
+                            name,
+                        })
+                    }
+                });
+
+        let template = self.env.get_template("inbox.jinja")?;
+        let render = template.render(context! {
+            notifications => Value::from_iter(notifications),
+            following => Value::from_iter(following),
+        })?;
+        Ok(render)
+    }
+
+    /// Marks a notification read, returning where it leads
+    pub fn open_notification(&self, user: Uuid, id: Uuid) -> Result<String> {
+        let n = self.notifications.mark_read(user, id)?;
+        // The work may have been trashed since
+        let Ok(work) = self.lib.get_work(n.work) else {
+            return Ok("/user/inbox".to_string());
+        };
+        let url = work_path(&work.title, n.work);
+        let chapter = n
+            .chapter
+            .and_then(|chapter| work.chapters.iter().position(|c| c.id == chapter));
+        Ok(match chapter {
+            Some(i) => format!("{url}/{i}"),
+            None => url,
+        })
+    }
+
+    pub fn mark_all_read(&self, user: Uuid) -> Result<()> {
+        self.notifications.mark_all_read(user)
+    }
+
+    /// Follows the work, or stops following it
+    pub fn toggle_follow_work(
+        &self,
+        user: Uuid,
+        params: &params::LiteraryWorkParams,
+    ) -> Result<()> {
+        if self.lib.get_work(params.id).is_err() {
+            bail!(LibraryError::Missing(params.title.clone()));
+        }
+        self.toggle_follow(user, params.id, FollowKind::Work)
+    }
+
+    /// Follows the user currently called `name`, or stops following them
+    pub fn toggle_follow_author(&self, user: Uuid, name: &str) -> Result<()> {
+        let Some(author) = self.members.find_by_name(name) else {
+            bail!(LibraryError::Missing(name.to_string()));
+        };
+        if author == user {
+            bail!("You can't follow yourself");
+        }
+        self.toggle_follow(user, author, FollowKind::Author)
+    }
+
+    fn toggle_follow(&self, user: Uuid, target: Uuid, kind: FollowKind) -> Result<()> {
+        if self.notifications.is_following(user, target) {
+            self.notifications.unfollow(user, target);
+            Ok(())
+        } else {
+            self.notifications.follow(user, target, kind)
+        }
+    }
+
+    /// Tells the followers of a work about its last chapter
+    pub(crate) fn notify_new_chapter(&self, id: Uuid, work: &LiteraryWork) {
+        let Some(chapter) = work.chapters.last() else {
+            return;
+        };
+        let notification = Notification {
+            kind: NotificationKind::NewChapter,
+            work: id,
+            work_title: work.title.clone(),
+            chapter: Some(chapter.id),
+            chapter_title: Some(chapter.title.clone()),
+            author: None,
+            created: std::time::SystemTime::now(),
+            read: false,
+        };
+        for user in self.notifications.followers(id) {
+            // Creators know about their own chapters
+            if !work.is_creator(user) {
+                self.send(user, &notification);
+            }
+        }
+    }
+
+    /// Tells the followers of a work's creators that it was started. Those following several of
+    /// them are only told once
+    pub(crate) fn notify_new_work(&self, id: Uuid, work: &LiteraryWork) {
+        let mut told = HashSet::new();
+        for author in work.creators.iter().filter_map(|c| c.id) {
+            let notification = Notification {
+                kind: NotificationKind::NewWork,
+                work: id,
+                work_title: work.title.clone(),
+                chapter: None,
+                chapter_title: None,
+                author: Some(author),
+                created: std::time::SystemTime::now(),
+                read: false,
+            };
+            for user in self.notifications.followers(author) {
+                if !work.is_creator(user) && told.insert(user) {
+                    self.send(user, &notification);
+                }
+            }
+        }
+    }
+
+    fn send(&self, user: Uuid, notification: &Notification) {
+        if let Err(e) = self.notifications.notify(user, notification) {
+            tracing::warn!("Could not notify {user}: {e}");
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Reading progress of signed in users
+
+use anyhow::{bail, Result};
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    application::Application, db::Backend, error::LibraryError, params, stats::Metric,
+    user::UserLibrary, utils::work_path,
+};
+
+impl<B: Backend> Application<B> {
+    /// Works the user has started, most recent first, with a link back to where they stopped
+    pub(crate) fn continue_reading(&self, lib: &UserLibrary) -> Value {
+        let works = lib.recently_read().into_iter().filter_map(|aw| {
+            // Works in the trash are skipped
+            let work = self.lib.get_work(aw.id).ok()?;
+            let chapter = aw
+                .last_chapter
+                .and_then(|last| work.chapters.iter().position(|c| c.id == last))
+                .unwrap_or_default();
+            let unread = work
+                .chapters
+                .iter()
+                .filter(|c| !aw.read_chapters.contains(&c.id))
+                .count();
+            Some(context! {
+                title => work.title,
+                url => format!("{}/{chapter}", work_path(&work.title, aw.id)),
+                chapter => chapter + 1,
+                chapters => work.chapters.len(),
+                percent => (aw.position * 100.0).round() as u32,
+                unread,
+            })
+        });
+        Value::from_iter(works)
+    }
+
+    /// Remembers how far into the chapter the user scrolled
+    pub fn save_position(
+        &self,
+        user: Uuid,
+        params: params::ChapterParams,
+        position: f32,
+    ) -> Result<()> {
+        let work = params.work_params.id;
+        let chapter = self.chapter_uuid(&params)?;
+        self.members
+            .update_library(user, |lib| lib.set_position(work, chapter, position))
+    }
+
+    pub fn set_chapter_read(
+        &self,
+        user: Uuid,
+        params: params::ChapterParams,
+        read: bool,
+    ) -> Result<()> {
+        let Ok(work) = self.lib.get_work(params.work_params.id) else {
+            bail!(LibraryError::Missing(params.work_params.title));
+        };
+        let Some(chapter) = work.chapters.get(params.chapter_id).map(|c| c.id) else {
+            bail!(LibraryError::Missing(params.work_params.title));
+        };
+        let chapters: Vec<_> = work.chapters.iter().map(|c| c.id).collect();
+        let id = params.work_params.id;
+        self.update_progress(user, id, &chapters, !work.is_creator(user), |lib| {
+            lib.set_read(id, chapter, read)
+        })
+    }
+
+    /// Applies `f` to the user's library. The first time it leaves every chapter of the work read,
+    /// it counts as a completion unless `counted` is false. Unreading and rereading doesn't count
+    /// again
+    pub(crate) fn update_progress(
+        &self,
+        user: Uuid,
+        work: Uuid,
+        chapters: &[Uuid],
+        counted: bool,
+        mut f: impl FnMut(&mut UserLibrary),
+    ) -> Result<()> {
+        let finished = |lib: &UserLibrary| {
+            !chapters.is_empty()
+                && lib
+                    .progress(work)
+                    .is_some_and(|p| chapters.iter().all(|c| p.read_chapters.contains(c)))
+        };
+        let completed = self.members.update_library(user, |lib| {
+            f(lib);
+            finished(lib) && lib.mark_completed(work)
+        })?;
+        if completed && counted {
+            self.stats.record(Metric::Completions, work, None, 1);
+        }
+        Ok(())
+    }
+
+    /// Chapters are addressed by position in URLs but tracked by id, so progress survives
+    /// reordering
+    fn chapter_uuid(&self, params: &params::ChapterParams) -> Result<Uuid> {
+        let Ok(work) = self.lib.get_work(params.work_params.id) else {
+            bail!(LibraryError::Missing(params.work_params.title.clone()));
+        };
+        match work.chapters.get(params.chapter_id) {
+            Some(chapter) => Ok(chapter.id),
+            None => bail!(LibraryError::Missing(params.work_params.title.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::Config, user::Role};
+
+    #[test]
+    fn completions_count_once() {
+        let app = Application::temporary(Config::default());
+        let reader = app
+            .members
+            .create_user(
+                "reader".into(),
+                "correct horse battery".into(),
+                Role::Reader,
+            )
+            .unwrap();
+        let work = Uuid::now_v7();
+        let chapters = [Uuid::now_v7(), Uuid::now_v7()];
+        let read = |chapter, read| {
+            app.update_progress(reader, work, &chapters, true, |lib| {
+                lib.set_read(work, chapter, read)
+            })
+            .unwrap()
+        };
+        let completions = || {
+            app.stats.flush().unwrap();
+            app.stats
+                .series(Metric::Completions, work, None)
+                .map_or(0, |s| s.total())
+        };
+
+        read(chapters[0], true);
+        assert_eq!(completions(), 0);
+        read(chapters[1], true);
+        assert_eq!(completions(), 1);
+        // Unreading and rereading the last chapter doesn't finish the work again
+        for _ in 0..3 {
+            read(chapters[1], false);
+            read(chapters[1], true);
+        }
+        assert_eq!(completions(), 1);
+    }
+}
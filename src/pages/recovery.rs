@@ -0,0 +1,198 @@
+//! Email addresses, confirming them and resetting forgotten passwords by mail
+
+use std::net::IpAddr;
+
+use anyhow::{bail, Result};
+use minijinja::context;
+use uuid::Uuid;
+
+use crate::{
+    application::Application, db::Backend, mail::Mail, mail_link::LinkPurpose, user::Login,
+    validate,
+};
+
+impl<B: Backend> Application<B> {
+    /// Signs up a user, returning their session. There is none while they have to confirm
+    /// their email address, which they are sent a link for
+    pub fn sign_up(
+        &self,
+        name: String,
+        pswd: String,
+        email: String,
+        user_agent: Option<String>,
+    ) -> Result<Option<Uuid>> {
+        let email = Some(email).filter(|e| !e.trim().is_empty());
+        let (user, sid) = match self
+            .members
+            .try_create_user(name, pswd, email, user_agent)?
+        {
+            Login::Session(sid) => (self.members.get_user_for_sid(sid), Some(sid)),
+            Login::Unverified(user) => (Some(user), None),
+            Login::SecondFactor(_) => bail!("New accounts don't have two-factor login"),
+        };
+        // The account exists either way, and logging in sends the link again
+        if let Some(Err(e)) = user.map(|user| self.send_verification(user)) {
+            tracing::error!("Couldn't send a verification mail: {e}");
+        }
+        Ok(sid)
+    }
+
+    /// Mails a link to confirm the address the user gave, if there is one
+    pub fn send_verification(&self, user: Uuid) -> Result<()> {
+        let data = self.members.get_user(user)?;
+        let Some(email) = data.unverified_email else {
+            return Ok(());
+        };
+        let secret = self
+            .members
+            .links
+            .create(user, LinkPurpose::VerifyEmail(email.clone()))?;
+        self.mailer.send(Mail {
+            to: email,
+            subject: "Confirm your email address".to_string(),
+            body: format!(
+                "Hi {},\n\nOpen this link to confirm your email address:\n{}/verify-email/{secret}\n",
+                data.name, self.config.mail.base_url
+            ),
+        })
+    }
+
+    /// Changes the user's address once they open the link mailed to it
+    pub fn set_email(&self, user: Uuid, email: &str) -> Result<()> {
+        self.members.set_email(user, email)?;
+        self.send_verification(user)
+    }
+
+    pub fn verify_email(&self, secret: &str) -> Result<()> {
+        let (user, email) = self.members.links.take_verification(secret)?;
+        self.members.verify_email(user, &email)
+    }
+
+    /// Counts a request for a reset mail against the address it came from
+    pub fn throttle_password_reset(&self, ip: IpAddr) -> Result<()> {
+        let ip = ip.to_string();
+        if self.reset_requests.check(&ip).is_some() {
+            bail!("Too many reset requests. Try again later");
+        }
+        self.reset_requests.record(&ip);
+        Ok(())
+    }
+
+    /// Mails a reset link if the address belongs to an account. Whether it does isn't revealed,
+    /// so this runs after the response is sent
+    pub fn send_password_reset(&self, email: &str) -> Result<()> {
+        let Some(user) = self.members.find_by_email(email.trim()) else {
+            return Ok(());
+        };
+        let name = self.members.get_name(user)?;
+        let secret = self
+            .members
+            .links
+            .create(user, LinkPurpose::ResetPassword)?;
+        let mail = Mail {
+            to: email.trim().to_string(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Hi {name},\n\nSomeone asked to reset the password of your account. If it was you, open this link within an hour:\n{}/reset-password/{secret}\n\nOtherwise you can ignore this mail.\n",
+                self.config.mail.base_url
+            ),
+        };
+        self.mailer.send(mail)
+    }
+
+    /// Uses up the link and sets the new password. A password that isn't allowed leaves the link
+    /// to try again with
+    pub fn reset_password(&self, secret: &str, pswd: &str) -> Result<()> {
+        let Some(user) = self.members.links.peek_reset(secret) else {
+            bail!("The link has expired or was already used");
+        };
+        validate::password(pswd, &self.members.get_name(user)?)?;
+        let user = self.members.links.take_reset(secret)?;
+        self.members.reset_password(user, pswd)
+    }
+
+    pub fn forgot_password_page(&self, sent: bool, error: Option<&str>) -> Result<String> {
+        let template = self.env.get_template("forgot_password.jinja")?;
+        let render = template.render(context! { sent, error })?;
+        Ok(render)
+    }
+
+    pub fn reset_password_page(&self, secret: &str, error: Option<&str>) -> Result<String> {
+        let valid = self.members.links.peek_reset(secret).is_some();
+        let template = self.env.get_template("reset_password.jinja")?;
+        let render = template.render(context! { secret, valid, error })?;
+        Ok(render)
+    }
+
+    /// A page with just a message, e.g. after signing up
+    pub fn notice_page(&self, title: &str, message: &str) -> Result<String> {
+        let template = self.env.get_template("notice.jinja")?;
+        let render = template.render(context! { title, message })?;
+        Ok(render)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::{
+        config::{Config, MailTransport},
+        user::Role,
+    };
+
+    /// Secrets of the links to `path` in the mails written to `dir`, and the mails removed
+    fn take_links(dir: &Path, path: &str) -> Vec<String> {
+        let mut links = vec![];
+        for file in std::fs::read_dir(dir).unwrap() {
+            let file = file.unwrap().path();
+            let mail = std::fs::read_to_string(&file).unwrap();
+            std::fs::remove_file(file).unwrap();
+            // Long lines are wrapped with soft breaks
+            let mail = mail.replace("=\r\n", "");
+            if let Some((_, rest)) = mail.split_once(&format!("/{path}/")) {
+                let secret = rest.split(|c: char| c.is_whitespace()).next().unwrap();
+                links.push(secret.to_string());
+            }
+        }
+        links
+    }
+
+    #[test]
+    fn reset_by_mail() {
+        let dir: PathBuf = std::env::temp_dir().join(format!("toshokan-mail-{}", Uuid::now_v7()));
+        let mut config = Config::default();
+        config.mail.transport = MailTransport::File { dir: dir.clone() };
+        let app = Application::temporary(config);
+        let user = app
+            .members
+            .create_user("alice".into(), "correct horse".into(), Role::Reader)
+            .unwrap();
+
+        app.set_email(user, "alice@example.com").unwrap();
+        let verify = take_links(&dir, "verify-email");
+        assert_eq!(verify.len(), 1);
+        // Unconfirmed addresses don't get reset links
+        app.send_password_reset("alice@example.com").unwrap();
+        assert!(take_links(&dir, "reset-password").is_empty());
+        app.verify_email(&verify[0]).unwrap();
+
+        app.send_password_reset("nobody@example.com").unwrap();
+        assert!(take_links(&dir, "reset-password").is_empty());
+        app.send_password_reset(" alice@example.com ").unwrap();
+        let old = take_links(&dir, "reset-password");
+        app.send_password_reset("alice@example.com").unwrap();
+        let new = take_links(&dir, "reset-password");
+        assert_eq!((old.len(), new.len()), (1, 1));
+
+        assert!(app.reset_password(&old[0], "staple lantern").is_err());
+        // A password that isn't allowed leaves the link to try again
+        assert!(app.reset_password(&new[0], "alice").is_err());
+        app.reset_password(&new[0], "staple lantern").unwrap();
+        assert!(app.reset_password(&new[0], "violet gravel").is_err());
+        app.members.check_password(user, "staple lantern").unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
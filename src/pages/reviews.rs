@@ -0,0 +1,90 @@
+//! Ratings and reviews on work pages
+
+use anyhow::{bail, Result};
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    application::Application,
+    db::Backend,
+    entry::LiteraryWork,
+    error::LibraryError,
+    params,
+    review::ReviewSort,
+    utils::{b64_encode_uuid, url_encode_segment},
+};
+
+impl<B: Backend> Application<B> {
+    /// Rating summary, the user's own review and the reviews of others, for the work template
+    pub(crate) fn work_reviews(
+        &self,
+        id: Uuid,
+        work: &LiteraryWork,
+        user: Option<Uuid>,
+        sort: ReviewSort,
+    ) -> Value {
+        let ratings = work.stats.ratings();
+        let mine = user.and_then(|user| self.reviews.get(id, user));
+        let reviews = self.reviews.for_work(id, sort).into_iter().map(|r| {
+            let name = self.members.get_name(r.user).ok();
+            context! {
+                reviewer => b64_encode_uuid(r.user.as_bytes()),
+                author_url => name.as_ref().map(|n| format!("/authors/{}", url_encode_segment(n))),
+                name,
+                stars => r.stars,
+                text => r.text,
+                spoiler => r.spoiler,
+                date => chrono::DateTime::<chrono::Utc>::from(r.created).date_naive(),
+                edited => r.edited.is_some(),
+                helpful => r.helpful.len(),
+                voted => user.is_some_and(|user| r.helpful.contains(&user)),
+                can_vote => user.is_some_and(|user| user != r.user),
+            }
+        });
+        context! {
+            average => ratings.average().map(|a| format!("{a:.1}")),
+            count => ratings.count(),
+            can_rate => user.is_some_and(|user| !work.is_creator(user)),
+            mine => mine.map(|r| context! { stars => r.stars, text => r.text, spoiler => r.spoiler }),
+            newest => sort == ReviewSort::Newest,
+            list => Value::from_iter(reviews),
+        }
+    }
+
+    /// Rates the work, replacing the user's earlier rating and review
+    pub fn review_work(
+        &self,
+        user: Uuid,
+        params: params::LiteraryWorkParams,
+        form: params::ReviewParams,
+    ) -> Result<()> {
+        let Ok(work) = self.lib.get_work(params.id) else {
+            bail!(LibraryError::Missing(params.title));
+        };
+        if work.is_creator(user) {
+            bail!("You can't rate your own work");
+        }
+        let old = self.reviews.save(
+            params.id,
+            user,
+            form.stars,
+            form.text,
+            form.spoiler.is_some(),
+        )?;
+        self.lib
+            .update_work(params.id, |work| work.stats.rate(old, Some(form.stars)))
+    }
+
+    pub fn delete_review(&self, user: Uuid, params: params::LiteraryWorkParams) -> Result<()> {
+        let Some(review) = self.reviews.remove(params.id, user)? else {
+            bail!("You haven't rated this work");
+        };
+        self.lib
+            .update_work(params.id, |work| work.stats.rate(Some(review.stars), None))
+    }
+
+    pub fn vote_helpful(&self, user: Uuid, params: params::ReviewerParams) -> Result<()> {
+        self.reviews
+            .toggle_helpful(params.work_params.id, params.reviewer, user)
+    }
+}
@@ -0,0 +1,174 @@
+//! Bookshelves and the public reading lists made from them
+
+use anyhow::{bail, Result};
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    application::Application,
+    db::Backend,
+    error::LibraryError,
+    params,
+    shelf::Shelf,
+    utils::{b64_encode_uuid, url_encode_segment, work_path},
+};
+
+impl<B: Backend> Application<B> {
+    /// The user's shelves, with forms to change them
+    pub fn shelves_page(&self, user: Uuid) -> Result<String> {
+        let name = self.members.get_name(user)?;
+        let lib = self.members.get_library(user)?;
+        let shelves = Value::from_iter(lib.shelves.iter().map(|shelf| {
+            context! {
+                public_url => shelf_url(&name, shelf),
+                status => shelf.kind.is_status(),
+                ..self.shelf_context(shelf)
+            }
+        }));
+        let template = self.env.get_template("shelves.jinja")?;
+        let render = template.render(context! { shelves })?;
+        Ok(render)
+    }
+
+    /// A public shelf. Its owner can also see it while it's private
+    pub fn shelf_page(
+        &self,
+        params: params::PublicShelfParams,
+        sid: Option<Uuid>,
+    ) -> Result<String> {
+        let Some(owner) = self.members.find_by_name(&params.name) else {
+            bail!(LibraryError::Missing(params.name));
+        };
+        let lib = self.members.get_library(owner)?;
+        let shelf = lib
+            .shelf(params.shelf)
+            .filter(|shelf| shelf.public || self.signed_in(sid) == Some(owner));
+        let Some(shelf) = shelf else {
+            bail!(LibraryError::Missing(params.name));
+        };
+        let owner_name = self.members.get_name(owner)?;
+        let template = self.env.get_template("shelf.jinja")?;
+        let render = template.render(context! {
+            owner => owner_name,
+            owner_url => format!("/authors/{}", url_encode_segment(&owner_name)),
+            ..self.shelf_context(shelf)
+        })?;
+        Ok(render)
+    }
+
+    /// Links to the public shelves of a user, for their author page
+    pub(crate) fn public_shelves(&self, user: Uuid, name: &str) -> Value {
+        let Ok(lib) = self.members.get_library(user) else {
+            return Value::from(());
+        };
+        Value::from_iter(lib.shelves.iter().filter(|s| s.public).map(|shelf| {
+            context! {
+                name => shelf.name,
+                url => shelf_url(name, shelf),
+                count => shelf.works.len(),
+            }
+        }))
+    }
+
+    /// The shelves a work could go on, for the work's page
+    pub(crate) fn work_shelves(&self, user: Uuid, work: Uuid) -> Value {
+        let Ok(lib) = self.members.get_library(user) else {
+            return Value::from(());
+        };
+        Value::from_iter(lib.shelves.iter().map(|shelf| {
+            context! {
+                id => b64_encode_uuid(shelf.id.as_bytes()),
+                name => shelf.name,
+                on => shelf.works.contains(&work),
+            }
+        }))
+    }
+
+    /// Puts the work on the shelf, or takes it off
+    pub fn toggle_shelved(
+        &self,
+        user: Uuid,
+        params: &params::LiteraryWorkParams,
+        form: params::ShelveParams,
+    ) -> Result<()> {
+        if self.lib.get_work(params.id).is_err() {
+            bail!(LibraryError::Missing(params.title.clone()));
+        }
+        self.members.update_library(user, |lib| {
+            let on = lib
+                .shelf(form.shelf)
+                .is_some_and(|s| s.works.contains(&params.id));
+            if on {
+                lib.unshelve(form.shelf, params.id)
+            } else {
+                lib.shelve(form.shelf, params.id)
+            }
+        })?
+    }
+
+    pub fn create_shelf(&self, user: Uuid, form: params::ShelfFormParams) -> Result<()> {
+        self.members
+            .update_library(user, |lib| {
+                lib.create_shelf(&form.name, form.public.is_some())
+            })?
+            .map(|_| ())
+    }
+
+    pub fn rename_shelf(&self, user: Uuid, shelf: Uuid, name: &str) -> Result<()> {
+        self.members
+            .update_library(user, |lib| lib.rename_shelf(shelf, name))?
+    }
+
+    pub fn set_shelf_public(&self, user: Uuid, shelf: Uuid, public: bool) -> Result<()> {
+        self.members
+            .update_library(user, |lib| lib.set_shelf_public(shelf, public))?
+    }
+
+    pub fn delete_shelf(&self, user: Uuid, shelf: Uuid) -> Result<()> {
+        self.members
+            .update_library(user, |lib| lib.delete_shelf(shelf))?
+    }
+
+    pub fn unshelve_work(&self, user: Uuid, params: params::ShelfWorkParams) -> Result<()> {
+        self.members
+            .update_library(user, |lib| lib.unshelve(params.shelf, params.work))?
+    }
+
+    pub fn move_on_shelf(
+        &self,
+        user: Uuid,
+        params: params::ShelfWorkParams,
+        form: params::MoveShelfWorkParams,
+    ) -> Result<()> {
+        self.members.update_library(user, |lib| {
+            lib.move_on_shelf(params.shelf, params.work, form.direction)
+        })?
+    }
+
+    /// Name and works of a shelf. Works no longer in the library are left out
+    fn shelf_context(&self, shelf: &Shelf) -> Value {
+        let works = shelf.works.iter().filter_map(|&id| {
+            let work = self.lib.get_work(id).ok()?;
+            Some(context! {
+                id => b64_encode_uuid(id.as_bytes()),
+                url => work_path(&work.title, id),
+                title => work.title,
+                chars => work.count.chars,
+            })
+        });
+        context! {
+            id => b64_encode_uuid(shelf.id.as_bytes()),
+            name => shelf.name,
+            public => shelf.public,
+            works => Value::from_iter(works),
+        }
+    }
+}
+
+fn shelf_url(owner: &str, shelf: &Shelf) -> String {
+    format!(
+        "/authors/{}/shelves/{}",
+        url_encode_segment(owner),
+        b64_encode_uuid(shelf.id.as_bytes())
+    )
+}
@@ -0,0 +1,350 @@
+//! Buying works: the cart kept with the session, checkout and past orders
+
+use anyhow::{bail, Result};
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    application::Application,
+    db::Backend,
+    error::LibraryError,
+    export::time,
+    params,
+    payment::{Charge, Payment},
+    shop::{format_price, Order, OrderItem, OrderStatus},
+    stats::Metric,
+    user::UserLibrary,
+    utils::{b64_encode_uuid, work_path},
+};
+
+impl<B: Backend> Application<B> {
+    pub fn add_to_cart(
+        &self,
+        user: Uuid,
+        sid: Uuid,
+        params: &params::LiteraryWorkParams,
+    ) -> Result<()> {
+        let Ok(work) = self.lib.get_work(params.id) else {
+            bail!(LibraryError::Missing(params.title.clone()));
+        };
+        if work.price.is_none() {
+            bail!("{} is free", work.title);
+        }
+        if work.is_creator(user) {
+            bail!("You can't buy your own work");
+        }
+        if self.members.get_library(user)?.works.contains(&params.id) {
+            bail!("You already own {}", work.title);
+        }
+        self.members.sessions.update_cart(sid, |cart| {
+            if !cart.contains(&params.id) {
+                cart.push(params.id);
+            }
+        })
+    }
+
+    pub fn remove_from_cart(&self, sid: Uuid, work: Uuid) -> Result<()> {
+        self.members
+            .sessions
+            .update_cart(sid, |cart| cart.retain(|&w| w != work))
+    }
+
+    /// Works in the cart that can still be bought, at today's prices
+    fn cart_items(&self, user: Uuid, cart: Vec<Uuid>) -> Result<Vec<OrderItem>> {
+        let owned = self.members.get_library(user)?.works;
+        Ok(cart
+            .into_iter()
+            .filter(|id| !owned.contains(id))
+            .filter_map(|id| {
+                let work = self.lib.get_work(id).ok()?;
+                Some(OrderItem {
+                    work: id,
+                    price: work.price?,
+                    title: work.title,
+                })
+            })
+            .collect())
+    }
+
+    pub fn cart_page(&self, user: Uuid, sid: Uuid, error: Option<&str>) -> Result<String> {
+        let currency = &self.config.shop.currency;
+        let items = self.cart_items(user, self.members.sessions.cart(sid))?;
+        let total: u64 = items.iter().map(|i| u64::from(i.price)).sum();
+        let template = self.env.get_template("cart.jinja")?;
+        let render = template.render(context! {
+            items => Value::from_iter(items.into_iter().map(|i| {
+                context! {
+                    url => work_path(&i.title, i.work),
+                    uuid => b64_encode_uuid(i.work.as_bytes()),
+                    title => i.title,
+                    price => format_price(i.price.into(), currency),
+                }
+            })),
+            total => format_price(total, currency),
+            error,
+        })?;
+        Ok(render)
+    }
+
+    /// Charges the user for the cart and adds the works to their library. Returns the order,
+    /// which is kept even when the payment fails
+    pub fn checkout(&self, user: Uuid, sid: Uuid, form: params::CheckoutParams) -> Result<Uuid> {
+        // So nothing that was paid for is charged again
+        self.reconcile_purchases(user)?;
+        // Taken out of the session first, so checking out twice at once can't charge twice
+        let cart = self.members.sessions.update_cart(sid, std::mem::take)?;
+        let res = self.charge_cart(user, cart.clone(), form);
+        if res.is_err() {
+            // Put back in front of anything added since
+            self.members.sessions.update_cart(sid, |current| {
+                let added: Vec<_> = current.iter().filter(|w| !cart.contains(w)).collect();
+                *current = cart.iter().chain(added).copied().collect();
+            })?;
+        }
+        res
+    }
+
+    fn charge_cart(
+        &self,
+        user: Uuid,
+        cart: Vec<Uuid>,
+        form: params::CheckoutParams,
+    ) -> Result<Uuid> {
+        let items = self.cart_items(user, cart)?;
+        if items.is_empty() {
+            bail!("Your cart is empty");
+        }
+        let works: Vec<_> = items.iter().map(|i| i.work).collect();
+        let Some(payments) = &self.payments else {
+            bail!("The shop doesn't take payments yet");
+        };
+        let currency = &self.config.shop.currency;
+        let id = self.orders.create(user, items, currency, payments.name())?;
+        let order = self.orders.get(id).expect("the order was just made");
+
+        let charge = Charge {
+            order: id,
+            amount: order.total(),
+            currency,
+            details: &form.card,
+        };
+        match payments.charge(&charge) {
+            Ok(Payment::Paid { reference }) => {
+                // The user has paid, so nothing from here on fails the checkout. The ledger is
+                // written first, as works missing from the library are added back from it
+                if let Err(e) =
+                    self.orders
+                        .settle(id, OrderStatus::Paid, Some(reference.clone()), None)
+                {
+                    tracing::error!("Marking order {id} as paid ({reference}) failed: {e:#}");
+                }
+                if let Err(e) = self
+                    .members
+                    .update_library(user, |lib| add_works(lib, &works))
+                {
+                    tracing::error!(
+                        "Adding the works of paid order {id} to the library failed: {e:#}"
+                    );
+                }
+                for &work in &works {
+                    self.stats.record(Metric::Purchases, work, None, 1);
+                }
+                Ok(id)
+            }
+            Ok(Payment::Declined { reason }) => {
+                self.orders
+                    .settle(id, OrderStatus::Failed, None, Some(reason.clone()))?;
+                bail!(reason)
+            }
+            Err(e) => {
+                tracing::error!("Payment for order {id} failed: {e:#}");
+                let reason = "The payment couldn't be made. You weren't charged".to_string();
+                self.orders
+                    .settle(id, OrderStatus::Failed, None, Some(reason.clone()))?;
+                bail!(reason)
+            }
+        }
+    }
+
+    /// Adds the works of paid orders that are missing from the user's library, e.g. because it
+    /// couldn't be written right after the payment
+    pub fn reconcile_purchases(&self, user: Uuid) -> Result<()> {
+        let paid: Vec<Uuid> = self
+            .orders
+            .for_user(user)
+            .into_iter()
+            .filter(|(_, order)| order.status == OrderStatus::Paid)
+            .flat_map(|(_, order)| order.items.into_iter().map(|i| i.work))
+            .collect();
+        let owned = self.members.get_library(user)?.works;
+        if paid.iter().all(|work| owned.contains(work)) {
+            return Ok(());
+        }
+        self.members
+            .update_library(user, |lib| add_works(lib, &paid))
+    }
+
+    pub fn orders_page(&self, user: Uuid) -> Result<String> {
+        let orders = Value::from_iter(
+            self.orders
+                .for_user(user)
+                .into_iter()
+                .map(|(id, order)| order_context(id, &order)),
+        );
+        let template = self.env.get_template("orders.jinja")?;
+        let render = template.render(context! { orders })?;
+        Ok(render)
+    }
+
+    pub fn order_page(&self, user: Uuid, id: Uuid) -> Result<String> {
+        let Some(order) = self.orders.get(id).filter(|o| o.user == user) else {
+            bail!("That order doesn't exist");
+        };
+        let template = self.env.get_template("order.jinja")?;
+        let render = template.render(context! { order => order_context(id, &order) })?;
+        Ok(render)
+    }
+}
+
+fn add_works(lib: &mut UserLibrary, works: &[Uuid]) {
+    for work in works {
+        if !lib.works.contains(work) {
+            lib.works.push(*work);
+        }
+    }
+}
+
+fn order_context(id: Uuid, order: &Order) -> Value {
+    let items = Value::from_iter(order.items.iter().map(|i| {
+        context! {
+            url => work_path(&i.title, i.work),
+            title => i.title,
+            price => format_price(i.price.into(), &order.currency),
+        }
+    }));
+    context! {
+        id => b64_encode_uuid(id.as_bytes()),
+        date => time(order.created).format("%Y-%m-%d %H:%M UTC").to_string(),
+        status => order.status,
+        total => format_price(order.total(), &order.currency),
+        reference => order.reference,
+        failure => order.failure,
+        items,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::{
+        config::{Config, PaymentConfig},
+        entry::LiteraryWork,
+        payment::DECLINED_CARD,
+        user::{Role, UserRef},
+    };
+
+    struct Shop {
+        app: Application<sled::Db>,
+        buyer: Uuid,
+        sid: Uuid,
+        work: Uuid,
+    }
+
+    fn shop(payments: Option<PaymentConfig>) -> Shop {
+        let mut config = Config::default();
+        config.shop.payments = payments;
+        let app = Application::temporary(config);
+        let user = |name: &str, role| {
+            app.members
+                .create_user(name.into(), "correct horse battery".into(), role)
+                .unwrap()
+        };
+        let (author, buyer) = (user("author", Role::Author), user("buyer", Role::Reader));
+        let mut work = LiteraryWork::new("Paid".into(), String::new());
+        work.creators.push(UserRef {
+            id: Some(author),
+            name: "author".into(),
+            created: SystemTime::now(),
+        });
+        work.price = Some(300);
+        let work = app.lib.add_work(work).unwrap();
+        let sid = app.members.sessions.create(buyer, None).unwrap();
+        let params = params::LiteraryWorkParams {
+            title: "Paid".into(),
+            id: work,
+        };
+        app.add_to_cart(buyer, sid, &params).unwrap();
+        Shop {
+            app,
+            buyer,
+            sid,
+            work,
+        }
+    }
+
+    fn checkout(shop: &Shop, card: &str) -> Result<Uuid> {
+        let form = params::CheckoutParams { card: card.into() };
+        shop.app.checkout(shop.buyer, shop.sid, form)
+    }
+
+    fn owned(shop: &Shop) -> bool {
+        let lib = shop.app.members.get_library(shop.buyer).unwrap();
+        lib.works.contains(&shop.work)
+    }
+
+    #[test]
+    fn checkout_needs_a_provider() {
+        let shop = shop(None);
+        assert!(checkout(&shop, "4242424242424242").is_err());
+        assert_eq!(shop.app.members.sessions.cart(shop.sid), [shop.work]);
+        assert!(shop.app.orders.for_user(shop.buyer).is_empty());
+    }
+
+    #[test]
+    fn declined_payments_keep_the_cart() {
+        let shop = shop(Some(PaymentConfig::Fake));
+        assert!(checkout(&shop, DECLINED_CARD).is_err());
+        assert_eq!(shop.app.members.sessions.cart(shop.sid), [shop.work]);
+        assert!(!owned(&shop));
+        let orders = shop.app.orders.for_user(shop.buyer);
+        assert_eq!(orders[0].1.status, OrderStatus::Failed);
+
+        let order = checkout(&shop, "4242 4242 4242 4242").unwrap();
+        assert!(owned(&shop));
+        assert!(shop.app.members.sessions.cart(shop.sid).is_empty());
+        let orders = shop.app.orders.for_user(shop.buyer);
+        assert_eq!(orders.len(), 2);
+        // Orders made in the same millisecond aren't ordered, so look it up by id
+        let paid = orders.iter().find(|(id, _)| *id == order).unwrap();
+        assert_eq!(paid.1.status, OrderStatus::Paid);
+    }
+
+    #[test]
+    fn paid_works_are_reconciled_from_the_ledger() {
+        let shop = shop(Some(PaymentConfig::Fake));
+        let item = OrderItem {
+            work: shop.work,
+            title: "Paid".into(),
+            price: 300,
+        };
+        let app = &shop.app;
+        let order = app
+            .orders
+            .create(shop.buyer, vec![item], "USD", "fake")
+            .unwrap();
+        app.reconcile_purchases(shop.buyer).unwrap();
+        // Pending orders don't count
+        assert!(!owned(&shop));
+        app.orders
+            .settle(order, OrderStatus::Paid, Some("ref".into()), None)
+            .unwrap();
+        app.reconcile_purchases(shop.buyer).unwrap();
+        assert!(owned(&shop));
+
+        // Nothing is left to buy, so nobody is charged twice
+        assert!(checkout(&shop, "4242424242424242").is_err());
+        assert_eq!(app.orders.for_user(shop.buyer).len(), 1);
+    }
+}
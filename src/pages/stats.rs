@@ -0,0 +1,85 @@
+//! Statistics of a work for its creators
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    application::Application,
+    db::Backend,
+    params,
+    stats::{self, Granularity, Metric},
+    utils::work_path,
+};
+
+impl<B: Backend> Application<B> {
+    /// Each metric of the work and its chapters per period, most recent first
+    pub fn work_stats_page(
+        &self,
+        params: params::LiteraryWorkParams,
+        user: Uuid,
+        query: params::StatsQuery,
+    ) -> Result<String> {
+        let work = self.editable_work(&params, user)?;
+        let chapters: Vec<_> = work.chapters.iter().map(|c| c.id).collect();
+        let to = stats::today();
+        // About as far back as the periods are kept by default
+        let from = to.saturating_sub(match query.by {
+            Granularity::Day => 30,
+            Granularity::Week => 26 * 7,
+            Granularity::Month => 2 * 365,
+        });
+
+        let mut periods: BTreeMap<u32, [u64; Metric::ALL.len()]> = BTreeMap::new();
+        let mut totals = [0; Metric::ALL.len()];
+        for (i, metric) in Metric::ALL.into_iter().enumerate() {
+            let series = self.stats.work_series(metric, params.id, &chapters);
+            totals[i] = series.total();
+            if metric.daily_only() && query.by != Granularity::Day {
+                continue;
+            }
+            for (day, n) in series.range(query.by, from, to) {
+                periods.entry(day).or_default()[i] = n;
+            }
+        }
+        let periods = Value::from_iter(periods.into_iter().rev().map(|(day, counts)| {
+            context! { start => date(day), counts }
+        }));
+
+        // Only views and comments are kept per chapter
+        let chapter_total = |metric, chapter| {
+            self.stats
+                .series(metric, params.id, Some(chapter))
+                .map_or(0, |s| s.total())
+        };
+        let chapters = Value::from_iter(work.chapters.iter().map(|c| {
+            context! {
+                title => c.title,
+                views => chapter_total(Metric::Views, c.id),
+                comments => chapter_total(Metric::Comments, c.id),
+            }
+        }));
+
+        let template = self.env.get_template("work_stats.jinja")?;
+        let render = template.render(context! {
+            url => work_path(&work.title, params.id),
+            title => work.title,
+            by => query.by,
+            metrics => Value::from_iter(Metric::ALL.map(Metric::name)),
+            daily_only => Metric::ALL.map(Metric::daily_only),
+            totals,
+            periods,
+            chapters,
+        })?;
+        Ok(render)
+    }
+}
+
+fn date(day: u32) -> String {
+    NaiveDate::from_num_days_from_ce_opt(day as i32)
+        .map(|d| d.to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,62 @@
+//! Managing personal API tokens
+
+use anyhow::{bail, Result};
+use minijinja::{context, Value};
+use uuid::Uuid;
+
+use crate::{
+    application::Application, db::Backend, export::time, params, token::Scope, user::Capability,
+    utils::b64_encode_uuid,
+};
+
+impl<B: Backend> Application<B> {
+    /// The user's tokens. `created` is a token just made, shown this once
+    pub fn tokens_page(
+        &self,
+        user: Uuid,
+        created: Option<&str>,
+        error: Option<&str>,
+    ) -> Result<String> {
+        let format = |t| time(t).format("%Y-%m-%d %H:%M UTC").to_string();
+        let tokens = Value::from_iter(self.members.tokens.for_user(user).into_iter().map(|t| {
+            context! {
+                id => b64_encode_uuid(t.id.as_bytes()),
+                name => t.name,
+                scopes => Value::from_iter(t.scopes.iter().map(|s| s.description())),
+                created => format(t.created),
+                last_used => t.last_used.map(format),
+            }
+        }));
+        let can_write = self.members.get_role(user)?.can(Capability::WriteWorks);
+        let template = self.env.get_template("tokens.jinja")?;
+        let render = template.render(context! {
+            tokens,
+            can_write,
+            created,
+            error,
+        })?;
+        Ok(render)
+    }
+
+    /// Makes a token with the ticked scopes, returning it
+    pub fn create_token(&self, user: Uuid, form: params::TokenFormParams) -> Result<String> {
+        let scopes: Vec<_> = [
+            (Scope::ReadLibrary, form.read_library),
+            (Scope::WriteProgress, form.write_progress),
+            (Scope::AuthorContent, form.author_content),
+        ]
+        .into_iter()
+        .filter(|(_, ticked)| ticked.is_some())
+        .map(|(scope, _)| scope)
+        .collect();
+        let can_write = self.members.get_role(user)?.can(Capability::WriteWorks);
+        if scopes.contains(&Scope::AuthorContent) && !can_write {
+            bail!("Your role can't write works, so neither can your tokens");
+        }
+        self.members.tokens.create(user, &form.name, scopes)
+    }
+
+    pub fn revoke_token(&self, user: Uuid, id: Uuid) -> Result<()> {
+        self.members.tokens.revoke(user, id)
+    }
+}
@@ -0,0 +1,94 @@
+//! Setting up two-factor login
+
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use minijinja::context;
+use uuid::Uuid;
+
+use crate::{
+    application::Application,
+    db::Backend,
+    export::time,
+    params,
+    two_factor::{self, TwoFactor},
+};
+
+impl<B: Backend> Application<B> {
+    /// Shows a new secret to scan while two-factor login is off. `recovery_codes` were just made
+    /// and are shown this once
+    pub fn two_factor_page(
+        &self,
+        user: Uuid,
+        recovery_codes: Option<Vec<String>>,
+        error: Option<&str>,
+    ) -> Result<String> {
+        let data = self.members.get_user(user)?;
+        let required = self.members.requires_two_factor(data.role);
+        let template = self.env.get_template("two_factor.jinja")?;
+        let render = match data.two_factor {
+            Some(two_factor) => template.render(context! {
+                enabled => time(two_factor.enabled).format("%Y-%m-%d").to_string(),
+                recovery_left => two_factor.recovery_left(),
+                recovery_codes,
+                required,
+                error,
+            })?,
+            None => {
+                let secret = two_factor::new_secret();
+                let uri = two_factor::otpauth_uri(&secret, &data.name);
+                template.render(context! {
+                    qr_code => two_factor::qr_code(&uri)?,
+                    uri,
+                    secret,
+                    required,
+                    error,
+                })?
+            }
+        };
+        Ok(render)
+    }
+
+    /// Turns on two-factor login once the user proved their app has the secret. Returns the
+    /// recovery codes
+    pub fn enable_two_factor(
+        &self,
+        user: Uuid,
+        form: params::EnableTwoFactorParams,
+    ) -> Result<Vec<String>> {
+        if self.members.get_two_factor(user)?.is_some() {
+            bail!("Two-factor login is already on");
+        }
+        let Some((two_factor, codes)) = TwoFactor::enable(&form.secret, &form.code) else {
+            bail!("Wrong code. Check the time on your device and try again");
+        };
+        self.members.set_two_factor(user, Some(two_factor))?;
+        Ok(codes)
+    }
+
+    pub fn disable_two_factor(
+        &self,
+        user: Uuid,
+        form: params::DisableTwoFactorParams,
+    ) -> Result<()> {
+        if self
+            .members
+            .requires_two_factor(self.members.get_role(user)?)
+        {
+            bail!("Your role requires two-factor login");
+        }
+        self.members.check_password(user, &form.pswd)?;
+        let Some(mut two_factor) = self.members.get_two_factor(user)? else {
+            bail!("Two-factor login isn't on");
+        };
+        if !two_factor.verify(&form.code, SystemTime::now()) {
+            bail!("Wrong code");
+        }
+        self.members.set_two_factor(user, None)
+    }
+
+    pub fn new_recovery_codes(&self, user: Uuid, pswd: &str) -> Result<Vec<String>> {
+        self.members.check_password(user, pswd)?;
+        self.members.new_recovery_codes(user)
+    }
+}
@@ -1,7 +1,27 @@
 use serde::Deserialize;
 use uuid::Uuid;
 
-use crate::utils::b64_decode_uuid;
+use crate::{
+    annotation::AnnotationKind,
+    entry::{ChapterAccess, WritingMode},
+    library::WorkSort,
+    review::ReviewSort,
+    stats::Granularity,
+    user::Role,
+    utils::b64_decode_uuid,
+};
+
+#[derive(Deserialize)]
+pub struct HomeQuery {
+    #[serde(default)]
+    pub sort: WorkSort,
+    /// Only list works that take at least this long to read
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    pub min_minutes: Option<usize>,
+    /// Only list works that can be read within this many minutes
+    #[serde(default, deserialize_with = "deserialize_empty_as_none")]
+    pub max_minutes: Option<usize>,
+}
 
 #[derive(Deserialize)]
 pub struct LiteraryWorkParams {
@@ -10,6 +30,29 @@ pub struct LiteraryWorkParams {
     pub id: Uuid,
 }
 
+#[derive(Deserialize)]
+pub struct ReviewQuery {
+    #[serde(default)]
+    pub reviews: ReviewSort,
+}
+
+#[derive(Deserialize)]
+pub struct ReviewParams {
+    pub stars: u8,
+    #[serde(default)]
+    pub text: String,
+    /// Checkbox, only sent when ticked
+    pub spoiler: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReviewerParams {
+    #[serde(flatten)]
+    pub work_params: LiteraryWorkParams,
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub reviewer: Uuid,
+}
+
 #[derive(Deserialize)]
 pub struct ChapterParams {
     #[serde(flatten)]
@@ -17,6 +60,65 @@ pub struct ChapterParams {
     pub chapter_id: usize,
 }
 
+#[derive(Deserialize)]
+pub struct PositionParams {
+    /// How far into the chapter the reader scrolled, from 0 to 1
+    pub position: f32,
+}
+
+#[derive(Deserialize)]
+pub struct ChapterReadParams {
+    pub read: bool,
+}
+
+#[derive(Deserialize)]
+pub struct AnnotationParams {
+    pub kind: AnnotationKind,
+    /// Index of the element in the chapter
+    pub paragraph: usize,
+    /// Characters into the paragraph, not counting ruby readings
+    #[serde(default)]
+    pub start: usize,
+    #[serde(default)]
+    pub end: usize,
+    #[serde(default)]
+    pub note: String,
+}
+
+#[derive(Deserialize)]
+pub struct AnnotationIdParams {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct AnnotationNoteParams {
+    pub note: String,
+}
+
+#[derive(Deserialize)]
+pub struct CommentIdParams {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct NotificationIdParams {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct CommentParams {
+    pub text: String,
+}
+
+#[derive(Deserialize)]
+pub struct WritingModeQuery {
+    /// Overrides the writing mode for this request only
+    pub mode: Option<WritingMode>,
+}
+
 fn deserialize_uuid<'de, D>(deserializer: D) -> Result<Uuid, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -30,8 +132,250 @@ where
     }
 }
 
+/// Empty form fields are treated as missing
+fn deserialize_empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    if s.is_empty() {
+        Ok(None)
+    } else {
+        s.parse().map(Some).map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Deserialize)]
 pub struct CreateUserParams {
     pub name: String,
     pub pswd: String,
 }
+
+#[derive(Deserialize)]
+pub struct RenameParams {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReaderSettingsParams {
+    /// Empty follows the default of each work
+    #[serde(deserialize_with = "deserialize_opt_writing_mode")]
+    pub writing_mode: Option<WritingMode>,
+}
+
+fn deserialize_opt_writing_mode<'de, D>(deserializer: D) -> Result<Option<WritingMode>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.as_str() {
+        "" => Ok(None),
+        "horizontal" => Ok(Some(WritingMode::Horizontal)),
+        "vertical" => Ok(Some(WritingMode::Vertical)),
+        _ => Err(serde::de::Error::custom("Invalid writing mode")),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WorkFormParams {
+    pub title: String,
+    pub description: String,
+    /// Comma separated
+    pub genres: String,
+    /// Comma separated
+    pub tags: String,
+    pub writing_mode: WritingMode,
+    /// Empty for a free work
+    #[serde(default)]
+    pub price: String,
+    /// Last day of a free campaign, as `YYYY-MM-DD`. Empty for none
+    #[serde(default)]
+    pub free_until: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChapterAction {
+    /// Render the text without saving
+    Preview,
+    Save,
+}
+
+#[derive(Deserialize)]
+pub struct ChapterFormParams {
+    pub title: String,
+    pub text: String,
+    #[serde(default)]
+    pub access: ChapterAccess,
+    pub action: ChapterAction,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MoveDirection {
+    Up,
+    Down,
+}
+
+#[derive(Deserialize)]
+pub struct MoveChapterParams {
+    pub chapter_id: usize,
+    pub direction: MoveDirection,
+}
+
+#[derive(Deserialize)]
+pub struct ShelfParams {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub shelf: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct ShelfWorkParams {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub shelf: Uuid,
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub work: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct PublicShelfParams {
+    pub name: String,
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub shelf: Uuid,
+}
+
+/// Picks a shelf from the work's page
+#[derive(Deserialize)]
+pub struct ShelveParams {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub shelf: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct ShelfFormParams {
+    pub name: String,
+    /// Checkbox
+    pub public: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ShelfNameParams {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct ShelfVisibilityParams {
+    pub public: bool,
+}
+
+#[derive(Deserialize)]
+pub struct MoveShelfWorkParams {
+    pub direction: MoveDirection,
+}
+
+/// What happens to works only the deleted user created
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkDisposal {
+    Trash,
+    /// Given to the user called `heir`
+    Transfer,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountParams {
+    pub pswd: String,
+    pub works: WorkDisposal,
+    #[serde(default)]
+    pub heir: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleParams {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub user: Uuid,
+    pub role: Role,
+}
+
+#[derive(Deserialize)]
+pub struct TokenFormParams {
+    pub name: String,
+    /// Checkboxes, one per scope
+    pub read_library: Option<String>,
+    pub write_progress: Option<String>,
+    pub author_content: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenIdParams {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct LoginCodeParams {
+    pub challenge: String,
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct EnableTwoFactorParams {
+    /// Base32 secret the setup page made
+    pub secret: String,
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct DisableTwoFactorParams {
+    pub pswd: String,
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordParams {
+    pub pswd: String,
+}
+
+#[derive(Deserialize)]
+pub struct SignupParams {
+    pub name: String,
+    pub pswd: String,
+    /// Optional unless signups have to be confirmed
+    #[serde(default)]
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct EmailParams {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct MailLinkParams {
+    pub secret: String,
+}
+
+#[derive(Deserialize)]
+pub struct CartWorkParams {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub work: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct CheckoutParams {
+    pub card: String,
+}
+
+#[derive(Deserialize)]
+pub struct OrderParams {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    #[serde(default)]
+    pub by: Granularity,
+}
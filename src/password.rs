@@ -0,0 +1,110 @@
+//! Password hashing with argon2id
+
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordHasher as _,
+    PasswordVerifier, Version,
+};
+use subtle::ConstantTimeEq;
+
+use crate::config::PasswordConfig;
+
+/// Outcome of checking a password against the stored value
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// The password is correct, but the stored value is plaintext or uses weaker settings than the
+    /// current ones and should be replaced
+    ValidNeedsRehash,
+}
+
+pub struct PasswordHasher {
+    argon2: Argon2<'static>,
+}
+
+impl PasswordHasher {
+    pub fn new(config: &PasswordConfig) -> Result<Self> {
+        let params = Params::new(
+            config.memory_cost,
+            config.time_cost,
+            config.parallelism,
+            None,
+        )
+        .map_err(|e| anyhow!("Invalid password hashing parameters: {e}"))?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        Ok(Self { argon2 })
+    }
+
+    /// Returns the PHC string of a salted hash
+    pub fn hash(&self, pswd: &str) -> Result<String> {
+        let salt = SaltString::generate(&mut rand::rngs::OsRng);
+        let hash = self
+            .argon2
+            .hash_password(pswd.as_bytes(), &salt)
+            .map_err(|e| anyhow!("Could not hash password: {e}"))?;
+        Ok(hash.to_string())
+    }
+
+    pub fn verify(&self, stored: &str, pswd: &str) -> Verification {
+        let Ok(hash) = PasswordHash::new(stored) else {
+            // Accounts from before hashing store the password as is
+            return if bool::from(stored.as_bytes().ct_eq(pswd.as_bytes())) {
+                Verification::ValidNeedsRehash
+            } else {
+                Verification::Invalid
+            };
+        };
+
+        // The parameters stored in the hash are used to verify it
+        if self.argon2.verify_password(pswd.as_bytes(), &hash).is_err() {
+            return Verification::Invalid;
+        }
+
+        let current = self.argon2.params();
+        let weaker = hash.algorithm.as_str() != Algorithm::Argon2id.as_str()
+            || Params::try_from(&hash).map_or(true, |p| {
+                p.m_cost() < current.m_cost()
+                    || p.t_cost() < current.t_cost()
+                    || p.p_cost() < current.p_cost()
+            });
+        if weaker {
+            Verification::ValidNeedsRehash
+        } else {
+            Verification::Valid
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hasher(memory_cost: u32) -> PasswordHasher {
+        PasswordHasher::new(&PasswordConfig {
+            memory_cost,
+            time_cost: 1,
+            parallelism: 1,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn rehash() {
+        let weak = hasher(8);
+        let strong = hasher(64);
+        let hash = weak.hash("hunter2").unwrap();
+
+        assert_eq!(weak.verify(&hash, "hunter2"), Verification::Valid);
+        assert_eq!(weak.verify(&hash, "hunter3"), Verification::Invalid);
+        assert_eq!(
+            strong.verify(&hash, "hunter2"),
+            Verification::ValidNeedsRehash
+        );
+        assert_eq!(
+            strong.verify("hunter2", "hunter2"),
+            Verification::ValidNeedsRehash
+        );
+        assert_eq!(strong.verify("hunter2", "hunter3"), Verification::Invalid);
+    }
+}
@@ -0,0 +1,92 @@
+//! Turns the text stored in an `Entry` into HTML for the chapter templates and EPUB export
+
+use std::fmt::Write;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use minijinja::HtmlEscape;
+
+use crate::{
+    entry::WritingMode,
+    ruby::{segments, Segment},
+    utils::image_mime,
+};
+
+/// Longest run of half-width Latin letters/digits that is set upright in vertical text.
+/// Anything longer is left to rotate sideways like it would in print
+const TCY_MAX_LEN: usize = 2;
+
+/// Escapes a paragraph and applies the markup needed for ruby and `mode`. The output is also
+/// valid XHTML
+pub fn paragraph(text: &str, mode: WritingMode) -> String {
+    let mut res = String::with_capacity(text.len());
+    for seg in segments(text) {
+        match (seg, mode) {
+            (Segment::Text(t), WritingMode::Horizontal) => {
+                write!(res, "{}", HtmlEscape(t)).unwrap()
+            }
+            (Segment::Text(t), WritingMode::Vertical) => res.push_str(&tate_chu_yoko(t)),
+            (Segment::Ruby { base, reading }, _) => write!(
+                res,
+                "<ruby>{}<rp>(</rp><rt>{}</rt><rp>)</rp></ruby>",
+                HtmlEscape(base),
+                HtmlEscape(reading)
+            )
+            .unwrap(),
+        }
+    }
+    res
+}
+
+/// Inlines an image as a data URI. Unknown formats render as nothing
+pub fn image(data: &[u8]) -> String {
+    match image_mime(data) {
+        Some(mime) => format!(
+            r#"<img src="data:{mime};base64,{}" alt="">"#,
+            STANDARD.encode(data)
+        ),
+        None => String::new(),
+    }
+}
+
+/// Wraps short runs of half-width alphanumerics in a `tcy` span so they are set horizontally
+/// within a vertical line (縦中横), e.g. the "12" in "12月"
+fn tate_chu_yoko(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_alphanumeric()) {
+        let (before, run) = rest.split_at(start);
+        let end = run
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(run.len());
+        let (run, after) = run.split_at(end);
+
+        write!(res, "{}", HtmlEscape(before)).unwrap();
+        if run.len() <= TCY_MAX_LEN {
+            write!(res, r#"<span class="tcy">{}</span>"#, HtmlEscape(run)).unwrap();
+        } else {
+            write!(res, "{}", HtmlEscape(run)).unwrap();
+        }
+        rest = after;
+    }
+    write!(res, "{}", HtmlEscape(rest)).unwrap();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcy_short_runs() {
+        assert_eq!(
+            tate_chu_yoko("12月3日"),
+            r#"<span class="tcy">12</span>月<span class="tcy">3</span>日"#
+        );
+        // Long runs are left alone
+        assert_eq!(tate_chu_yoko("西暦2024年"), "西暦2024年");
+        assert_eq!(
+            tate_chu_yoko("<b>"),
+            r#"&lt;<span class="tcy">b</span>&gt;"#
+        );
+    }
+}
@@ -0,0 +1,328 @@
+//! Star ratings and written reviews, one per user per work
+
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use bincode::{Decode, Encode};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    db::{index_key, Backend, Table},
+    utils::{decode_bincode, encode_bincode},
+};
+
+/// Keys of [`index_key`] of the work and the user
+const REVIEW_TABLE: &str = "REVIEWS";
+/// Keys of [`index_key`] of the user and the work, to find a user's reviews
+const USER_REVIEW_TABLE: &str = "USER_REVIEWS";
+pub const REVIEW_MAX_LEN: usize = 10_000;
+
+#[derive(Clone, Encode, Decode)]
+pub struct Review {
+    #[bincode(with_serde)]
+    pub user: Uuid,
+    /// From 1 to 5
+    pub stars: u8,
+    /// Empty for a rating without a review
+    pub text: String,
+    /// The text gives away the plot and is hidden until clicked
+    pub spoiler: bool,
+    pub created: SystemTime,
+    pub edited: Option<SystemTime>,
+    /// Users who found the review helpful
+    #[bincode(with_serde)]
+    pub helpful: Vec<Uuid>,
+}
+
+/// Order of the reviews on a work's page
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReviewSort {
+    /// Most helpful votes first
+    #[default]
+    Helpful,
+    Newest,
+}
+
+pub struct ReviewStore<B: Backend> {
+    reviews: <B as Backend>::OutTable,
+    by_user: <B as Backend>::OutTable,
+}
+
+impl<B: Backend> ReviewStore<B> {
+    pub fn new(db: &B) -> Result<Self> {
+        let reviews = db.get_table(REVIEW_TABLE)?;
+        let by_user = db.get_table(USER_REVIEW_TABLE)?;
+        Ok(Self { reviews, by_user })
+    }
+
+    pub fn get(&self, work: Uuid, user: Uuid) -> Option<Review> {
+        let data = self.reviews.get_value(index_key(work, user))?;
+        decode_bincode(data.as_ref()).ok()
+    }
+
+    /// Adds or replaces the user's review, returning the stars of the one it replaced
+    pub fn save(
+        &self,
+        work: Uuid,
+        user: Uuid,
+        stars: u8,
+        text: String,
+        spoiler: bool,
+    ) -> Result<Option<u8>> {
+        if !(1..=5).contains(&stars) {
+            bail!("Ratings are from 1 to 5 stars");
+        }
+        let text = text.trim().to_string();
+        if text.chars().count() > REVIEW_MAX_LEN {
+            bail!("Reviews can't be longer than {REVIEW_MAX_LEN} characters");
+        }
+
+        let old = self.update(work, user, |old| {
+            Ok(Some(match old {
+                Some(old) => Review {
+                    stars,
+                    // Votes were for the old text
+                    helpful: if old.text == text {
+                        old.helpful
+                    } else {
+                        vec![]
+                    },
+                    text: text.clone(),
+                    spoiler,
+                    edited: Some(SystemTime::now()),
+                    ..old
+                },
+                None => Review {
+                    user,
+                    stars,
+                    text: text.clone(),
+                    spoiler,
+                    created: SystemTime::now(),
+                    edited: None,
+                    helpful: vec![],
+                },
+            }))
+        })?;
+        Ok(old.map(|r| r.stars))
+    }
+
+    /// Removes the user's review, returning it
+    pub fn remove(&self, work: Uuid, user: Uuid) -> Result<Option<Review>> {
+        self.update(work, user, |_| Ok(None))
+    }
+
+    /// Adds or takes back `voter`'s helpful vote. Nobody can vote for their own review
+    pub fn toggle_helpful(&self, work: Uuid, reviewer: Uuid, voter: Uuid) -> Result<()> {
+        if reviewer == voter {
+            bail!("You can't vote for your own review");
+        }
+        self.update(work, reviewer, |review| {
+            let Some(mut review) = review else {
+                bail!("That review doesn't exist");
+            };
+            match review.helpful.iter().position(|&v| v == voter) {
+                Some(i) => {
+                    review.helpful.swap_remove(i);
+                }
+                None => review.helpful.push(voter),
+            }
+            Ok(Some(review))
+        })?;
+        Ok(())
+    }
+
+    /// Every review by `user`, with the work it is of
+    pub fn for_user(&self, user: Uuid) -> Vec<(Uuid, Review)> {
+        self.by_user
+            .indexed(user)
+            .filter_map(|work| Some((work, self.get(work, user)?)))
+            .collect()
+    }
+
+    /// Takes back every helpful vote `user` gave
+    pub fn remove_votes(&self, user: Uuid) -> Result<()> {
+        let voted: Vec<_> = self
+            .reviews
+            .iter()
+            .filter_map(|(key, value)| {
+                let review: Review = decode_bincode(value.as_ref()).ok()?;
+                let work = Uuid::from_slice(&key.as_ref()[..16]).ok()?;
+                review
+                    .helpful
+                    .contains(&user)
+                    .then_some((work, review.user))
+            })
+            .collect();
+        for (work, reviewer) in voted {
+            self.update(work, reviewer, |review| {
+                Ok(review.map(|mut review| {
+                    review.helpful.retain(|&v| v != user);
+                    review
+                }))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Reviews of a work with text. Plain ratings are only counted in the work's statistics
+    pub fn for_work(&self, work: Uuid, sort: ReviewSort) -> Vec<Review> {
+        let mut res: Vec<Review> = self
+            .reviews
+            .scan_prefix(work.into_bytes())
+            .filter_map(|(_, value)| decode_bincode(value.as_ref()).ok())
+            .filter(|r: &Review| !r.text.is_empty())
+            .collect();
+        match sort {
+            ReviewSort::Helpful => res.sort_by_key(|r| {
+                (
+                    std::cmp::Reverse(r.helpful.len()),
+                    std::cmp::Reverse(r.created),
+                )
+            }),
+            ReviewSort::Newest => res.sort_by_key(|r| std::cmp::Reverse(r.created)),
+        }
+        res
+    }
+
+    /// Replaces the user's review of `work` with what `f` makes of it, returning the old one.
+    /// `f` runs again if someone else changed the review in the meantime
+    fn update(
+        &self,
+        work: Uuid,
+        user: Uuid,
+        mut f: impl FnMut(Option<Review>) -> Result<Option<Review>>,
+    ) -> Result<Option<Review>> {
+        let key = index_key(work, user);
+        loop {
+            let data = self.reviews.get_value(key);
+            let old = data.as_ref().map(|d| d.as_ref());
+            let review: Option<Review> = old.map(decode_bincode).transpose()?;
+            let existed = review.is_some();
+            let new = f(review.clone())?;
+            let new = new.as_ref().map(encode_bincode).transpose()?;
+            if self.reviews.compare_and_swap(key, old, new.as_deref()) {
+                match (existed, new.is_some()) {
+                    (false, true) => {
+                        self.by_user.insert(index_key(user, work), []);
+                    }
+                    (true, false) => {
+                        self.by_user.remove(index_key(user, work));
+                    }
+                    _ => {}
+                }
+                return Ok(review);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn store() -> ReviewStore<sled::Db> {
+        ReviewStore::new(&crate::db::temporary()).unwrap()
+    }
+
+    #[test]
+    fn save() {
+        let store = store();
+        let (work, user, voter) = (Uuid::now_v7(), Uuid::now_v7(), Uuid::now_v7());
+        assert!(store.save(work, user, 0, String::new(), false).is_err());
+        let long = "あ".repeat(REVIEW_MAX_LEN + 1);
+        assert!(store.save(work, user, 3, long, false).is_err());
+
+        assert_eq!(
+            store.save(work, user, 3, " Good ".into(), false).unwrap(),
+            None
+        );
+        store.toggle_helpful(work, user, voter).unwrap();
+        // Votes stay while the text does
+        assert_eq!(
+            store.save(work, user, 4, "Good".into(), true).unwrap(),
+            Some(3)
+        );
+        let review = store.get(work, user).unwrap();
+        assert_eq!((review.stars, review.spoiler), (4, true));
+        assert_eq!(review.helpful, [voter]);
+        assert!(review.edited.is_some());
+        store.save(work, user, 4, "Better".into(), true).unwrap();
+        assert!(store.get(work, user).unwrap().helpful.is_empty());
+
+        assert_eq!(store.for_user(user).len(), 1);
+        assert_eq!(store.remove(work, user).unwrap().unwrap().stars, 4);
+        assert!(store.remove(work, user).unwrap().is_none());
+        assert!(store.for_user(user).is_empty());
+    }
+
+    #[test]
+    fn votes() {
+        let store = store();
+        let (work, user, voter) = (Uuid::now_v7(), Uuid::now_v7(), Uuid::now_v7());
+        assert!(store.toggle_helpful(work, user, voter).is_err());
+        store.save(work, user, 5, "Great".into(), false).unwrap();
+        assert!(store.toggle_helpful(work, user, user).is_err());
+        store.toggle_helpful(work, user, voter).unwrap();
+        store.toggle_helpful(work, user, voter).unwrap();
+        assert!(store.get(work, user).unwrap().helpful.is_empty());
+
+        store.toggle_helpful(work, user, voter).unwrap();
+        store.remove_votes(voter).unwrap();
+        assert!(store.get(work, user).unwrap().helpful.is_empty());
+    }
+
+    #[test]
+    fn concurrent_votes_all_count() {
+        let store = Arc::new(store());
+        let (work, user) = (Uuid::now_v7(), Uuid::now_v7());
+        store.save(work, user, 5, "Great".into(), false).unwrap();
+        let voters: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    store.toggle_helpful(work, user, Uuid::now_v7()).unwrap();
+                })
+            })
+            .collect();
+        for voter in voters {
+            voter.join().unwrap();
+        }
+        assert_eq!(store.get(work, user).unwrap().helpful.len(), 8);
+    }
+
+    #[test]
+    fn sort() {
+        let store = store();
+        let work = Uuid::now_v7();
+        let users: Vec<_> = (0..3).map(|_| Uuid::now_v7()).collect();
+        store
+            .save(work, users[0], 5, "First".into(), false)
+            .unwrap();
+        store
+            .save(work, users[1], 4, "Second".into(), false)
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        store.save(work, users[2], 3, String::new(), false).unwrap();
+        store
+            .save(Uuid::now_v7(), users[2], 1, "Elsewhere".into(), false)
+            .unwrap();
+        store.toggle_helpful(work, users[0], users[2]).unwrap();
+
+        let texts = |sort| -> Vec<_> {
+            let reviews = store.for_work(work, sort);
+            reviews.into_iter().map(|r| r.text).collect()
+        };
+        // Plain ratings aren't listed
+        assert_eq!(texts(ReviewSort::Helpful), ["First", "Second"]);
+        store
+            .save(work, users[0], 5, "First again".into(), false)
+            .unwrap();
+        // Edits don't move a review up, and lose their votes
+        assert_eq!(texts(ReviewSort::Newest), ["Second", "First again"]);
+        assert_eq!(texts(ReviewSort::Helpful), ["Second", "First again"]);
+    }
+}
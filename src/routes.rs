@@ -1,14 +1,34 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{
+    marker::PhantomData,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use axum::{
-    extract::{Path, State},
+    extract::{ConnectInfo, DefaultBodyLimit, Multipart, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
     response::{Html, IntoResponse, Redirect, Response},
-    routing::get,
-    Form, Router,
+    routing::{get, post},
+    Form, Json, Router,
 };
-use axum_extra::extract::{cookie::Cookie, CookieJar};
+use uuid::Uuid;
 
-use crate::{application::Application, db::Backend, params, user};
+use crate::{
+    application::Application,
+    auth::{
+        caps::{ManageTrash, ManageUsers, Moderate, WriteWorks},
+        scopes::{ReadLibrary, WriteProgress},
+        Authorized, CurrentUser, Scoped,
+    },
+    cookies::SessionJar,
+    csrf,
+    db::Backend,
+    error::LibraryError,
+    page_header, params,
+    user::{self, Login},
+    utils::{b64_encode_uuid, url_encode_segment, work_path},
+};
 
 // So I don't have to type generics everytime
 pub struct AppRoutes<B: Backend> {
@@ -17,6 +37,9 @@ pub struct AppRoutes<B: Backend> {
 
 pub type App<B> = Arc<Application<B>>;
 
+/// Largest book file that can be uploaded
+const IMPORT_LIMIT: usize = 64 * 1024 * 1024;
+
 /*
     MVP Routes:
         - Home
@@ -39,9 +62,138 @@ impl<B: Backend + 'static> AppRoutes<B> {
             .route("/", get(Self::home))
             .route("/works/:title/:id/:chapter_id", get(Self::get_chapter))
             .route("/works/:title/:id", get(Self::get_work))
+            .route("/works/:title/:id/epub", get(Self::get_epub))
+            .route("/works/:title/:id/review", post(Self::review_work))
+            .route("/works/:title/:id/review/delete", post(Self::delete_review))
+            .route(
+                "/works/:title/:id/reviews/:reviewer/helpful",
+                post(Self::vote_helpful),
+            )
+            .route("/works/:title/:id/follow", post(Self::follow_work))
+            .route("/works/:title/:id/shelve", post(Self::shelve_work))
+            .route("/works/:title/:id/cart", post(Self::add_to_cart))
+            .route("/cart", get(Self::cart))
+            .route("/cart/:work/remove", post(Self::remove_from_cart))
+            .route("/cart/checkout", post(Self::checkout))
+            .route("/user/orders", get(Self::orders))
+            .route("/user/orders/:id", get(Self::order))
+            .route("/works/new", get(Self::new_work).post(Self::create_work))
+            .route(
+                "/works/:title/:id/edit",
+                get(Self::edit_work).post(Self::update_work),
+            )
+            .route("/works/:title/:id/move", post(Self::move_chapter))
+            .route("/works/:title/:id/stats", get(Self::work_stats))
+            .route(
+                "/works/:title/:id/new-chapter",
+                get(Self::new_chapter).post(Self::save_new_chapter),
+            )
+            .route(
+                "/works/:title/:id/:chapter_id/edit",
+                get(Self::edit_chapter).post(Self::save_chapter),
+            )
+            .route(
+                "/works/:title/:id/:chapter_id/position",
+                post(Self::save_position),
+            )
+            .route("/works/:title/:id/:chapter_id/read", post(Self::set_read))
+            .route(
+                "/works/:title/:id/:chapter_id/annotations",
+                post(Self::add_annotation),
+            )
+            .route(
+                "/works/:title/:id/:chapter_id/comments",
+                post(Self::post_comment),
+            )
+            .route(
+                "/works/:title/:id/:chapter_id/comments/lock",
+                post(Self::lock_comments),
+            )
+            .route("/comments/:id/reply", post(Self::reply_to_comment))
+            .route("/comments/:id/edit", post(Self::edit_comment))
+            .route("/comments/:id/delete", post(Self::delete_comment))
+            .route("/comments/:id/hide", post(Self::hide_comment))
+            .route("/comments/:id/ban", post(Self::ban_commenter))
             .route("/signup", get(Self::signup).post(Self::create_user))
             .route("/login", get(Self::login).post(Self::create_session))
+            .route("/login/code", post(Self::submit_login_code))
+            .route(
+                "/forgot-password",
+                get(Self::forgot_password).post(Self::request_password_reset),
+            )
+            .route(
+                "/reset-password/:secret",
+                get(Self::reset_password_page).post(Self::reset_password),
+            )
+            .route("/verify-email/:secret", get(Self::verify_email))
+            .route("/user/email", post(Self::set_email))
+            .route("/logout", post(Self::logout))
             .route("/user", get(Self::user_library))
+            .route("/user/settings", post(Self::update_settings))
+            .route("/user/rename", post(Self::rename))
+            .route("/authors/:name", get(Self::author))
+            .route("/authors/:name/follow", post(Self::follow_author))
+            .route("/authors/:name/shelves/:shelf", get(Self::shelf))
+            .route("/user/shelves", get(Self::shelves).post(Self::create_shelf))
+            .route("/user/shelves/:shelf/rename", post(Self::rename_shelf))
+            .route(
+                "/user/shelves/:shelf/visibility",
+                post(Self::set_shelf_visibility),
+            )
+            .route("/user/shelves/:shelf/delete", post(Self::delete_shelf))
+            .route(
+                "/user/shelves/:shelf/:work/remove",
+                post(Self::unshelve_work),
+            )
+            .route("/user/shelves/:shelf/:work/move", post(Self::move_on_shelf))
+            .route("/user/inbox", get(Self::inbox))
+            .route("/user/inbox/read-all", post(Self::mark_all_read))
+            .route("/user/inbox/:id/read", post(Self::open_notification))
+            .route("/works/:title/:id/trash", post(Self::trash_work))
+            .route("/admin/trash", get(Self::trash))
+            .route("/admin/trash/:title/:id/restore", post(Self::restore_work))
+            .route("/admin/users", get(Self::users))
+            .route("/admin/users/role", post(Self::set_role))
+            .route("/user/annotations", get(Self::annotations))
+            .route(
+                "/user/annotations/:id/note",
+                post(Self::set_annotation_note),
+            )
+            .route(
+                "/user/annotations/:id/delete",
+                post(Self::delete_annotation),
+            )
+            .route("/user/export", get(Self::export_account))
+            .route(
+                "/user/delete",
+                get(Self::delete_account_page).post(Self::delete_account),
+            )
+            .route("/user/sessions", get(Self::sessions))
+            .route("/user/two-factor", get(Self::two_factor))
+            .route("/user/two-factor/enable", post(Self::enable_two_factor))
+            .route("/user/two-factor/disable", post(Self::disable_two_factor))
+            .route(
+                "/user/two-factor/recovery-codes",
+                post(Self::new_recovery_codes),
+            )
+            .route("/user/tokens", get(Self::tokens).post(Self::create_token))
+            .route("/user/tokens/:id/revoke", post(Self::revoke_token))
+            .route("/api/library", get(Self::api_library))
+            .route("/user/sessions/revoke-all", post(Self::log_out_everywhere))
+            .route(
+                "/import",
+                get(Self::import_page)
+                    .post(Self::import)
+                    .layer(DefaultBodyLimit::max(IMPORT_LIMIT)),
+            )
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                page_header::load::<B>,
+            ))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                csrf::protect::<B>,
+            ))
             .with_state(state)
     }
 
@@ -52,15 +204,20 @@ impl<B: Backend + 'static> AppRoutes<B> {
             - Recently updated works
             - Announcements
     */
-    async fn home(State(state): State<App<B>>, jar: CookieJar) -> Html<String> {
-        Html(state.home().unwrap())
+    async fn home(
+        State(state): State<App<B>>,
+        Query(query): Query<params::HomeQuery>,
+    ) -> Html<String> {
+        Html(state.home(query).unwrap())
     }
 
     async fn get_work(
         Path(params): Path<params::LiteraryWorkParams>,
+        Query(query): Query<params::ReviewQuery>,
         State(state): State<App<B>>,
+        jar: SessionJar,
     ) -> Html<String> {
-        let Ok(work) = state.work(params) else {
+        let Ok(work) = state.work(params, query, jar.session_id()) else {
             return Html("Work not found".to_string());
         };
         Html(work)
@@ -68,54 +225,592 @@ impl<B: Backend + 'static> AppRoutes<B> {
 
     async fn get_chapter(
         Path(params): Path<params::ChapterParams>,
+        Query(query): Query<params::WritingModeQuery>,
         State(state): State<App<B>>,
+        jar: SessionJar,
     ) -> Html<String> {
         // TODO: Handle
-        let Ok(work) = state.chapter(params) else {
+        let Ok(work) = state.chapter(params, query, jar.session_id()) else {
             return Html("Work not found".to_string());
         };
         Html(work)
     }
 
-    async fn signup(State(state): State<App<B>>) -> Html<String> {
-        Html(state.signup().unwrap())
+    /// Sent by the chapter page while reading, so there is nothing to redirect to
+    async fn save_position(
+        Path(params): Path<params::ChapterParams>,
+        State(state): State<App<B>>,
+        Scoped { user, .. }: Scoped<WriteProgress>,
+        Form(input): Form<params::PositionParams>,
+    ) -> Response {
+        match state.save_position(user.id, params, input.position) {
+            Ok(()) => StatusCode::NO_CONTENT.into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    /// Marks a chapter read or unread
+    async fn set_read(
+        Path(params): Path<params::ChapterParams>,
+        State(state): State<App<B>>,
+        Scoped { user, .. }: Scoped<WriteProgress>,
+        Form(input): Form<params::ChapterReadParams>,
+    ) -> Response {
+        let url = work_path(&params.work_params.title, params.work_params.id);
+        match state.set_chapter_read(user.id, params, input.read) {
+            Ok(()) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn add_annotation(
+        Path(params): Path<params::ChapterParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::AnnotationParams>,
+    ) -> Response {
+        let url = format!(
+            "{}/{}",
+            work_path(&params.work_params.title, params.work_params.id),
+            params.chapter_id
+        );
+        match state.add_annotation(user.id, params, input) {
+            Ok(paragraph) => Redirect::to(&format!("{url}#p{paragraph}")).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn review_work(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::ReviewParams>,
+    ) -> Response {
+        let url = format!("{}#reviews", work_path(&params.title, params.id));
+        match state.review_work(user.id, params, input) {
+            Ok(()) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn delete_review(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        let url = format!("{}#reviews", work_path(&params.title, params.id));
+        match state.delete_review(user.id, params) {
+            Ok(()) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn vote_helpful(
+        Path(params): Path<params::ReviewerParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        let url = format!(
+            "{}#reviews",
+            work_path(&params.work_params.title, params.work_params.id)
+        );
+        match state.vote_helpful(user.id, params) {
+            Ok(()) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn post_comment(
+        Path(params): Path<params::ChapterParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::CommentParams>,
+    ) -> Response {
+        match state.post_comment(user.id, params, input) {
+            Ok(url) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn lock_comments(
+        Path(params): Path<params::ChapterParams>,
+        State(state): State<App<B>>,
+        _: Authorized<Moderate>,
+    ) -> Response {
+        let url = format!(
+            "{}/{}#comments",
+            work_path(&params.work_params.title, params.work_params.id),
+            params.chapter_id
+        );
+        match state.toggle_comment_lock(&params) {
+            Ok(()) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn reply_to_comment(
+        Path(params): Path<params::CommentIdParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::CommentParams>,
+    ) -> Response {
+        match state.reply_to_comment(user.id, params.id, input.text) {
+            Ok(url) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn edit_comment(
+        Path(params): Path<params::CommentIdParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::CommentParams>,
+    ) -> Response {
+        match state.edit_comment(user.id, params.id, input.text) {
+            Ok(url) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn delete_comment(
+        Path(params): Path<params::CommentIdParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        match state.delete_comment(user.id, params.id) {
+            Ok(url) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn hide_comment(
+        Path(params): Path<params::CommentIdParams>,
+        State(state): State<App<B>>,
+        _: Authorized<Moderate>,
+    ) -> Response {
+        match state.toggle_hidden(params.id) {
+            Ok(url) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn ban_commenter(
+        Path(params): Path<params::CommentIdParams>,
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<Moderate>,
+    ) -> Response {
+        match state.toggle_comment_ban(user.id, params.id) {
+            Ok(url) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn get_epub(
+        Path(params): Path<params::LiteraryWorkParams>,
+        Query(query): Query<params::WritingModeQuery>,
+        State(state): State<App<B>>,
+        jar: SessionJar,
+    ) -> Response {
+        let filename = format!("{}.epub", params.title);
+        match state.epub(params, query, jar.session_id()) {
+            Ok(data) => {
+                // Titles are rarely ASCII, so the name is only given in the extended form
+                let filename = url_encode_segment(&filename);
+                let disposition = format!("attachment; filename*=UTF-8''{filename}");
+                (
+                    [
+                        (header::CONTENT_TYPE, "application/epub+zip".to_string()),
+                        (header::CONTENT_DISPOSITION, disposition),
+                    ],
+                    data,
+                )
+                    .into_response()
+            }
+            Err(e) => match e.downcast::<LibraryError>() {
+                Ok(e) => e.into_response(),
+                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+            },
+        }
+    }
+
+    async fn new_work(State(state): State<App<B>>, _: Authorized<WriteWorks>) -> Response {
+        match state.new_work_page() {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn create_work(
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<WriteWorks>,
+        Form(input): Form<params::WorkFormParams>,
+    ) -> Response {
+        match state.create_work(user.id, input) {
+            Ok((id, title)) => {
+                Redirect::to(&format!("{}/edit", work_path(&title, id))).into_response()
+            }
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn edit_work(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<WriteWorks>,
+    ) -> Response {
+        match state.edit_work_page(params, user.id) {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn work_stats(
+        Path(params): Path<params::LiteraryWorkParams>,
+        Query(query): Query<params::StatsQuery>,
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<WriteWorks>,
+    ) -> Response {
+        match state.work_stats_page(params, user.id, query) {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn update_work(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<WriteWorks>,
+        Form(input): Form<params::WorkFormParams>,
+    ) -> Response {
+        let id = params.id;
+        match state.update_work_info(params, user.id, input) {
+            Ok(title) => Redirect::to(&format!("{}/edit", work_path(&title, id))).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn move_chapter(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<WriteWorks>,
+        Form(input): Form<params::MoveChapterParams>,
+    ) -> Response {
+        let url = format!("{}/edit", work_path(&params.title, params.id));
+        match state.move_chapter(params, user.id, input) {
+            Ok(()) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn new_chapter(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<WriteWorks>,
+    ) -> Response {
+        match state.chapter_editor(params, None, user.id, None) {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn save_new_chapter(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<WriteWorks>,
+        Form(input): Form<params::ChapterFormParams>,
+    ) -> Response {
+        Self::submit_chapter(state, params, None, user.id, input)
+    }
+
+    async fn edit_chapter(
+        Path(params): Path<params::ChapterParams>,
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<WriteWorks>,
+    ) -> Response {
+        let chapter_id = Some(params.chapter_id);
+        match state.chapter_editor(params.work_params, chapter_id, user.id, None) {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn save_chapter(
+        Path(params): Path<params::ChapterParams>,
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<WriteWorks>,
+        Form(input): Form<params::ChapterFormParams>,
+    ) -> Response {
+        let chapter_id = Some(params.chapter_id);
+        Self::submit_chapter(state, params.work_params, chapter_id, user.id, input)
+    }
+
+    /// Either previews or saves the chapter editor
+    fn submit_chapter(
+        state: App<B>,
+        params: params::LiteraryWorkParams,
+        chapter_id: Option<usize>,
+        user: Uuid,
+        input: params::ChapterFormParams,
+    ) -> Response {
+        let res = match input.action {
+            params::ChapterAction::Preview => state
+                .chapter_editor(params, chapter_id, user, Some(input))
+                .map(|page| Html(page).into_response()),
+            params::ChapterAction::Save => {
+                let url = format!("{}/edit", work_path(&params.title, params.id));
+                state
+                    .save_chapter(params, chapter_id, user, input)
+                    .map(|()| Redirect::to(&url).into_response())
+            }
+        };
+        res.unwrap_or_else(error_response)
+    }
+
+    async fn import_page(State(state): State<App<B>>, _: Authorized<WriteWorks>) -> Response {
+        Html(state.import_page().unwrap()).into_response()
+    }
+
+    async fn import(
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<WriteWorks>,
+        mut multipart: Multipart,
+    ) -> Response {
+        let mut file = None;
+        while let Ok(Some(field)) = multipart.next_field().await {
+            if field.name() == Some("file") {
+                let file_name = field.file_name().unwrap_or_default().to_string();
+                match field.bytes().await {
+                    Ok(data) => file = Some((file_name, data)),
+                    Err(e) => return e.into_response(),
+                }
+            }
+        }
+        let Some((file_name, data)) = file else {
+            return (StatusCode::BAD_REQUEST, "No file was uploaded").into_response();
+        };
+
+        // Unzipping and decoding a book takes a while
+        let app = state.clone();
+        let res =
+            tokio::task::spawn_blocking(move || app.import(Some(user.id), &file_name, &data)).await;
+        match res {
+            Ok(Ok((id, title))) => {
+                tracing::info!("User {} imported {title} ({id})", user.id);
+                Redirect::to(&work_path(&title, id)).into_response()
+            }
+            Ok(Err(e)) => (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response(),
+            Err(e) => error_response(e.into()),
+        }
+    }
+
+    async fn signup(State(state): State<App<B>>) -> Response {
+        match state.signup("", "", None) {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
     }
 
     async fn create_user(
         State(state): State<App<B>>,
-        jar: CookieJar,
-        Form(input): Form<params::CreateUserParams>,
-    ) -> (CookieJar, Redirect) {
-        let session = state
-            .members
-            .try_create_user(input.name, input.pswd)
-            .unwrap();
-        (
-            jar.add(Cookie::new(
-                user::SID_COOKIE,
-                session.to_string().to_owned(),
-            )),
-            Redirect::to("/"),
-        )
+        jar: SessionJar,
+        headers: HeaderMap,
+        Form(input): Form<params::SignupParams>,
+    ) -> Response {
+        let user_agent = user_agent(&headers);
+        let (name, email) = (input.name.clone(), input.email.clone());
+        // Hashing the password and sending mail take a while
+        let app = state.clone();
+        let session = tokio::task::spawn_blocking(move || {
+            app.sign_up(input.name, input.pswd, input.email, user_agent)
+        })
+        .await;
+        match session {
+            Ok(Ok(Some(session))) => {
+                (jar.with_session(&state.cookies, session), Redirect::to("/")).into_response()
+            }
+            Ok(Ok(None)) => {
+                let message = format!(
+                    "We sent a link to {email}. Open it to confirm your address, then log in."
+                );
+                match state.notice_page("Check your email", &message) {
+                    Ok(page) => Html(page).into_response(),
+                    Err(e) => error_response(e),
+                }
+            }
+            Ok(Err(e)) => match state.signup(&name, &email, Some(&e.to_string())) {
+                Ok(page) => (StatusCode::UNPROCESSABLE_ENTITY, Html(page)).into_response(),
+                Err(e) => error_response(e),
+            },
+            Err(e) => error_response(e.into()),
+        }
     }
 
-    async fn login(State(state): State<App<B>>) -> Html<String> {
-        Html(state.login().unwrap())
+    async fn login(State(state): State<App<B>>) -> Response {
+        match state.login("", None) {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
     }
 
     async fn create_session(
         State(state): State<App<B>>,
-        jar: CookieJar,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        jar: SessionJar,
+        headers: HeaderMap,
         Form(input): Form<params::CreateUserParams>,
-    ) -> (CookieJar, Redirect) {
-        let session = state.members.login(input.name, input.pswd).unwrap();
-        (
-            jar.add(Cookie::new(
-                user::SID_COOKIE,
-                session.to_string().to_owned(),
-            )),
-            Redirect::to("/"),
-        )
+    ) -> Response {
+        let user_agent = user_agent(&headers);
+        let ip = client_ip(&state, &headers, addr);
+        let name = input.name.clone();
+        let app = state.clone();
+        let session =
+            tokio::task::spawn_blocking(move || app.log_in(input.name, input.pswd, ip, user_agent))
+                .await;
+        match session {
+            Ok(Ok(Login::Session(session))) => {
+                (jar.with_session(&state.cookies, session), Redirect::to("/")).into_response()
+            }
+            Ok(Ok(Login::SecondFactor(challenge))) => match state.login_code_page(&challenge, None)
+            {
+                Ok(page) => Html(page).into_response(),
+                Err(e) => error_response(e),
+            },
+            Ok(Ok(Login::Unverified(_))) => {
+                let error = "Confirm your email address first. We sent a new link to it";
+                match state.login(&name, Some(error)) {
+                    Ok(page) => (StatusCode::FORBIDDEN, Html(page)).into_response(),
+                    Err(e) => error_response(e),
+                }
+            }
+            Ok(Err(e)) => match state.login(&name, Some(&e.to_string())) {
+                Ok(page) => (StatusCode::UNAUTHORIZED, Html(page)).into_response(),
+                Err(e) => error_response(e),
+            },
+            Err(e) => error_response(e.into()),
+        }
+    }
+
+    async fn submit_login_code(
+        State(state): State<App<B>>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        jar: SessionJar,
+        headers: HeaderMap,
+        Form(input): Form<params::LoginCodeParams>,
+    ) -> Response {
+        let ip = client_ip(&state, &headers, addr);
+        match state.log_in_second_factor(&input.challenge, &input.code, ip) {
+            Ok(session) => {
+                (jar.with_session(&state.cookies, session), Redirect::to("/")).into_response()
+            }
+            Err(e) => match state.login_code_page(&input.challenge, Some(&e.to_string())) {
+                Ok(page) => (StatusCode::UNAUTHORIZED, Html(page)).into_response(),
+                Err(e) => error_response(e),
+            },
+        }
+    }
+
+    async fn forgot_password(State(state): State<App<B>>) -> Response {
+        match state.forgot_password_page(false, None) {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn request_password_reset(
+        State(state): State<App<B>>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        headers: HeaderMap,
+        Form(input): Form<params::EmailParams>,
+    ) -> Response {
+        let ip = client_ip(&state, &headers, addr);
+        let page = match state.throttle_password_reset(ip) {
+            Ok(()) => {
+                // Not waited for, so the response takes as long whether or not the address
+                // belongs to an account
+                let app = state.clone();
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = app.send_password_reset(&input.email) {
+                        tracing::error!("Couldn't send a password reset mail: {e}");
+                    }
+                });
+                state.forgot_password_page(true, None)
+            }
+            Err(e) => state.forgot_password_page(false, Some(&e.to_string())),
+        };
+        match page {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn reset_password_page(
+        Path(params): Path<params::MailLinkParams>,
+        State(state): State<App<B>>,
+    ) -> Response {
+        match state.reset_password_page(&params.secret, None) {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn reset_password(
+        Path(params): Path<params::MailLinkParams>,
+        State(state): State<App<B>>,
+        Form(input): Form<params::PasswordParams>,
+    ) -> Response {
+        let app = state.clone();
+        let secret = params.secret.clone();
+        let res =
+            tokio::task::spawn_blocking(move || app.reset_password(&secret, &input.pswd)).await;
+        match res {
+            Ok(Ok(())) => Redirect::to("/login").into_response(),
+            Ok(Err(e)) => match state.reset_password_page(&params.secret, Some(&e.to_string())) {
+                Ok(page) => (StatusCode::UNPROCESSABLE_ENTITY, Html(page)).into_response(),
+                Err(e) => error_response(e),
+            },
+            Err(e) => error_response(e.into()),
+        }
+    }
+
+    async fn verify_email(
+        Path(params): Path<params::MailLinkParams>,
+        State(state): State<App<B>>,
+    ) -> Response {
+        let page = match state.verify_email(&params.secret) {
+            Ok(()) => state.notice_page(
+                "Email address confirmed",
+                "Thanks! Password reset links will go to this address.",
+            ),
+            Err(e) => state.notice_page("Couldn't confirm the address", &e.to_string()),
+        };
+        match page {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn set_email(
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::EmailParams>,
+    ) -> Response {
+        let app = state.clone();
+        let res = tokio::task::spawn_blocking(move || app.set_email(user.id, &input.email)).await;
+        match res {
+            Ok(Ok(())) => Redirect::to("/user").into_response(),
+            Ok(Err(e)) => error_response(e),
+            Err(e) => error_response(e.into()),
+        }
+    }
+
+    /// Ends the current session
+    async fn logout(
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        jar: SessionJar,
+    ) -> (SessionJar, Redirect) {
+        state.members.sessions.revoke(user.sid);
+        (jar.without_session(), Redirect::to("/"))
     }
 
     /*
@@ -124,12 +819,8 @@ impl<B: Backend + 'static> AppRoutes<B> {
             - Active works (bookshelf)
         - Otherwise, it redirects to home
     */
-    async fn user_library(State(state): State<App<B>>, jar: CookieJar) -> Response {
-        let sid = jar.get(user::SID_COOKIE).and_then(|v| {
-            let s = v.value_trimmed();
-            uuid::Uuid::try_parse(s).ok()
-        });
-        if let Some(sid) = sid {
+    async fn user_library(State(state): State<App<B>>, jar: SessionJar) -> Response {
+        if let Some(sid) = jar.session_id() {
             match state.user_library(sid) {
                 Ok(res) => Html(res).into_response(),
                 Err(e) => e.to_string().into_response(),
@@ -138,4 +829,550 @@ impl<B: Backend + 'static> AppRoutes<B> {
             Redirect::to("/").into_response()
         }
     }
+
+    async fn update_settings(
+        State(state): State<App<B>>,
+        jar: SessionJar,
+        Form(input): Form<params::ReaderSettingsParams>,
+    ) -> Response {
+        let Some(sid) = jar.session_id() else {
+            return Redirect::to("/").into_response();
+        };
+        let settings = user::ReaderSettings {
+            writing_mode: input.writing_mode,
+        };
+        match state.set_reader_settings(sid, settings) {
+            Ok(()) => Redirect::to("/user").into_response(),
+            Err(e) => e.to_string().into_response(),
+        }
+    }
+
+    async fn rename(
+        State(state): State<App<B>>,
+        jar: SessionJar,
+        Form(input): Form<params::RenameParams>,
+    ) -> Response {
+        let Some(sid) = jar.session_id() else {
+            return Redirect::to("/login").into_response();
+        };
+        match state.rename(sid, input.name) {
+            Ok(()) => Redirect::to("/user").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn author(
+        State(state): State<App<B>>,
+        Path(name): Path<String>,
+        jar: SessionJar,
+    ) -> Response {
+        match state.author_page(&name, jar.session_id()) {
+            Ok(res) => Html(res).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn follow_author(
+        State(state): State<App<B>>,
+        Path(name): Path<String>,
+        user: CurrentUser,
+    ) -> Response {
+        let url = format!("/authors/{}", url_encode_segment(&name));
+        match state.toggle_follow_author(user.id, &name) {
+            Ok(()) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn follow_work(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        match state.toggle_follow_work(user.id, &params) {
+            Ok(()) => Redirect::to(&work_path(&params.title, params.id)).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn shelve_work(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::ShelveParams>,
+    ) -> Response {
+        let url = format!("{}#shelves", work_path(&params.title, params.id));
+        match state.toggle_shelved(user.id, &params, input) {
+            Ok(()) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn add_to_cart(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        match state.add_to_cart(user.id, user.sid, &params) {
+            Ok(()) => Redirect::to("/cart").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn cart(State(state): State<App<B>>, user: CurrentUser) -> Response {
+        match state.cart_page(user.id, user.sid, None) {
+            Ok(res) => Html(res).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn remove_from_cart(
+        Path(params): Path<params::CartWorkParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        match state.remove_from_cart(user.sid, params.work) {
+            Ok(()) => Redirect::to("/cart").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    /// Declined payments show the cart again with the reason
+    async fn checkout(
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::CheckoutParams>,
+    ) -> Response {
+        // The payment provider is called synchronously
+        let app = state.clone();
+        let res = tokio::task::spawn_blocking(move || app.checkout(user.id, user.sid, input)).await;
+        let e = match res {
+            Ok(Ok(order)) => {
+                let url = format!("/user/orders/{}", b64_encode_uuid(order.as_bytes()));
+                return Redirect::to(&url).into_response();
+            }
+            Ok(Err(e)) => e,
+            Err(e) => return error_response(e.into()),
+        };
+        match state.cart_page(user.id, user.sid, Some(&e.to_string())) {
+            Ok(page) => (StatusCode::PAYMENT_REQUIRED, Html(page)).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn orders(State(state): State<App<B>>, user: CurrentUser) -> Response {
+        match state.orders_page(user.id) {
+            Ok(res) => Html(res).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn order(
+        Path(params): Path<params::OrderParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        match state.order_page(user.id, params.id) {
+            Ok(res) => Html(res).into_response(),
+            Err(e) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        }
+    }
+
+    async fn shelf(
+        Path(params): Path<params::PublicShelfParams>,
+        State(state): State<App<B>>,
+        jar: SessionJar,
+    ) -> Response {
+        match state.shelf_page(params, jar.session_id()) {
+            Ok(res) => Html(res).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn shelves(State(state): State<App<B>>, user: CurrentUser) -> Response {
+        match state.shelves_page(user.id) {
+            Ok(res) => Html(res).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn create_shelf(
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::ShelfFormParams>,
+    ) -> Response {
+        match state.create_shelf(user.id, input) {
+            Ok(()) => Redirect::to("/user/shelves").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn rename_shelf(
+        Path(params): Path<params::ShelfParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::ShelfNameParams>,
+    ) -> Response {
+        match state.rename_shelf(user.id, params.shelf, &input.name) {
+            Ok(()) => Redirect::to("/user/shelves").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn set_shelf_visibility(
+        Path(params): Path<params::ShelfParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::ShelfVisibilityParams>,
+    ) -> Response {
+        match state.set_shelf_public(user.id, params.shelf, input.public) {
+            Ok(()) => Redirect::to("/user/shelves").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn delete_shelf(
+        Path(params): Path<params::ShelfParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        match state.delete_shelf(user.id, params.shelf) {
+            Ok(()) => Redirect::to("/user/shelves").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn unshelve_work(
+        Path(params): Path<params::ShelfWorkParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        match state.unshelve_work(user.id, params) {
+            Ok(()) => Redirect::to("/user/shelves").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn move_on_shelf(
+        Path(params): Path<params::ShelfWorkParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::MoveShelfWorkParams>,
+    ) -> Response {
+        match state.move_on_shelf(user.id, params, input) {
+            Ok(()) => Redirect::to("/user/shelves").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn inbox(State(state): State<App<B>>, user: CurrentUser) -> Response {
+        match state.inbox_page(user.id) {
+            Ok(res) => Html(res).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn open_notification(
+        Path(params): Path<params::NotificationIdParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        match state.open_notification(user.id, params.id) {
+            Ok(url) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn mark_all_read(State(state): State<App<B>>, user: CurrentUser) -> Response {
+        match state.mark_all_read(user.id) {
+            Ok(()) => Redirect::to("/user/inbox").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn trash_work(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        _: Authorized<ManageTrash>,
+    ) -> Response {
+        match state.trash_work(params) {
+            Ok(()) => Redirect::to("/admin/trash").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn trash(State(state): State<App<B>>, _: Authorized<ManageTrash>) -> Response {
+        match state.trash_page() {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn restore_work(
+        Path(params): Path<params::LiteraryWorkParams>,
+        State(state): State<App<B>>,
+        _: Authorized<ManageTrash>,
+    ) -> Response {
+        let url = work_path(&params.title, params.id);
+        match state.restore_work(params) {
+            Ok(()) => Redirect::to(&url).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn users(
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<ManageUsers>,
+    ) -> Response {
+        match state.users_page(user.id) {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn set_role(
+        State(state): State<App<B>>,
+        Authorized { user, .. }: Authorized<ManageUsers>,
+        Form(input): Form<params::SetRoleParams>,
+    ) -> Response {
+        match state.set_role(user.id, input) {
+            Ok(()) => Redirect::to("/admin/users").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn annotations(State(state): State<App<B>>, user: CurrentUser) -> Response {
+        match state.annotations_page(user.id) {
+            Ok(res) => Html(res).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn set_annotation_note(
+        Path(params): Path<params::AnnotationIdParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::AnnotationNoteParams>,
+    ) -> Response {
+        match state.set_annotation_note(user.id, params.id, input.note) {
+            Ok(()) => Redirect::to("/user/annotations").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn delete_annotation(
+        Path(params): Path<params::AnnotationIdParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        match state.delete_annotation(user.id, params.id) {
+            Ok(()) => Redirect::to("/user/annotations").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn sessions(State(state): State<App<B>>, user: CurrentUser) -> Response {
+        match state.sessions_page(user.id, user.sid) {
+            Ok(res) => Html(res).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn two_factor(State(state): State<App<B>>, user: CurrentUser) -> Response {
+        match state.two_factor_page(user.id, None, None) {
+            Ok(res) => Html(res).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    /// Shows the recovery codes right away, as they can't be seen again after a redirect
+    async fn enable_two_factor(
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::EnableTwoFactorParams>,
+    ) -> Response {
+        let res = state.enable_two_factor(user.id, input);
+        Self::two_factor_result(&state, user.id, res)
+    }
+
+    async fn disable_two_factor(
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::DisableTwoFactorParams>,
+    ) -> Response {
+        // Checking the password takes a while
+        let app = state.clone();
+        let res = tokio::task::spawn_blocking(move || app.disable_two_factor(user.id, input)).await;
+        match res {
+            Ok(res) => Self::two_factor_result(&state, user.id, res.map(|()| vec![])),
+            Err(e) => error_response(e.into()),
+        }
+    }
+
+    async fn new_recovery_codes(
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::PasswordParams>,
+    ) -> Response {
+        let app = state.clone();
+        let res =
+            tokio::task::spawn_blocking(move || app.new_recovery_codes(user.id, &input.pswd)).await;
+        match res {
+            Ok(res) => Self::two_factor_result(&state, user.id, res),
+            Err(e) => error_response(e.into()),
+        }
+    }
+
+    /// The two-factor page with the new recovery codes, or the error
+    fn two_factor_result(state: &App<B>, user: Uuid, res: anyhow::Result<Vec<String>>) -> Response {
+        let (status, page) = match res {
+            Ok(codes) => {
+                let codes = Some(codes).filter(|c| !c.is_empty());
+                (StatusCode::OK, state.two_factor_page(user, codes, None))
+            }
+            Err(e) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                state.two_factor_page(user, None, Some(&e.to_string())),
+            ),
+        };
+        match page {
+            Ok(page) => (status, Html(page)).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn tokens(State(state): State<App<B>>, user: CurrentUser) -> Response {
+        match state.tokens_page(user.id, None, None) {
+            Ok(res) => Html(res).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    /// Shows the new token right away, as it can't be seen again after a redirect
+    async fn create_token(
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        Form(input): Form<params::TokenFormParams>,
+    ) -> Response {
+        let (status, page) = match state.create_token(user.id, input) {
+            Ok(token) => (
+                StatusCode::OK,
+                state.tokens_page(user.id, Some(&token), None),
+            ),
+            Err(e) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                state.tokens_page(user.id, None, Some(&e.to_string())),
+            ),
+        };
+        match page {
+            Ok(page) => (status, Html(page)).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn revoke_token(
+        Path(params): Path<params::TokenIdParams>,
+        State(state): State<App<B>>,
+        user: CurrentUser,
+    ) -> Response {
+        match state.revoke_token(user.id, params.id) {
+            Ok(()) => Redirect::to("/user/tokens").into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    /// Owned works, reading progress and shelves, for scripts
+    async fn api_library(
+        State(state): State<App<B>>,
+        Scoped { user, .. }: Scoped<ReadLibrary>,
+    ) -> Response {
+        match state.members.get_library(user.id) {
+            Ok(lib) => Json(lib).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn export_account(State(state): State<App<B>>, user: CurrentUser) -> Response {
+        match state.export_account(user.id) {
+            Ok((name, data)) => {
+                let filename = url_encode_segment(&format!("toshokan-{name}.zip"));
+                let disposition = format!("attachment; filename*=UTF-8''{filename}");
+                (
+                    [
+                        (header::CONTENT_TYPE, "application/zip".to_string()),
+                        (header::CONTENT_DISPOSITION, disposition),
+                    ],
+                    data,
+                )
+                    .into_response()
+            }
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn delete_account_page(State(state): State<App<B>>, user: CurrentUser) -> Response {
+        match state.delete_account_page(user.id, None) {
+            Ok(page) => Html(page).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    async fn delete_account(
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        jar: SessionJar,
+        Form(input): Form<params::DeleteAccountParams>,
+    ) -> Response {
+        // Checking the password takes a while
+        let app = state.clone();
+        let res = tokio::task::spawn_blocking(move || app.delete_account(user.id, input)).await;
+        match res {
+            Ok(Ok(())) => {
+                tracing::info!("User {} deleted their account", user.id);
+                (jar.without_session(), Redirect::to("/")).into_response()
+            }
+            Ok(Err(e)) => match state.delete_account_page(user.id, Some(&e.to_string())) {
+                Ok(page) => (StatusCode::UNPROCESSABLE_ENTITY, Html(page)).into_response(),
+                Err(e) => error_response(e),
+            },
+            Err(e) => error_response(e.into()),
+        }
+    }
+
+    async fn log_out_everywhere(
+        State(state): State<App<B>>,
+        user: CurrentUser,
+        jar: SessionJar,
+    ) -> (SessionJar, Redirect) {
+        state.log_out_everywhere(user.id);
+        (jar.without_session(), Redirect::to("/login"))
+    }
+}
+
+/// Kept with new sessions so users can tell them apart
+fn user_agent(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.chars().take(256).collect())
+}
+
+/// Address of the client, as the reverse proxy reports it if one is configured
+fn client_ip<B: Backend>(state: &App<B>, headers: &HeaderMap, addr: SocketAddr) -> IpAddr {
+    let forwarded = state.config.accounts.client_ip_header.as_ref();
+    forwarded
+        .and_then(|name| headers.get(name.as_str()))
+        .and_then(|v| v.to_str().ok())
+        // Proxies append the address they saw to X-Forwarded-For, so the last one is ours
+        .and_then(|v| v.rsplit(',').next())
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(addr.ip())
+}
+
+/// Library errors map to their own status, anything else is a bad request
+fn error_response(e: anyhow::Error) -> Response {
+    match e.downcast::<LibraryError>() {
+        Ok(e) => e.into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
 }
@@ -0,0 +1,112 @@
+//! Ruby (furigana) in paragraph text, written the way Aozora Bunko does it:
+//! `｜青空《あおぞら》`, or just `青空《あおぞら》` when the base is a run of kanji
+
+/// Piece of a paragraph
+#[derive(Debug, PartialEq, Eq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+    Ruby { base: &'a str, reading: &'a str },
+}
+
+const OPEN: char = '《';
+const CLOSE: char = '》';
+
+/// Splits `s` into plain text and annotated runs. Markup that doesn't form a valid annotation is
+/// left as text
+pub fn segments(s: &str) -> Vec<Segment<'_>> {
+    let mut res = Vec::new();
+    let mut cursor = 0;
+    let mut search = 0;
+
+    while let Some(open) = s[search..].find(OPEN).map(|i| i + search) {
+        let Some(close) = s[open..].find(CLOSE).map(|i| i + open) else {
+            break;
+        };
+        let pending = &s[cursor..open];
+        let reading = &s[open + OPEN.len_utf8()..close];
+
+        // An explicit start takes priority, otherwise the base is the kanji right before
+        let (text, base) = if let Some(bar) = pending.rfind(['｜', '|']) {
+            let bar_len = pending[bar..].chars().next().unwrap().len_utf8();
+            (&pending[..bar], &pending[bar + bar_len..])
+        } else {
+            let start = pending
+                .char_indices()
+                .rev()
+                .take_while(|(_, c)| is_kanji(*c))
+                .last()
+                .map_or(pending.len(), |(i, _)| i);
+            pending.split_at(start)
+        };
+
+        search = close + CLOSE.len_utf8();
+        if base.is_empty() || reading.is_empty() {
+            // Not an annotation; keep scanning and emit it as text later
+            continue;
+        }
+        if !text.is_empty() {
+            res.push(Segment::Text(text));
+        }
+        res.push(Segment::Ruby { base, reading });
+        cursor = search;
+    }
+
+    if cursor < s.len() {
+        res.push(Segment::Text(&s[cursor..]));
+    }
+    res
+}
+
+/// The text as it is read, without the readings
+pub fn plain_text(s: &str) -> String {
+    segments(s)
+        .into_iter()
+        .map(|seg| match seg {
+            Segment::Text(t) => t,
+            Segment::Ruby { base, .. } => base,
+        })
+        .collect()
+}
+
+fn is_kanji(c: char) -> bool {
+    matches!(c,
+        '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{20000}'..='\u{3134F}'
+        | '々' | '〆' | '〇' | 'ヶ'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            segments("今日は青空《あおぞら》だ"),
+            vec![
+                Segment::Text("今日は"),
+                Segment::Ruby {
+                    base: "青空",
+                    reading: "あおぞら"
+                },
+                Segment::Text("だ"),
+            ]
+        );
+        assert_eq!(
+            segments("｜ジョン万次郎《じょんまんじろう》"),
+            vec![Segment::Ruby {
+                base: "ジョン万次郎",
+                reading: "じょんまんじろう"
+            }]
+        );
+        // Nothing to attach to
+        assert_eq!(
+            segments("ああ《いい》"),
+            vec![Segment::Text("ああ《いい》")]
+        );
+        assert_eq!(plain_text("東京《とうきょう》へ"), "東京へ");
+    }
+}
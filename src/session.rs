@@ -0,0 +1,265 @@
+//! Signed in sessions, kept in their own table keyed by session id
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Result};
+use bincode::{Decode, Encode};
+use uuid::Uuid;
+
+use crate::{
+    config::SessionConfig,
+    db::{index_key, Backend, Table},
+    utils::{decode_bincode, encode_bincode},
+};
+
+const SESSION_TABLE: &str = "SESSIONS";
+/// Index of the sessions of each user, keyed by user id then session id
+const USER_SESSION_TABLE: &str = "USER_SESSIONS";
+
+/// Most sessions a user can have. Signing in again drops the least recently used one
+const MAX_SESSIONS: usize = 100;
+
+/// `last_seen` is only written back once it is this stale, so reading a page doesn't always write
+const LAST_SEEN_PRECISION: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Encode, Decode)]
+pub struct Session {
+    /// Id of the signed in user
+    #[bincode(with_serde)]
+    pub user: Uuid,
+    pub created: SystemTime,
+    pub last_seen: SystemTime,
+    /// Pushed back every time the session is used
+    pub expires: SystemTime,
+    pub user_agent: Option<String>,
+    /// Works to buy, in the order they were added
+    #[bincode(with_serde)]
+    pub cart: Vec<Uuid>,
+}
+
+impl Session {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires <= now
+    }
+}
+
+pub struct SessionStore<B: Backend> {
+    sessions: <B as Backend>::OutTable,
+    by_user: <B as Backend>::OutTable,
+    lifetime: Duration,
+}
+
+impl<B: Backend> SessionStore<B> {
+    pub fn new(db: &B, config: &SessionConfig) -> Result<Self> {
+        let sessions = db.get_table(SESSION_TABLE)?;
+        let by_user = db.get_table(USER_SESSION_TABLE)?;
+        let lifetime = Duration::from_secs(config.lifetime_hours * 60 * 60);
+        Ok(Self {
+            sessions,
+            by_user,
+            lifetime,
+        })
+    }
+
+    /// Starts a session for `user`, returning its id
+    pub fn create(&self, user: Uuid, user_agent: Option<String>) -> Result<Uuid> {
+        let mut existing = self.for_user(user);
+        if existing.len() >= MAX_SESSIONS {
+            existing.sort_by_key(|(_, s)| s.last_seen);
+            for (sid, _) in &existing[..=existing.len() - MAX_SESSIONS] {
+                self.remove(user, *sid);
+            }
+        }
+
+        let sid = Uuid::now_v7();
+        let now = SystemTime::now();
+        let session = Session {
+            user,
+            created: now,
+            last_seen: now,
+            expires: now + self.lifetime,
+            user_agent,
+            cart: vec![],
+        };
+        self.sessions.insert(sid, encode_bincode(&session)?);
+        self.by_user.insert(index_key(user, sid), []);
+        Ok(sid)
+    }
+
+    /// Id of the user signed in with `sid`, if the session hasn't expired. Marks it as used
+    pub fn user_for(&self, sid: Uuid) -> Option<Uuid> {
+        let old = self.sessions.get_value(sid)?;
+        let mut session: Session = decode_bincode(old.as_ref()).ok()?;
+        let now = SystemTime::now();
+        if session.is_expired(now) {
+            self.remove(session.user, sid);
+            return None;
+        }
+        let stale = now
+            .duration_since(session.last_seen)
+            .is_ok_and(|d| d >= LAST_SEEN_PRECISION);
+        if stale {
+            session.last_seen = now;
+            session.expires = now + self.lifetime;
+            // Skipped if the session changed since it was read, so an older cart isn't written
+            // back. Another request has just used it then anyway
+            if let Ok(data) = encode_bincode(&session) {
+                self.sessions
+                    .compare_and_swap(sid, Some(old.as_ref()), Some(&data));
+            }
+        }
+        Some(session.user)
+    }
+
+    pub fn cart(&self, sid: Uuid) -> Vec<Uuid> {
+        self.get(sid).map(|s| s.cart).unwrap_or_default()
+    }
+
+    /// Applies `f` to the cart of the session and writes it back. If the session changed in the
+    /// meantime, `f` is applied again to the new cart
+    pub fn update_cart<T>(&self, sid: Uuid, mut f: impl FnMut(&mut Vec<Uuid>) -> T) -> Result<T> {
+        loop {
+            let Some(old) = self.sessions.get_value(sid) else {
+                bail!("The session has ended");
+            };
+            let old = old.as_ref();
+            let mut session: Session = decode_bincode(old)?;
+            let res = f(&mut session.cart);
+            let new = encode_bincode(&session)?;
+            if self.sessions.compare_and_swap(sid, Some(old), Some(&new)) {
+                return Ok(res);
+            }
+        }
+    }
+
+    fn get(&self, sid: Uuid) -> Option<Session> {
+        let data = self.sessions.get_value(sid)?;
+        decode_bincode(data.as_ref()).ok()
+    }
+
+    /// Unexpired sessions of a user, oldest first
+    pub fn for_user(&self, user: Uuid) -> Vec<(Uuid, Session)> {
+        let now = SystemTime::now();
+        self.user_sids(user)
+            .into_iter()
+            .filter_map(|sid| Some((sid, self.get(sid)?)))
+            .filter(|(_, s)| !s.is_expired(now))
+            .collect()
+    }
+
+    /// Signs out of a single session
+    pub fn revoke(&self, sid: Uuid) {
+        if let Some(session) = self.get(sid) {
+            self.remove(session.user, sid);
+        }
+    }
+
+    /// Signs the user out everywhere
+    pub fn revoke_all(&self, user: Uuid) {
+        for sid in self.user_sids(user) {
+            self.remove(user, sid);
+        }
+    }
+
+    /// Deletes expired sessions, returning how many there were
+    pub fn sweep(&self) -> usize {
+        let now = SystemTime::now();
+        let expired: Vec<_> = self.iter().filter(|(_, s)| s.is_expired(now)).collect();
+        for (sid, session) in &expired {
+            self.remove(session.user, *sid);
+        }
+        expired.len()
+    }
+
+    /// Ids of the sessions of `user`, oldest first
+    fn user_sids(&self, user: Uuid) -> Vec<Uuid> {
+        self.by_user.indexed(user).collect()
+    }
+
+    fn remove(&self, user: Uuid, sid: Uuid) {
+        self.sessions.remove(sid);
+        self.by_user.remove(index_key(user, sid));
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Uuid, Session)> + '_ {
+        self.sessions.iter().filter_map(|(key, value)| {
+            let sid = Uuid::from_slice(key.as_ref()).ok()?;
+            let session = decode_bincode(value.as_ref()).ok()?;
+            Some((sid, session))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn store(lifetime_hours: u64) -> SessionStore<sled::Db> {
+        let config = SessionConfig {
+            lifetime_hours,
+            ..SessionConfig::default()
+        };
+        SessionStore::new(&crate::db::temporary(), &config).unwrap()
+    }
+
+    #[test]
+    fn expiry() {
+        let sessions = store(0);
+        let user = Uuid::now_v7();
+        let sid = sessions.create(user, None).unwrap();
+        sessions.create(user, None).unwrap();
+        assert!(sessions.for_user(user).is_empty());
+        // Using an expired session ends it
+        assert_eq!(sessions.user_for(sid), None);
+        assert_eq!(sessions.user_sids(user).len(), 1);
+        assert_eq!(sessions.sweep(), 1);
+        assert!(sessions.user_sids(user).is_empty());
+        assert!(sessions.by_user.is_empty());
+    }
+
+    #[test]
+    fn revoking() {
+        let sessions = store(1);
+        let (alice, bob) = (Uuid::now_v7(), Uuid::now_v7());
+        let phone = sessions.create(alice, Some("phone".into())).unwrap();
+        let laptop = sessions.create(alice, Some("laptop".into())).unwrap();
+        let other = sessions.create(bob, None).unwrap();
+        assert_eq!(sessions.user_for(phone), Some(alice));
+        let mut sids = sessions.user_sids(alice);
+        sids.sort();
+        let mut expected = [phone, laptop];
+        expected.sort();
+        assert_eq!(sids, expected);
+
+        sessions.revoke(phone);
+        assert_eq!(sessions.user_for(phone), None);
+        let left: Vec<_> = sessions
+            .for_user(alice)
+            .into_iter()
+            .map(|(s, _)| s)
+            .collect();
+        assert_eq!(left, [laptop]);
+
+        sessions.revoke_all(alice);
+        assert_eq!(sessions.user_for(laptop), None);
+        assert!(sessions.user_sids(alice).is_empty());
+        assert_eq!(sessions.user_for(other), Some(bob));
+        assert_eq!(sessions.user_sids(bob), [other]);
+        assert_eq!(sessions.sweep(), 0);
+    }
+
+    #[test]
+    fn oldest_sessions_make_room() {
+        let sessions = store(1);
+        let user = Uuid::now_v7();
+        let first = sessions.create(user, None).unwrap();
+        for _ in 1..MAX_SESSIONS {
+            sessions.create(user, None).unwrap();
+        }
+        assert_eq!(sessions.user_sids(user).len(), MAX_SESSIONS);
+        let newest = sessions.create(user, None).unwrap();
+        assert_eq!(sessions.user_sids(user).len(), MAX_SESSIONS);
+        assert_eq!(sessions.user_for(first), None);
+        assert_eq!(sessions.user_for(newest), Some(user));
+    }
+}
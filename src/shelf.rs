@@ -0,0 +1,216 @@
+//! Shelves users sort works onto. Every library has the reading status shelves, and users can
+//! add their own
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{params::MoveDirection, user::UserLibrary};
+
+pub const SHELF_NAME_MAX_LEN: usize = 64;
+/// Shelves a user can make on top of the status shelves
+pub const CUSTOM_SHELF_MAX: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShelfKind {
+    PlanToRead,
+    Finished,
+    Dropped,
+    Custom,
+}
+
+impl ShelfKind {
+    /// A work has at most one reading status, so it is on at most one of these shelves
+    pub fn is_status(self) -> bool {
+        self != ShelfKind::Custom
+    }
+
+    fn default_name(self) -> &'static str {
+        match self {
+            ShelfKind::PlanToRead => "Plan to read",
+            ShelfKind::Finished => "Finished",
+            ShelfKind::Dropped => "Dropped",
+            ShelfKind::Custom => "",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Shelf {
+    pub id: Uuid,
+    pub kind: ShelfKind,
+    pub name: String,
+    /// Anyone can see public shelves from the user's author page
+    pub public: bool,
+    /// In the order the user chose
+    pub works: Vec<Uuid>,
+    pub created: DateTime<Utc>,
+}
+
+impl Shelf {
+    fn new(kind: ShelfKind, name: String) -> Self {
+        Self {
+            id: Uuid::now_v7(),
+            kind,
+            name,
+            public: false,
+            works: vec![],
+            created: Utc::now(),
+        }
+    }
+
+    /// The status shelves every library starts with
+    pub fn defaults() -> Vec<Shelf> {
+        [
+            ShelfKind::PlanToRead,
+            ShelfKind::Finished,
+            ShelfKind::Dropped,
+        ]
+        .into_iter()
+        .map(|kind| Shelf::new(kind, kind.default_name().to_string()))
+        .collect()
+    }
+}
+
+impl UserLibrary {
+    pub fn shelf(&self, id: Uuid) -> Option<&Shelf> {
+        self.shelves.iter().find(|s| s.id == id)
+    }
+
+    pub fn create_shelf(&mut self, name: &str, public: bool) -> Result<Uuid> {
+        let custom = self
+            .shelves
+            .iter()
+            .filter(|s| s.kind == ShelfKind::Custom)
+            .count();
+        if custom >= CUSTOM_SHELF_MAX {
+            bail!("You can't have more than {CUSTOM_SHELF_MAX} shelves");
+        }
+        let name = self.check_name(name, None)?;
+        let mut shelf = Shelf::new(ShelfKind::Custom, name);
+        shelf.public = public;
+        let id = shelf.id;
+        self.shelves.push(shelf);
+        Ok(id)
+    }
+
+    /// Status shelves can be renamed too, but not deleted
+    pub fn rename_shelf(&mut self, id: Uuid, name: &str) -> Result<()> {
+        let name = self.check_name(name, Some(id))?;
+        self.shelf_mut(id)?.name = name;
+        Ok(())
+    }
+
+    pub fn set_shelf_public(&mut self, id: Uuid, public: bool) -> Result<()> {
+        self.shelf_mut(id)?.public = public;
+        Ok(())
+    }
+
+    pub fn delete_shelf(&mut self, id: Uuid) -> Result<()> {
+        if self.shelf_mut(id)?.kind.is_status() {
+            bail!("Reading status shelves can't be deleted");
+        }
+        self.shelves.retain(|s| s.id != id);
+        Ok(())
+    }
+
+    /// Puts `work` at the end of the shelf. Putting it on a status shelf takes it off the others
+    pub fn shelve(&mut self, id: Uuid, work: Uuid) -> Result<()> {
+        let shelf = self.shelf_mut(id)?;
+        if shelf.works.contains(&work) {
+            return Ok(());
+        }
+        shelf.works.push(work);
+        if shelf.kind.is_status() {
+            for other in &mut self.shelves {
+                if other.id != id && other.kind.is_status() {
+                    other.works.retain(|&w| w != work);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn unshelve(&mut self, id: Uuid, work: Uuid) -> Result<()> {
+        self.shelf_mut(id)?.works.retain(|&w| w != work);
+        Ok(())
+    }
+
+    /// Swaps `work` with its neighbour on the shelf
+    pub fn move_on_shelf(&mut self, id: Uuid, work: Uuid, direction: MoveDirection) -> Result<()> {
+        let works = &mut self.shelf_mut(id)?.works;
+        let Some(i) = works.iter().position(|&w| w == work) else {
+            bail!("That work isn't on the shelf");
+        };
+        let j = match direction {
+            MoveDirection::Up => i.checked_sub(1),
+            MoveDirection::Down => Some(i + 1),
+        };
+        if let Some(j) = j.filter(|&j| j < works.len()) {
+            works.swap(i, j);
+        }
+        Ok(())
+    }
+
+    fn shelf_mut(&mut self, id: Uuid) -> Result<&mut Shelf> {
+        match self.shelves.iter_mut().find(|s| s.id == id) {
+            Some(shelf) => Ok(shelf),
+            None => bail!("That shelf doesn't exist"),
+        }
+    }
+
+    /// Checks a new shelf name, returning it trimmed. `renaming` is allowed to keep its own name
+    fn check_name(&self, name: &str, renaming: Option<Uuid>) -> Result<String> {
+        let name = name.trim();
+        if name.is_empty() {
+            bail!("The shelf name can't be empty");
+        }
+        if name.chars().count() > SHELF_NAME_MAX_LEN {
+            bail!("Shelf names can't be longer than {SHELF_NAME_MAX_LEN} characters");
+        }
+        let taken = self
+            .shelves
+            .iter()
+            .any(|s| Some(s.id) != renaming && s.name.to_lowercase() == name.to_lowercase());
+        if taken {
+            bail!("You already have a shelf called {name}");
+        }
+        Ok(name.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn status_shelves() {
+        let mut lib = UserLibrary::default();
+        let [plan, finished, _] = [0, 1, 2].map(|i| lib.shelves[i].id);
+        let custom = lib.create_shelf("Favourites", true).unwrap();
+        assert!(lib.create_shelf(" favourites ", false).is_err());
+
+        let (a, b) = (Uuid::now_v7(), Uuid::now_v7());
+        lib.shelve(plan, a).unwrap();
+        lib.shelve(custom, a).unwrap();
+        lib.shelve(plan, b).unwrap();
+        // Finishing a work takes it off the plan to read shelf but not custom ones
+        lib.shelve(finished, a).unwrap();
+        let on: Vec<_> = lib
+            .shelves
+            .iter()
+            .filter(|s| s.works.contains(&a))
+            .map(|s| s.id)
+            .collect();
+        assert_eq!(on, [finished, custom]);
+        assert_eq!(lib.shelf(plan).unwrap().works, [b]);
+
+        lib.shelve(plan, a).unwrap();
+        lib.move_on_shelf(plan, a, MoveDirection::Up).unwrap();
+        assert_eq!(lib.shelf(plan).unwrap().works, [a, b]);
+        assert!(lib.delete_shelf(plan).is_err());
+        lib.delete_shelf(custom).unwrap();
+        assert!(lib.shelf(custom).is_none());
+    }
+}
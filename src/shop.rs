@@ -0,0 +1,201 @@
+//! Prices and the ledger of orders. Orders are never deleted, only moved from pending to paid
+//! or failed, so they stay a record of every purchase
+
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use bincode::{Decode, Encode};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    db::{index_key, Backend, Table},
+    utils::{decode_bincode, encode_bincode},
+};
+
+/// Keyed by order id, which sorts them by age
+const ORDER_TABLE: &str = "ORDERS";
+/// Keys of [`index_key`] of the user and order id
+const USER_ORDER_TABLE: &str = "USER_ORDERS";
+/// Highest price of a work, in cents
+const PRICE_MAX: u32 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Encode, Decode)]
+#[serde(rename_all = "kebab-case")]
+pub enum OrderStatus {
+    /// Waiting for the payment provider
+    Pending,
+    Paid,
+    Failed,
+}
+
+#[derive(Clone, Serialize, Encode, Decode)]
+pub struct OrderItem {
+    #[bincode(with_serde)]
+    pub work: Uuid,
+    /// As it was when bought
+    pub title: String,
+    pub price: u32,
+}
+
+#[derive(Clone, Encode, Decode)]
+pub struct Order {
+    #[bincode(with_serde)]
+    pub user: Uuid,
+    pub items: Vec<OrderItem>,
+    pub currency: String,
+    pub status: OrderStatus,
+    /// Name of the payment provider
+    pub provider: String,
+    /// The provider's id for the payment
+    pub reference: Option<String>,
+    /// Why the payment failed
+    pub failure: Option<String>,
+    pub created: SystemTime,
+    pub updated: SystemTime,
+}
+
+impl Order {
+    pub fn total(&self) -> u64 {
+        self.items.iter().map(|i| u64::from(i.price)).sum()
+    }
+}
+
+pub struct OrderStore<B: Backend> {
+    orders: <B as Backend>::OutTable,
+    by_user: <B as Backend>::OutTable,
+}
+
+impl<B: Backend> OrderStore<B> {
+    pub fn new(db: &B) -> Result<Self> {
+        Ok(Self {
+            orders: db.get_table(ORDER_TABLE)?,
+            by_user: db.get_table(USER_ORDER_TABLE)?,
+        })
+    }
+
+    /// Records a pending order, returning its id
+    pub fn create(
+        &self,
+        user: Uuid,
+        items: Vec<OrderItem>,
+        currency: &str,
+        provider: &str,
+    ) -> Result<Uuid> {
+        let id = Uuid::now_v7();
+        let now = SystemTime::now();
+        let order = Order {
+            user,
+            items,
+            currency: currency.to_string(),
+            status: OrderStatus::Pending,
+            provider: provider.to_string(),
+            reference: None,
+            failure: None,
+            created: now,
+            updated: now,
+        };
+        self.orders.insert(id, encode_bincode(&order)?);
+        self.by_user.insert(index_key(user, id), []);
+        Ok(id)
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Order> {
+        decode_bincode(self.orders.get_value(id)?.as_ref()).ok()
+    }
+
+    /// Settles a pending order
+    pub fn settle(
+        &self,
+        id: Uuid,
+        status: OrderStatus,
+        reference: Option<String>,
+        failure: Option<String>,
+    ) -> Result<()> {
+        let Some(mut order) = self.get(id) else {
+            bail!("That order doesn't exist");
+        };
+        if order.status != OrderStatus::Pending {
+            bail!("The order was already settled");
+        }
+        order.status = status;
+        order.reference = reference;
+        order.failure = failure;
+        order.updated = SystemTime::now();
+        self.orders.insert(id, encode_bincode(&order)?);
+        Ok(())
+    }
+
+    /// Orders of `user`, newest first
+    pub fn for_user(&self, user: Uuid) -> Vec<(Uuid, Order)> {
+        let mut res: Vec<_> = self
+            .by_user
+            .indexed(user)
+            .filter_map(|id| Some((id, self.get(id)?)))
+            .collect();
+        res.reverse();
+        res
+    }
+}
+
+/// Cents as an amount with two decimals, e.g. `4.99`
+pub fn format_amount(cents: u64) -> String {
+    format!("{}.{:02}", cents / 100, cents % 100)
+}
+
+/// Like [`format_amount`] with the currency, e.g. `4.99 USD`
+pub fn format_price(cents: u64, currency: &str) -> String {
+    format!("{} {currency}", format_amount(cents))
+}
+
+/// Reads a price typed by an author, e.g. `4.99` or `5`. Empty means free
+pub fn parse_price(s: &str) -> Result<Option<u32>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    let (whole, fraction) = match s.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (s, None),
+    };
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !digits(whole) || fraction.is_some_and(|f| f.len() > 2 || !digits(f)) {
+        bail!("{s} isn't a price");
+    }
+    let cents = whole
+        .parse::<u32>()
+        .ok()
+        .and_then(|w| w.checked_mul(100))
+        .and_then(|w| {
+            w.checked_add(
+                format!("{:0<2}", fraction.unwrap_or_default())
+                    .parse::<u32>()
+                    .ok()?,
+            )
+        })
+        .filter(|&c| c <= PRICE_MAX);
+    match cents {
+        Some(0) => Ok(None),
+        Some(cents) => Ok(Some(cents)),
+        None => bail!("Prices can't be higher than {}", PRICE_MAX / 100),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prices() {
+        assert_eq!(parse_price("4.99").unwrap(), Some(499));
+        assert_eq!(parse_price(" 5 ").unwrap(), Some(500));
+        assert_eq!(parse_price("0.5").unwrap(), Some(50));
+        assert_eq!(parse_price("").unwrap(), None);
+        assert_eq!(parse_price("0").unwrap(), None);
+        for bad in ["-1", "1.999", "1.", ".5", "1e3", "99999999"] {
+            assert!(parse_price(bad).is_err(), "{bad}");
+        }
+        assert_eq!(format_price(499, "USD"), "4.99 USD");
+        assert_eq!(format_price(50, "EUR"), "0.50 EUR");
+    }
+}
@@ -6,46 +6,573 @@
         - Should not block server
 */
 
-use chrono::{Datelike, Days, Utc};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::AddAssign,
+    sync::{Mutex, PoisonError},
+};
 
-/// Container for all statistics per object
+use anyhow::Result;
+use bincode::{Decode, Encode};
+use chrono::{Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    config::StatsConfig,
+    db::{Backend, Table},
+    utils::{decode_bincode, encode_bincode},
+};
+
+/// Keyed by the work's id, the chapter's id (or zeros for the work as a whole) and the metric
+const STATS_TABLE: &str = "STATS";
+/// Readers already counted as unique, keyed by day, work and user. Only recent days are kept
+const READERS_TABLE: &str = "STATS_READERS";
+/// Counts are spread over this many locks so readers rarely wait on each other
+const SHARDS: usize = 16;
+
+/// Container for all statistics per object. Counts that change often are kept in [`StatsStore`]
+/// instead
+#[derive(Default, Serialize, Encode, Decode)]
 pub struct Statistics {
-    views: DataBucketVec<usize>,
+    ratings: Ratings,
+}
+
+/// How many readers gave each number of stars
+#[derive(Default, Clone, Copy, Serialize, Encode, Decode)]
+pub struct Ratings {
+    /// Index 0 is one star
+    stars: [u32; 5],
+}
+
+impl Ratings {
+    pub fn count(&self) -> u32 {
+        self.stars.iter().sum()
+    }
+
+    /// Mean number of stars, if anyone rated
+    pub fn average(&self) -> Option<f32> {
+        let count = self.count();
+        let total: u32 = (1..).zip(self.stars).map(|(s, n)| s * n).sum();
+        (count > 0).then(|| total as f32 / count as f32)
+    }
+}
+
+/// What is counted. Each is kept per work, and per chapter where that makes sense
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Metric {
+    /// Page loads of a work or a chapter
+    Views,
+    /// Signed in users who opened a chapter, once per work and day
+    UniqueReaders,
+    /// Times signed in users finished reading every chapter of a work
+    Completions,
+    /// Copies of a work sold
+    Purchases,
+    /// Comments posted on a chapter
+    Comments,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 5] = [
+        Metric::Views,
+        Metric::UniqueReaders,
+        Metric::Completions,
+        Metric::Purchases,
+        Metric::Comments,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::Views => "Views",
+            Metric::UniqueReaders => "Readers",
+            Metric::Completions => "Completions",
+            Metric::Purchases => "Purchases",
+            Metric::Comments => "Comments",
+        }
+    }
+
+    /// Counts that can't be summed into weeks and months, as someone reading on two days of a
+    /// week is still one reader that week. They are only kept per day
+    pub fn daily_only(self) -> bool {
+        self == Metric::UniqueReaders
+    }
+
+    /// Stored in keys, so these never change
+    fn id(self) -> u8 {
+        match self {
+            Metric::Views => 0,
+            Metric::UniqueReaders => 1,
+            Metric::Completions => 2,
+            Metric::Purchases => 3,
+            Metric::Comments => 4,
+        }
+    }
+}
+
+/// Length of the periods a series is read in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Granularity {
+    #[default]
+    Day,
+    /// Starting on Mondays
+    Week,
+    Month,
 }
 
-/// A bucket where all datapoints per day are stored.
+impl Granularity {
+    /// Index of the period `day` falls in. Days are counted from the common era
+    pub fn period(self, day: u32) -> u32 {
+        match self {
+            Granularity::Day => day,
+            // Day 1 of the common era was a Monday
+            Granularity::Week => day.saturating_sub(1) / 7,
+            Granularity::Month => NaiveDate::from_num_days_from_ce_opt(day as i32)
+                .map_or(0, |d| d.year() as u32 * 12 + d.month0()),
+        }
+    }
+
+    /// First day of a period
+    pub fn first_day(self, period: u32) -> u32 {
+        match self {
+            Granularity::Day => period,
+            Granularity::Week => period * 7 + 1,
+            Granularity::Month => NaiveDate::from_ymd_opt((period / 12) as i32, period % 12 + 1, 1)
+                .map_or(0, |d| d.num_days_from_ce() as u32),
+        }
+    }
+}
+
+/// A bucket where all datapoints per period (usually a day) are stored.
+#[derive(Serialize, Encode, Decode)]
+#[bincode(bounds = "T: Encode + Decode + for<'de> bincode::BorrowDecode<'de> + 'static")]
 pub struct DataBucketVec<T> {
     offset: u32,
-    // (DayOffset, T)
+    // (PeriodOffset, T)
     data: Vec<(u32, T)>,
 }
 
 impl<T> Default for DataBucketVec<T> {
     fn default() -> Self {
-        // SAFETY: UTC > CE
-        let offset = Utc::now().num_days_from_ce() as u32;
+        Self::starting(today())
+    }
+}
+
+impl<T> DataBucketVec<T> {
+    /// Empty, with periods counted from `period`
+    pub fn starting(period: u32) -> Self {
         Self {
-            offset,
+            offset: period,
             data: vec![],
         }
     }
+
+    /// Periods from `from` to `to` inclusive that have data, oldest first
+    pub fn range(&self, from: u32, to: u32) -> impl Iterator<Item = (u32, &T)> {
+        self.data
+            .iter()
+            .map(|(p, t)| (self.offset + p, t))
+            .skip_while(move |(p, _)| *p < from)
+            .take_while(move |(p, _)| *p <= to)
+    }
+
+    /// Drops the data of periods before `period`, returning if there was any
+    pub fn prune_before(&mut self, period: u32) -> bool {
+        let before = self.data.len();
+        let offset = self.offset;
+        self.data.retain(|(p, _)| offset + p >= period);
+        self.data.len() < before
+    }
+}
+
+impl<T: Default + AddAssign> DataBucketVec<T> {
+    /// Adds `n` to the bucket of `period`
+    pub fn add(&mut self, period: u32, n: T) {
+        if period < self.offset {
+            let shift = self.offset - period;
+            for (p, _) in &mut self.data {
+                *p += shift;
+            }
+            self.offset = period;
+        }
+        let period_offset = period - self.offset;
+        match self.data.binary_search_by_key(&period_offset, |(p, _)| *p) {
+            Ok(i) => self.data[i].1 += n,
+            Err(i) => {
+                let mut bucket = T::default();
+                bucket += n;
+                self.data.insert(i, (period_offset, bucket));
+            }
+        }
+    }
+}
+
+impl DataBucketVec<u64> {
+    pub fn total(&self) -> u64 {
+        self.data.iter().map(|(_, n)| n).sum()
+    }
+}
+
+/// One metric of one work or chapter. Every count goes into its day, week and month, so old
+/// days and weeks can be dropped while the longer periods keep their totals
+#[derive(Encode, Decode)]
+pub struct Series {
+    daily: DataBucketVec<u64>,
+    weekly: DataBucketVec<u64>,
+    monthly: DataBucketVec<u64>,
+}
+
+impl Series {
+    /// Empty, for counts from `day` on
+    pub fn starting(day: u32) -> Self {
+        Self {
+            daily: DataBucketVec::starting(day),
+            weekly: DataBucketVec::starting(Granularity::Week.period(day)),
+            monthly: DataBucketVec::starting(Granularity::Month.period(day)),
+        }
+    }
+
+    pub fn add(&mut self, day: u32, n: u64) {
+        self.daily.add(day, n);
+        self.weekly.add(Granularity::Week.period(day), n);
+        self.monthly.add(Granularity::Month.period(day), n);
+    }
+
+    /// Adds to the day only, for [`Metric::daily_only`] counts
+    pub fn add_day(&mut self, day: u32, n: u64) {
+        self.daily.add(day, n);
+    }
+
+    /// Adds the counts of `other`, e.g. to sum a work's chapters
+    pub fn merge(&mut self, other: &Series) {
+        let all = u32::MAX;
+        for (mine, theirs) in [
+            (&mut self.daily, &other.daily),
+            (&mut self.weekly, &other.weekly),
+            (&mut self.monthly, &other.monthly),
+        ] {
+            for (period, n) in theirs.range(0, all) {
+                mine.add(period, *n);
+            }
+        }
+    }
+
+    /// Monthly data is never dropped, so it holds every count
+    pub fn total(&self) -> u64 {
+        self.monthly.total()
+    }
+
+    /// Counts per period between two days, inclusive, as the first day of each period. Periods
+    /// without counts are left out. Days and weeks past retention are gone
+    pub fn range(&self, by: Granularity, from: u32, to: u32) -> Vec<(u32, u64)> {
+        let buckets = match by {
+            Granularity::Day => &self.daily,
+            Granularity::Week => &self.weekly,
+            Granularity::Month => &self.monthly,
+        };
+        buckets
+            .range(by.period(from), by.period(to))
+            .map(|(period, n)| (by.first_day(period), *n))
+            .collect()
+    }
+
+    /// Drops days and weeks older than the config keeps as of `today`, returning if any were
+    fn compact(&mut self, today: u32, config: &StatsConfig) -> bool {
+        let days = today.saturating_sub(config.daily_retention_days);
+        let weeks = Granularity::Week
+            .period(today)
+            .saturating_sub(config.weekly_retention_weeks);
+        let pruned_days = self.daily.prune_before(days);
+        self.weekly.prune_before(weeks) || pruned_days
+    }
+}
+
+/// The current day in UTC, counted from the common era
+pub fn today() -> u32 {
+    // SAFETY: UTC > CE
+    Utc::now().num_days_from_ce() as u32
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct CountKey {
+    metric: Metric,
+    work: Uuid,
+    /// `None` for the work as a whole
+    chapter: Option<Uuid>,
+    day: u32,
+}
+
+#[derive(Default)]
+struct Shard {
+    counts: HashMap<CountKey, u64>,
+    /// Work, day and user
+    readers: HashSet<(Uuid, u32, Uuid)>,
+}
+
+/// Counts made in memory since the last flush
+struct Pending {
+    shards: [Mutex<Shard>; SHARDS],
+}
+
+impl Pending {
+    fn new() -> Self {
+        Self {
+            shards: std::array::from_fn(|_| Mutex::default()),
+        }
+    }
+
+    fn shard(&self, work: Uuid) -> std::sync::MutexGuard<'_, Shard> {
+        // The low bits of v7 ids are random
+        let shard = &self.shards[work.as_u128() as usize % SHARDS];
+        shard.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Empties the shards, returning what they held
+    fn take(&self) -> Shard {
+        let mut all = Shard::default();
+        for shard in &self.shards {
+            let shard = std::mem::take(&mut *shard.lock().unwrap_or_else(PoisonError::into_inner));
+            for (key, n) in shard.counts {
+                *all.counts.entry(key).or_default() += n;
+            }
+            all.readers.extend(shard.readers);
+        }
+        all
+    }
+}
+
+/// Daily metrics of works and chapters. Counts are made in memory, so recording one never
+/// touches the db, and written to the table by [`StatsStore::flush`]
+pub struct StatsStore<B: Backend> {
+    stats: <B as Backend>::OutTable,
+    readers: <B as Backend>::OutTable,
+    pending: Pending,
+    /// Held while series are read and written back, so a flush and a compaction at the same
+    /// time don't overwrite each other's changes
+    writing: Mutex<()>,
+}
+
+impl<B: Backend> StatsStore<B> {
+    pub fn new(db: &B) -> Result<Self> {
+        Ok(Self {
+            stats: db.get_table(STATS_TABLE)?,
+            readers: db.get_table(READERS_TABLE)?,
+            pending: Pending::new(),
+            writing: Mutex::default(),
+        })
+    }
+
+    /// Adds `n` to a metric of a work, or of one of its chapters
+    pub fn record(&self, metric: Metric, work: Uuid, chapter: Option<Uuid>, n: u64) {
+        let key = CountKey {
+            metric,
+            work,
+            chapter,
+            day: today(),
+        };
+        *self.pending.shard(work).counts.entry(key).or_default() += n;
+    }
+
+    /// Counts `user` as a reader of the work today, unless they already were
+    pub fn record_reader(&self, work: Uuid, user: Uuid) {
+        self.pending
+            .shard(work)
+            .readers
+            .insert((work, today(), user));
+    }
+
+    /// Adds the counts made since the last flush to the table, returning how many there were
+    pub fn flush(&self) -> Result<u64> {
+        let _writing = self.writing.lock().unwrap_or_else(PoisonError::into_inner);
+        let Shard {
+            mut counts,
+            readers,
+        } = self.pending.take();
+        for (work, day, user) in readers {
+            if self
+                .readers
+                .insert(reader_key(day, work, user), [])
+                .is_none()
+            {
+                let key = CountKey {
+                    metric: Metric::UniqueReaders,
+                    work,
+                    chapter: None,
+                    day,
+                };
+                *counts.entry(key).or_default() += 1;
+            }
+        }
+
+        let mut merged: HashMap<[u8; 33], Series> = HashMap::new();
+        let mut total = 0;
+        for (count, n) in counts {
+            let key = stats_key(count.metric, count.work, count.chapter);
+            let series = merged
+                .entry(key)
+                .or_insert_with(|| self.get(key).unwrap_or_else(|| Series::starting(count.day)));
+            if count.metric.daily_only() {
+                series.add_day(count.day, n);
+            } else {
+                series.add(count.day, n);
+            }
+            total += n;
+        }
+        for (key, series) in merged {
+            self.stats.insert(key, encode_bincode(&series)?);
+        }
+        Ok(total)
+    }
+
+    /// A metric of a work as a whole, or of one of its chapters
+    pub fn series(&self, metric: Metric, work: Uuid, chapter: Option<Uuid>) -> Option<Series> {
+        self.get(stats_key(metric, work, chapter))
+    }
+
+    /// A metric of a work summed with that of its chapters
+    pub fn work_series(&self, metric: Metric, work: Uuid, chapters: &[Uuid]) -> Series {
+        let mut sum = Series::starting(today());
+        let keys = std::iter::once(None).chain(chapters.iter().copied().map(Some));
+        for series in keys.filter_map(|chapter| self.series(metric, work, chapter)) {
+            sum.merge(&series);
+        }
+        sum
+    }
+
+    /// Drops daily and weekly data past retention and readers of past days, returning how many
+    /// series changed
+    pub fn compact(&self, config: &StatsConfig) -> Result<usize> {
+        let _writing = self.writing.lock().unwrap_or_else(PoisonError::into_inner);
+        let today = today();
+        let mut changed = 0;
+        let keys: Vec<_> = self
+            .stats
+            .iter()
+            .map(|(k, _)| k.as_ref().to_vec())
+            .collect();
+        for key in keys {
+            let Some(mut series) = self.get(&key) else {
+                continue;
+            };
+            if series.compact(today, config) {
+                self.stats.insert(&key, encode_bincode(&series)?);
+                changed += 1;
+            }
+        }
+
+        // Readers are only looked up for the day being flushed, and days start with the key
+        let yesterday = today.saturating_sub(1).to_be_bytes();
+        let old: Vec<_> = self
+            .readers
+            .iter()
+            .map(|(k, _)| k.as_ref().to_vec())
+            .take_while(|k| k[..4] < yesterday[..])
+            .collect();
+        for key in old {
+            self.readers.remove(key);
+        }
+        Ok(changed)
+    }
+
+    fn get(&self, key: impl AsRef<[u8]>) -> Option<Series> {
+        decode_bincode(self.stats.get_value(key)?.as_ref()).ok()
+    }
+}
+
+fn stats_key(metric: Metric, work: Uuid, chapter: Option<Uuid>) -> [u8; 33] {
+    let mut key = [0; 33];
+    key[..16].copy_from_slice(work.as_bytes());
+    key[16..32].copy_from_slice(chapter.unwrap_or(Uuid::nil()).as_bytes());
+    key[32] = metric.id();
+    key
+}
+
+fn reader_key(day: u32, work: Uuid, user: Uuid) -> [u8; 36] {
+    let mut key = [0; 36];
+    key[..4].copy_from_slice(&day.to_be_bytes());
+    key[4..20].copy_from_slice(work.as_bytes());
+    key[20..].copy_from_slice(user.as_bytes());
+    key
 }
 
 impl Statistics {
-    pub fn add_view(&mut self) {
-        // SAFETY: UTC > CE
-        let time = Utc::now().num_days_from_ce() as u32;
-        let day_offset = time - self.views.offset;
-
-        if let Some(bucket) = self
-            .views
-            .data
-            .last_mut()
-            .filter(|(last_day_offset, _)| *last_day_offset == day_offset)
-        {
-            bucket.1 += 1;
-        } else {
-            self.views.data.push((day_offset, 1));
+    pub fn ratings(&self) -> Ratings {
+        self.ratings
+    }
+
+    /// Replaces a reader's rating. `None` is no rating
+    pub fn rate(&mut self, old: Option<u8>, new: Option<u8>) {
+        let slot = |stars: u8| usize::from(stars.clamp(1, 5)) - 1;
+        if let Some(old) = old {
+            let n = &mut self.ratings.stars[slot(old)];
+            *n = n.saturating_sub(1);
+        }
+        if let Some(new) = new {
+            self.ratings.stars[slot(new)] += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets_stay_sorted_by_day() {
+        let mut views = DataBucketVec::starting(100);
+        views.add(102, 1u64);
+        views.add(100, 2);
+        views.add(102, 3);
+        // Counts made just before midnight can be flushed after it
+        views.add(99, 1);
+        assert_eq!(views.offset, 99);
+        assert_eq!(views.data, [(0, 1), (1, 2), (3, 4)]);
+        assert_eq!(views.total(), 7);
+        let range: Vec<_> = views.range(100, 101).map(|(p, n)| (p, *n)).collect();
+        assert_eq!(range, [(100, 2)]);
+    }
+
+    #[test]
+    fn roll_ups_outlive_days() {
+        let day = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap().num_days_from_ce() as u32;
+        // A Monday
+        let monday = day(2026, 3, 2);
+        let mut series = Series::starting(monday);
+        for (d, n) in [(0, 1), (1, 2), (7, 4), (30, 8)] {
+            series.add(monday + d, n);
         }
+        let weeks = series.range(Granularity::Week, monday, monday + 30);
+        assert_eq!(weeks, [(monday, 3), (monday + 7, 4), (monday + 28, 8)]);
+        let months = series.range(Granularity::Month, monday, monday + 30);
+        assert_eq!(months, [(day(2026, 3, 1), 7), (day(2026, 4, 1), 8)]);
+
+        let config = StatsConfig {
+            daily_retention_days: 10,
+            weekly_retention_weeks: 2,
+            ..StatsConfig::default()
+        };
+        assert!(series.compact(monday + 30, &config));
+        assert_eq!(
+            series.range(Granularity::Day, 0, u32::MAX),
+            [(monday + 30, 8)]
+        );
+        assert_eq!(series.range(Granularity::Week, 0, u32::MAX).len(), 1);
+        assert_eq!(series.total(), 15);
+        assert!(!series.compact(monday + 30, &config));
+    }
+
+    #[test]
+    fn ratings() {
+        let mut stats = Statistics::default();
+        assert_eq!(stats.ratings().average(), None);
+        stats.rate(None, Some(5));
+        stats.rate(None, Some(2));
+        stats.rate(Some(2), Some(4));
+        assert_eq!(stats.ratings().count(), 2);
+        assert_eq!(stats.ratings().average(), Some(4.5));
+        stats.rate(Some(5), None);
+        assert_eq!(stats.ratings().average(), Some(4.0));
     }
 }
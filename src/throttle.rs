@@ -0,0 +1,127 @@
+//! Limits how often something can happen within a window of time, e.g. failed logins per account
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Keys kept before `record` forgets the expired ones itself instead of waiting for a sweep
+const PRUNE_AT: usize = 1024;
+
+pub struct Throttle {
+    /// Events allowed per key within `window`
+    max: usize,
+    window: Duration,
+    events: Mutex<Events>,
+}
+
+#[derive(Default)]
+struct Events {
+    times: HashMap<String, Vec<Instant>>,
+    /// Number of keys at which `record` prunes next
+    prune_at: usize,
+}
+
+impl Events {
+    fn prune(&mut self, window: Duration) {
+        let now = Instant::now();
+        self.times.retain(|_, times| {
+            times.retain(|t| now.duration_since(*t) < window);
+            !times.is_empty()
+        });
+        self.prune_at = (self.times.len() * 2).max(PRUNE_AT);
+    }
+}
+
+impl Throttle {
+    pub fn new(max: usize, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            events: Mutex::new(Events {
+                times: HashMap::new(),
+                prune_at: PRUNE_AT,
+            }),
+        }
+    }
+
+    /// If `key` has hit the limit, returns how long until it can try again
+    pub fn check(&self, key: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let mut events = self.events.lock().unwrap();
+        let times = events.times.get_mut(key)?;
+        times.retain(|t| now.duration_since(*t) < self.window);
+        if times.len() < self.max {
+            return None;
+        }
+        // The oldest event in the window has to expire first
+        let oldest = times[times.len() - self.max];
+        Some(self.window.saturating_sub(now.duration_since(oldest)))
+    }
+
+    /// Keys that have stopped getting events are normally dropped by `sweep`, but someone
+    /// cycling through keys could pile up many before it runs, so expired ones are also dropped
+    /// whenever the number of keys doubles
+    pub fn record(&self, key: &str) {
+        let mut events = self.events.lock().unwrap();
+        if events.times.len() >= events.prune_at {
+            events.prune(self.window);
+        }
+        events
+            .times
+            .entry(key.to_string())
+            .or_default()
+            .push(Instant::now());
+    }
+
+    pub fn clear(&self, key: &str) {
+        self.events.lock().unwrap().times.remove(key);
+    }
+
+    /// Forgets events that are outside the window
+    pub fn sweep(&self) {
+        self.events.lock().unwrap().prune(self.window);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits_within_the_window() {
+        let throttle = Throttle::new(2, Duration::from_secs(60));
+        throttle.record("a");
+        assert_eq!(throttle.check("a"), None);
+        throttle.record("a");
+        assert!(throttle.check("a").unwrap() > Duration::from_secs(59));
+        assert_eq!(throttle.check("b"), None);
+        throttle.clear("a");
+        assert_eq!(throttle.check("a"), None);
+    }
+
+    #[test]
+    fn expired_keys_are_pruned_on_record() {
+        let throttle = Throttle::new(1, Duration::from_millis(10));
+        for i in 0..PRUNE_AT {
+            throttle.record(&i.to_string());
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        throttle.record("new");
+        let events = throttle.events.lock().unwrap();
+        assert_eq!(events.times.len(), 1);
+        assert_eq!(events.prune_at, PRUNE_AT);
+    }
+
+    #[test]
+    fn live_keys_push_pruning_back() {
+        let throttle = Throttle::new(1, Duration::from_secs(60));
+        for i in 0..=PRUNE_AT {
+            throttle.record(&i.to_string());
+        }
+        let events = throttle.events.lock().unwrap();
+        assert_eq!(events.times.len(), PRUNE_AT + 1);
+        assert_eq!(events.prune_at, PRUNE_AT * 2);
+    }
+}
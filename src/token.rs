@@ -0,0 +1,229 @@
+//! Personal API tokens for scripts. Each grants some scopes of what its owner may do and is
+//! sent as `Authorization: Bearer <token>`. Only a hash of the token is kept
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Result};
+use axum::http::{header, HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bincode::{Decode, Encode};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    db::{index_key, Backend, Table},
+    utils::{decode_bincode, encode_bincode},
+};
+
+/// Keyed by the SHA-256 of the token
+const TOKEN_TABLE: &str = "API_TOKENS";
+/// Keys of [`index_key`] of the user and token id, with the token's key as the value
+const USER_TOKEN_TABLE: &str = "USER_API_TOKENS";
+/// Makes tokens easy to spot, e.g. by secret scanners
+const TOKEN_PREFIX: &str = "tsk_";
+const MAX_TOKENS: usize = 20;
+const TOKEN_NAME_MAX_LEN: usize = 64;
+/// `last_used` is only written back once it is this stale, so scripts don't write on every call
+const LAST_USED_PRECISION: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum Scope {
+    /// Owned works, reading progress and shelves
+    ReadLibrary,
+    /// Saving reading positions and marking chapters read
+    WriteProgress,
+    /// Creating and editing one's own works
+    AuthorContent,
+}
+
+impl Scope {
+    pub fn description(self) -> &'static str {
+        match self {
+            Scope::ReadLibrary => "Read your library",
+            Scope::WriteProgress => "Save reading progress",
+            Scope::AuthorContent => "Create and edit your works",
+        }
+    }
+}
+
+#[derive(Clone, Encode, Decode)]
+pub struct ApiToken {
+    #[bincode(with_serde)]
+    pub id: Uuid,
+    #[bincode(with_serde)]
+    pub user: Uuid,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created: SystemTime,
+    pub last_used: Option<SystemTime>,
+}
+
+/// The secret of an `Authorization: Bearer` header. Other kinds of credentials, e.g. the Basic
+/// ones of a proxy in front of the site, aren't tokens
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let auth = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    Some(auth.strip_prefix("Bearer ")?.trim())
+}
+
+pub struct TokenStore<B: Backend> {
+    tokens: <B as Backend>::OutTable,
+    by_user: <B as Backend>::OutTable,
+}
+
+impl<B: Backend> TokenStore<B> {
+    pub fn new(db: &B) -> Result<Self> {
+        Ok(Self {
+            tokens: db.get_table(TOKEN_TABLE)?,
+            by_user: db.get_table(USER_TOKEN_TABLE)?,
+        })
+    }
+
+    /// Makes a token for `user`, returning it. This is the only time it can be seen
+    pub fn create(&self, user: Uuid, name: &str, scopes: Vec<Scope>) -> Result<String> {
+        let name = name.trim();
+        if name.is_empty() {
+            bail!("The token needs a name");
+        }
+        if name.chars().count() > TOKEN_NAME_MAX_LEN {
+            bail!("Token names can't be longer than {TOKEN_NAME_MAX_LEN} characters");
+        }
+        if scopes.is_empty() {
+            bail!("The token needs at least one scope");
+        }
+        if self.for_user(user).len() >= MAX_TOKENS {
+            bail!("You can't have more than {MAX_TOKENS} tokens");
+        }
+
+        let mut bytes = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        let secret = format!("{TOKEN_PREFIX}{}", URL_SAFE_NO_PAD.encode(bytes));
+        let token = ApiToken {
+            id: Uuid::now_v7(),
+            user,
+            name: name.to_string(),
+            scopes,
+            created: SystemTime::now(),
+            last_used: None,
+        };
+        let key = hash(&secret);
+        self.tokens.insert(key, encode_bincode(&token)?);
+        self.by_user.insert(index_key(user, token.id), key);
+        Ok(secret)
+    }
+
+    /// The token sent by a script, if it exists. Marks it as used
+    pub fn authenticate(&self, secret: &str) -> Option<ApiToken> {
+        let key = hash(secret);
+        let mut token: ApiToken = decode_bincode(self.tokens.get_value(key)?.as_ref()).ok()?;
+        let now = SystemTime::now();
+        let stale = token.last_used.is_none_or(|t| {
+            now.duration_since(t)
+                .is_ok_and(|d| d >= LAST_USED_PRECISION)
+        });
+        if stale {
+            token.last_used = Some(now);
+            if let Ok(data) = encode_bincode(&token) {
+                self.tokens.insert(key, data);
+            }
+        }
+        Some(token)
+    }
+
+    /// Tokens of a user, oldest first
+    pub fn for_user(&self, user: Uuid) -> Vec<ApiToken> {
+        self.by_user
+            .scan_prefix(user.as_bytes())
+            .filter_map(|(_, key)| decode_bincode(self.tokens.get_value(key)?.as_ref()).ok())
+            .collect()
+    }
+
+    pub fn revoke(&self, user: Uuid, id: Uuid) -> Result<()> {
+        let Some(key) = self.by_user.remove(index_key(user, id)) else {
+            bail!("That token doesn't exist");
+        };
+        self.tokens.remove(key);
+        Ok(())
+    }
+
+    pub fn revoke_all(&self, user: Uuid) {
+        let tokens: Vec<_> = self.by_user.scan_prefix(user.as_bytes()).collect();
+        for (index, key) in tokens {
+            self.tokens.remove(key);
+            self.by_user.remove(index);
+        }
+    }
+}
+
+fn hash(secret: &str) -> [u8; 32] {
+    Sha256::digest(secret.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn store() -> TokenStore<sled::Db> {
+        TokenStore::new(&crate::db::temporary()).unwrap()
+    }
+
+    #[test]
+    fn tokens() {
+        let store = store();
+        let (alice, bob) = (Uuid::now_v7(), Uuid::now_v7());
+        let scopes = || vec![Scope::ReadLibrary];
+        assert!(store.create(alice, " ", scopes()).is_err());
+        assert!(store.create(alice, "Script", vec![]).is_err());
+
+        let secret = store.create(alice, "Script", scopes()).unwrap();
+        assert!(secret.starts_with(TOKEN_PREFIX));
+        let token = store.authenticate(&secret).unwrap();
+        assert_eq!((token.user, token.name.as_str()), (alice, "Script"));
+        assert!(token.last_used.is_some());
+        assert!(store.authenticate("tsk_made_up").is_none());
+
+        let other = store.create(alice, "Other", scopes()).unwrap();
+        store.create(bob, "Bob's", scopes()).unwrap();
+        let names: Vec<_> = store.for_user(alice).into_iter().map(|t| t.name).collect();
+        assert_eq!(names.len(), 2);
+
+        // Only the owner can revoke it
+        assert!(store.revoke(bob, token.id).is_err());
+        store.revoke(alice, token.id).unwrap();
+        assert!(store.authenticate(&secret).is_none());
+        assert!(store.revoke(alice, token.id).is_err());
+
+        store.revoke_all(alice);
+        assert!(store.authenticate(&other).is_none());
+        assert!(store.for_user(alice).is_empty());
+        assert_eq!(store.for_user(bob).len(), 1);
+    }
+
+    #[test]
+    fn at_most_max_tokens() {
+        let store = store();
+        let user = Uuid::now_v7();
+        for _ in 0..MAX_TOKENS {
+            store
+                .create(user, "Script", vec![Scope::ReadLibrary])
+                .unwrap();
+        }
+        assert!(store
+            .create(user, "Script", vec![Scope::ReadLibrary])
+            .is_err());
+    }
+
+    #[test]
+    fn only_bearer_is_a_token() {
+        let headers = |auth: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::AUTHORIZATION, HeaderValue::from_str(auth).unwrap());
+            headers
+        };
+        assert_eq!(bearer(&headers("Bearer tsk_a ")), Some("tsk_a"));
+        assert_eq!(bearer(&headers("Basic YTpi")), None);
+        assert_eq!(bearer(&HeaderMap::new()), None);
+    }
+}
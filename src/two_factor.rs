@@ -0,0 +1,237 @@
+//! Two-factor login with time-based one-time passwords (RFC 6238) from an authenticator app, and
+//! single-use recovery codes for when the app is lost
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Result;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bincode::{Decode, Encode};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use qrcode::{render::svg, QrCode};
+use rand::RngCore;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::utils::url_encode_segment;
+
+/// Shown in authenticator apps
+const ISSUER: &str = "Toshokan";
+/// Seconds each code is valid for
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+/// Steps before and after the current one that are accepted, for clocks that are a bit off
+const SKEW: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+/// How long users have for the second step of a login
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+/// Wrong codes allowed per login before it has to start over
+const CHALLENGE_ATTEMPTS: usize = 5;
+
+#[derive(Clone, Encode, Decode)]
+pub struct TwoFactor {
+    secret: Vec<u8>,
+    /// SHA-256 of the unused recovery codes
+    recovery: Vec<[u8; 32]>,
+    /// Step of the last accepted code, so codes can't be used twice
+    last_step: u64,
+    pub enabled: SystemTime,
+}
+
+impl TwoFactor {
+    /// Turns on two-factor login if `code` was made from `secret`. Returns it with the recovery
+    /// codes, which are only shown this once
+    pub fn enable(secret: &str, code: &str) -> Option<(Self, Vec<String>)> {
+        let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+        let last_step = check_code(&secret, code, SystemTime::now())?;
+        let mut two_factor = Self {
+            secret,
+            recovery: vec![],
+            last_step,
+            enabled: SystemTime::now(),
+        };
+        let codes = two_factor.new_recovery_codes();
+        Some((two_factor, codes))
+    }
+
+    /// Replaces the recovery codes, returning the new ones
+    pub fn new_recovery_codes(&mut self) -> Vec<String> {
+        let codes: Vec<_> = (0..RECOVERY_CODES)
+            .map(|_| {
+                let mut bytes = [0; 5];
+                rand::rngs::OsRng.fill_bytes(&mut bytes);
+                let code = BASE32_NOPAD.encode(&bytes).to_lowercase();
+                format!("{}-{}", &code[..4], &code[4..])
+            })
+            .collect();
+        self.recovery = codes.iter().map(|c| hash_recovery(c)).collect();
+        codes
+    }
+
+    pub fn recovery_left(&self) -> usize {
+        self.recovery.len()
+    }
+
+    /// Checks a code from the app or a recovery code, using it up
+    pub fn verify(&mut self, code: &str, now: SystemTime) -> bool {
+        let code = code.trim();
+        if code.len() == DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit()) {
+            return match check_code(&self.secret, code, now) {
+                Some(step) if step > self.last_step => {
+                    self.last_step = step;
+                    true
+                }
+                _ => false,
+            };
+        }
+        let hash = hash_recovery(code);
+        let before = self.recovery.len();
+        self.recovery.retain(|h| *h != hash);
+        self.recovery.len() < before
+    }
+}
+
+/// A secret for a new authenticator, base32 encoded as apps expect
+pub fn new_secret() -> String {
+    let mut bytes = [0; 20];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+/// URI for adding the secret to an authenticator app
+pub fn otpauth_uri(secret: &str, account: &str) -> String {
+    let label = url_encode_segment(&format!("{ISSUER}:{account}"));
+    format!("otpauth://totp/{label}?secret={secret}&issuer={ISSUER}&digits={DIGITS}&period={STEP}")
+}
+
+/// The URI as a QR code to scan, as an SVG image
+pub fn qr_code(uri: &str) -> Result<String> {
+    let code = QrCode::new(uri.as_bytes())?;
+    Ok(code.render::<svg::Color>().min_dimensions(200, 200).build())
+}
+
+/// The time step `code` is valid for, if any
+fn check_code(secret: &[u8], code: &str, now: SystemTime) -> Option<u64> {
+    let code: u32 = code.trim().parse().ok()?;
+    let now = now.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs() / STEP;
+    (now.saturating_sub(SKEW)..=now + SKEW).find(|&step| hotp(secret, step) == code)
+}
+
+/// RFC 4226
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let bytes: [u8; 4] = hash[offset..offset + 4].try_into().unwrap();
+    (u32::from_be_bytes(bytes) & 0x7fff_ffff) % 10u32.pow(DIGITS)
+}
+
+/// Recovery codes are random, so a fast hash is enough. Dashes and case don't matter
+fn hash_recovery(code: &str) -> [u8; 32] {
+    let code: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    Sha256::digest(code.as_bytes()).into()
+}
+
+struct Challenge {
+    user: Uuid,
+    user_agent: Option<String>,
+    started: Instant,
+    failures: usize,
+}
+
+/// Logins waiting for their second factor, after the password was right
+#[derive(Default)]
+pub struct LoginChallenges {
+    pending: Mutex<HashMap<String, Challenge>>,
+}
+
+impl LoginChallenges {
+    /// Returns the id of the new challenge, which the login form sends back with the code
+    pub fn start(&self, user: Uuid, user_agent: Option<String>) -> String {
+        let mut bytes = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        let id = URL_SAFE_NO_PAD.encode(bytes);
+        let challenge = Challenge {
+            user,
+            user_agent,
+            started: Instant::now(),
+            failures: 0,
+        };
+        self.pending.lock().unwrap().insert(id.clone(), challenge);
+        id
+    }
+
+    /// The user logging in and their user agent, unless the challenge expired
+    pub fn get(&self, id: &str) -> Option<(Uuid, Option<String>)> {
+        let pending = self.pending.lock().unwrap();
+        let challenge = pending
+            .get(id)
+            .filter(|c| c.started.elapsed() < CHALLENGE_LIFETIME)?;
+        Some((challenge.user, challenge.user_agent.clone()))
+    }
+
+    /// Counts a wrong code. Too many end the challenge
+    pub fn fail(&self, id: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(challenge) = pending.get_mut(id) {
+            challenge.failures += 1;
+            if challenge.failures >= CHALLENGE_ATTEMPTS {
+                pending.remove(id);
+            }
+        }
+    }
+
+    pub fn finish(&self, id: &str) {
+        self.pending.lock().unwrap().remove(id);
+    }
+
+    /// Forgets expired challenges
+    pub fn sweep(&self) {
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|_, c| c.started.elapsed() < CHALLENGE_LIFETIME);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rfc_6238() {
+        let secret = b"12345678901234567890";
+        // The RFC's 8 digit codes cut to 6
+        for (time, code) in [(59, 287082), (1111111109, 81804), (2000000000, 279037)] {
+            assert_eq!(hotp(secret, time / STEP), code);
+        }
+    }
+
+    #[test]
+    fn codes_are_single_use() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(59);
+        let mut two_factor = TwoFactor {
+            secret: b"12345678901234567890".to_vec(),
+            recovery: vec![],
+            last_step: 0,
+            enabled: now,
+        };
+        let codes = two_factor.new_recovery_codes();
+        assert!(two_factor.verify("287082", now));
+        assert!(!two_factor.verify("287082", now));
+
+        assert!(two_factor.verify(&codes[3].to_uppercase(), now));
+        assert!(!two_factor.verify(&codes[3], now));
+        assert_eq!(two_factor.recovery_left(), RECOVERY_CODES - 1);
+    }
+}
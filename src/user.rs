@@ -7,124 +7,749 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
+    config::Config,
     db::{Backend, Table},
+    entry::WritingMode,
+    mail_link::MailLinkStore,
+    password::{PasswordHasher, Verification},
+    session::SessionStore,
+    shelf::Shelf,
+    token::TokenStore,
+    two_factor::{LoginChallenges, TwoFactor},
     utils::{decode_bincode, encode_bincode},
+    validate,
 };
 
-const USER_TABLE: &'static str = "USERS";
-pub const SID_COOKIE: &'static str = "session_id";
+/// Keyed by user id
+const USER_TABLE: &str = "USERS";
+/// Index of the current names, keyed by [`name_key`]
+const USER_NAME_TABLE: &str = "USER_NAMES";
+/// Index of confirmed email addresses, keyed by [`email_key`]
+const USER_EMAIL_TABLE: &str = "USER_EMAILS";
+pub const SID_COOKIE: &str = "session_id";
 
-#[derive(Serialize, Encode, Decode)]
+/// A creator of a work. Creators with an account are resolved by `id`; imported works can credit
+/// people without one
+#[derive(Clone, Serialize, Encode, Decode)]
 pub struct UserRef {
+    #[bincode(with_serde)]
+    pub id: Option<Uuid>,
+    /// Name at the time the work was credited. Only shown for creators without an account
     pub name: String,
     pub created: SystemTime,
 }
 
+impl UserRef {
+    /// Credits someone who doesn't have an account
+    pub fn external(name: String) -> Self {
+        Self {
+            id: None,
+            name,
+            created: SystemTime::now(),
+        }
+    }
+}
+
 #[derive(Encode, Decode)]
 pub struct UserData {
+    /// Name used to sign in and shown on works
+    pub name: String,
+    /// Names used before renames, oldest first
+    pub previous_names: Vec<PreviousName>,
     #[bincode(with_serde)]
     pub created: DateTime<Utc>,
-    // TODO: Use argon2
+    /// PHC string of the argon2id hash. Accounts from before hashing hold the plaintext password
+    /// until their next login
     pub pswd: String,
 
-    // TODO: Expire after time and bound it to e.g. 100
-    #[bincode(with_serde)]
-    pub sessions: Vec<Uuid>,
-
     // Books in user library
     #[bincode(with_serde)]
     pub lib: UserLibrary,
+
+    pub settings: ReaderSettings,
+
+    pub role: Role,
+
+    pub two_factor: Option<TwoFactor>,
+
+    /// Confirmed address, which password reset links go to
+    pub email: Option<String>,
+    /// Address waiting for the user to open the link sent to it
+    pub unverified_email: Option<String>,
+}
+
+/// What a user is allowed to do. Every role can do everything the ones before it can
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    Encode,
+    Decode,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Reads and keeps a library
+    #[default]
+    Reader,
+    /// Writes and imports works
+    Author,
+    /// Looks after other people's content
+    Moderator,
+    /// Manages users
+    Admin,
+}
+
+/// Something only some roles may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Create, import and edit one's own works
+    WriteWorks,
+    /// Move works to and from the trash
+    ManageTrash,
+    /// Hide comments, lock comment threads and ban users from commenting
+    Moderate,
+    /// Change the roles of users
+    ManageUsers,
 }
 
-#[derive(Default, Serialize, Deserialize)]
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Reader, Role::Author, Role::Moderator, Role::Admin];
+
+    pub fn can(self, capability: Capability) -> bool {
+        let needed = match capability {
+            Capability::WriteWorks => Role::Author,
+            Capability::ManageTrash | Capability::Moderate => Role::Moderator,
+            Capability::ManageUsers => Role::Admin,
+        };
+        self >= needed
+    }
+}
+
+#[derive(Clone, Serialize, Encode, Decode)]
+pub struct PreviousName {
+    pub name: String,
+    /// When the user stopped using it
+    #[bincode(with_serde)]
+    pub until: DateTime<Utc>,
+}
+
+/// Per-reader display preferences
+#[derive(Default, Clone, Serialize, Encode, Decode)]
+pub struct ReaderSettings {
+    /// Overrides the writing mode of every work. `None` follows the work's default
+    pub writing_mode: Option<WritingMode>,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct UserLibrary {
     /// Works owned/bought by the user
     pub works: Vec<Uuid>,
     /// Works currently being read and have associated data (current chapter, etc.). Not necessarily owned books
     /// e.g. trial or free books
     pub active: Vec<ActiveWork>,
+    /// The reading status shelves first, then the user's own
+    pub shelves: Vec<Shelf>,
+}
+
+impl Default for UserLibrary {
+    fn default() -> Self {
+        Self {
+            works: vec![],
+            active: vec![],
+            shelves: Shelf::defaults(),
+        }
+    }
+}
+
+impl UserLibrary {
+    /// Reading progress in a work, if the user has opened it
+    pub fn progress(&self, work: Uuid) -> Option<&ActiveWork> {
+        self.active.iter().find(|aw| aw.id == work)
+    }
+
+    /// Works being read, most recently read first
+    pub fn recently_read(&self) -> Vec<&ActiveWork> {
+        let mut active: Vec<_> = self.active.iter().collect();
+        active.sort_by_key(|aw| std::cmp::Reverse(aw.updated));
+        active
+    }
+
+    /// Records that `chapter` was opened and marks it read. Reopening the last chapter keeps the
+    /// position so the reader can pick up where they left off
+    pub fn open_chapter(&mut self, work: Uuid, chapter: Uuid) {
+        let aw = self.active_mut(work);
+        if aw.last_chapter != Some(chapter) {
+            aw.last_chapter = Some(chapter);
+            aw.position = 0.0;
+        }
+        if !aw.read_chapters.contains(&chapter) {
+            aw.read_chapters.push(chapter);
+        }
+    }
+
+    /// Saves how far into `chapter` the reader scrolled, from 0 to 1
+    pub fn set_position(&mut self, work: Uuid, chapter: Uuid, position: f32) {
+        let aw = self.active_mut(work);
+        aw.last_chapter = Some(chapter);
+        aw.position = if position.is_finite() {
+            position.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+    }
+
+    pub fn set_read(&mut self, work: Uuid, chapter: Uuid, read: bool) {
+        let aw = self.active_mut(work);
+        aw.read_chapters.retain(|&c| c != chapter);
+        if read {
+            aw.read_chapters.push(chapter);
+        }
+    }
+
+    /// Remembers that every chapter of `work` was read. False if it already was once before
+    pub fn mark_completed(&mut self, work: Uuid) -> bool {
+        let aw = self.active_mut(work);
+        !std::mem::replace(&mut aw.completed, true)
+    }
+
+    /// Progress in `work`, starting it if needed. Counts as reading it now
+    fn active_mut(&mut self, work: Uuid) -> &mut ActiveWork {
+        let i = match self.active.iter().position(|aw| aw.id == work) {
+            Some(i) => i,
+            None => {
+                self.active.push(ActiveWork {
+                    id: work,
+                    read_chapters: vec![],
+                    last_chapter: None,
+                    position: 0.0,
+                    updated: Utc::now(),
+                    completed: false,
+                });
+                self.active.len() - 1
+            }
+        };
+        let aw = &mut self.active[i];
+        aw.updated = Utc::now();
+        aw
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ActiveWork {
     pub id: Uuid,
     pub read_chapters: Vec<Uuid>,
+    /// Chapter opened most recently
+    pub last_chapter: Option<Uuid>,
+    /// How far into `last_chapter` the reader scrolled, from 0 to 1
+    pub position: f32,
+    pub updated: DateTime<Utc>,
+    /// Set the first time every chapter is read, so finishing again isn't another completion
+    pub completed: bool,
 }
 
 pub struct MemberCollection<B: Backend> {
     users: <B as Backend>::OutTable,
+    names: <B as Backend>::OutTable,
+    emails: <B as Backend>::OutTable,
+    hasher: PasswordHasher,
+    /// Role given to new accounts
+    default_role: Role,
+    /// Lowest role that has to use two-factor login
+    two_factor_role: Role,
+    /// New accounts can't log in until they confirm their email address
+    verify_signups: bool,
+    pub sessions: SessionStore<B>,
+    pub tokens: TokenStore<B>,
+    pub challenges: LoginChallenges,
+    pub links: MailLinkStore<B>,
 }
 
 impl<B: Backend> MemberCollection<B> {
-    pub fn new(db: &B) -> Result<Self> {
+    pub fn new(db: &B, config: &Config) -> Result<Self> {
         let users = db.get_table(USER_TABLE)?;
-        Ok(Self { users })
+        let names = db.get_table(USER_NAME_TABLE)?;
+        let emails = db.get_table(USER_EMAIL_TABLE)?;
+        let hasher = PasswordHasher::new(&config.password)?;
+        let sessions = SessionStore::new(db, &config.session)?;
+        let tokens = TokenStore::new(db)?;
+        Ok(Self {
+            users,
+            names,
+            emails,
+            hasher,
+            default_role: config.accounts.default_role,
+            two_factor_role: config.accounts.two_factor_role,
+            verify_signups: config.mail.verify_signups,
+            sessions,
+            tokens,
+            challenges: LoginChallenges::default(),
+            links: MailLinkStore::new(db)?,
+        })
     }
 
-    /// `name` and `pswd` are base64 encoded
-    /// Returns session
-    pub fn try_create_user(&self, name: String, pswd: String) -> Result<Uuid> {
-        if self.users.get_value(&name).is_some() {
-            bail!("User already exists");
-        } else {
-            let session = Uuid::now_v7();
-            let user = UserData {
-                created: Utc::now(),
-                pswd,
-                sessions: vec![session.clone()],
-                lib: UserLibrary::default(),
-            };
-            self.users.insert(name, encode_bincode(&user)?);
-            Ok(session)
+    /// Signs up a user. They get a session unless they have to confirm their email address
+    pub fn try_create_user(
+        &self,
+        name: String,
+        pswd: String,
+        email: Option<String>,
+        user_agent: Option<String>,
+    ) -> Result<Login> {
+        if self.verify_signups && email.is_none() {
+            bail!("An email address is needed to sign up");
+        }
+        let email = email.map(|e| self.check_email(&e, None)).transpose()?;
+        let id = self.create_user(name, pswd, self.default_role)?;
+        if let Some(email) = email {
+            self.update_user(id, |user| user.unverified_email = Some(email.clone()))?;
+            if self.verify_signups {
+                return Ok(Login::Unverified(id));
+            }
+        }
+        Ok(Login::Session(self.sessions.create(id, user_agent)?))
+    }
+
+    /// Returns the id of the new user
+    pub fn create_user(&self, name: String, pswd: String, role: Role) -> Result<Uuid> {
+        let name = validate::name(&name)?;
+        validate::password(&pswd, &name)?;
+        let id = Uuid::now_v7();
+        let user = UserData {
+            name: name.clone(),
+            previous_names: vec![],
+            created: Utc::now(),
+            pswd: self.hasher.hash(&pswd)?,
+            lib: UserLibrary::default(),
+            settings: ReaderSettings::default(),
+            role,
+            two_factor: None,
+            email: None,
+            unverified_email: None,
+        };
+        let data = encode_bincode(&user)?;
+        if !self.reserve_name(&name, id) {
+            bail!("That name is taken or too similar to an existing one");
+        }
+        self.users.insert(id, data);
+        Ok(id)
+    }
+
+    /// Checks the password. Users with two-factor login get a challenge to answer with
+    /// [`Self::login_second_factor`] instead of a session
+    pub fn login(&self, name: String, pswd: String, user_agent: Option<String>) -> Result<Login> {
+        let Some(id) = self.find_by_name(&name) else {
+            // Takes as long as a wrong password so names can't be probed
+            self.hasher.hash(&pswd)?;
+            bail!("Wrong name or password");
+        };
+        let user = self.get_user(id)?;
+
+        match self.hasher.verify(&user.pswd, &pswd) {
+            Verification::Valid => {}
+            Verification::ValidNeedsRehash => {
+                let hash = self.hasher.hash(&pswd)?;
+                self.update_user(id, |user| user.pswd = hash.clone())?;
+            }
+            Verification::Invalid => bail!("Wrong name or password"),
+        }
+        if self.verify_signups && user.email.is_none() && user.unverified_email.is_some() {
+            return Ok(Login::Unverified(id));
+        }
+        if user.two_factor.is_some() {
+            return Ok(Login::SecondFactor(self.challenges.start(id, user_agent)));
+        }
+        Ok(Login::Session(self.sessions.create(id, user_agent)?))
+    }
+
+    /// Checks the code from the authenticator app or a recovery code. Returns the session
+    pub fn login_second_factor(&self, challenge: &str, code: &str) -> Result<Uuid> {
+        let Some((id, user_agent)) = self.challenges.get(challenge) else {
+            bail!("The login took too long. Sign in again");
+        };
+        let valid = self.update_user(id, |user| {
+            let now = SystemTime::now();
+            user.two_factor
+                .as_mut()
+                .is_some_and(|two_factor| two_factor.verify(code, now))
+        })?;
+        if !valid {
+            self.challenges.fail(challenge);
+            bail!("Wrong code");
+        }
+        self.challenges.finish(challenge);
+        self.sessions.create(id, user_agent)
+    }
+
+    /// The user who started a login challenge
+    pub fn challenge_user(&self, challenge: &str) -> Option<Uuid> {
+        self.challenges.get(challenge).map(|(id, _)| id)
+    }
+
+    /// Whether the user's role requires two-factor login and they haven't set it up
+    pub fn needs_two_factor(&self, id: Uuid) -> Result<bool> {
+        let user = self.get_user(id)?;
+        Ok(user.role >= self.two_factor_role && user.two_factor.is_none())
+    }
+
+    pub fn requires_two_factor(&self, role: Role) -> bool {
+        role >= self.two_factor_role
+    }
+
+    pub fn get_two_factor(&self, id: Uuid) -> Result<Option<TwoFactor>> {
+        Ok(self.get_user(id)?.two_factor)
+    }
+
+    pub fn set_two_factor(&self, id: Uuid, two_factor: Option<TwoFactor>) -> Result<()> {
+        self.update_user(id, |user| user.two_factor = two_factor.clone())
+    }
+
+    /// Replaces the recovery codes of a user with two-factor login, returning the new ones
+    pub fn new_recovery_codes(&self, id: Uuid) -> Result<Vec<String>> {
+        let codes = self.update_user(id, |user| {
+            user.two_factor.as_mut().map(TwoFactor::new_recovery_codes)
+        })?;
+        match codes {
+            Some(codes) => Ok(codes),
+            None => bail!("Two-factor login isn't on"),
+        }
+    }
+
+    /// Checks the password of a signed in user before something that can't be undone
+    pub fn check_password(&self, id: Uuid, pswd: &str) -> Result<()> {
+        let user = self.get_user(id)?;
+        if self.hasher.verify(&user.pswd, pswd) == Verification::Invalid {
+            bail!("Wrong password");
+        }
+        Ok(())
+    }
+
+    /// Removes the account, its name, its sessions, its API tokens and its mail links
+    pub fn delete_user(&self, id: Uuid) -> Result<()> {
+        let user = self.get_user(id)?;
+        self.sessions.revoke_all(id);
+        self.tokens.revoke_all(id);
+        self.links.remove_user(id);
+        if let Some(email) = user.email {
+            self.emails.remove(email_key(&email));
         }
+        self.names.remove(name_key(&user.name));
+        self.users.remove(id);
+        Ok(())
     }
 
-    pub fn login(&self, name: String, pswd: String) -> Result<Uuid> {
-        let Some(data) = self.users.get_value(&name) else {
+    /// Id of the user signed in with `sid`
+    pub fn get_user_for_sid(&self, sid: Uuid) -> Option<Uuid> {
+        self.sessions.user_for(sid)
+    }
+
+    /// Claims `name` for `id` unless it, or one that looks the same, is taken
+    fn reserve_name(&self, name: &str, id: Uuid) -> bool {
+        self.names
+            .compare_and_swap(name_key(name), None, Some(id.as_bytes()))
+    }
+
+    /// Id of the user currently called `name`
+    pub fn find_by_name(&self, name: &str) -> Option<Uuid> {
+        let id = self.names.get_value(name_key(name))?;
+        Uuid::from_slice(id.as_ref()).ok()
+    }
+
+    pub fn get_user(&self, id: Uuid) -> Result<UserData> {
+        let Some(data) = self.users.get_value(id) else {
             bail!("User doesn't exist!");
         };
-        let user: UserData = decode_bincode(data.as_ref())?;
+        decode_bincode(data.as_ref())
+    }
 
-        // Borrow checker isn't smart enough
-        std::mem::drop(data);
+    /// Current name of a user
+    pub fn get_name(&self, id: Uuid) -> Result<String> {
+        Ok(self.get_user(id)?.name)
+    }
 
-        if user.pswd == pswd {
-            Ok(self.generate_session(name)?)
-        } else {
-            bail!("Wrong password!")
+    /// Name to show for a creator: the current one if they have an account
+    pub fn creator_name(&self, creator: &UserRef) -> String {
+        creator
+            .id
+            .and_then(|id| self.get_name(id).ok())
+            .unwrap_or_else(|| creator.name.clone())
+    }
+
+    /// Changes a user's name, keeping the old one in their history. The old name becomes free
+    pub fn rename(&self, id: Uuid, new_name: String) -> Result<()> {
+        let new_name = validate::name(&new_name)?;
+        let old_name = self.get_name(id)?;
+        let new_key = name_key(&new_name) != name_key(&old_name);
+        if new_key && !self.reserve_name(&new_name, id) {
+            bail!("That name is taken or too similar to an existing one");
+        }
+        let renamed = self.update_user(id, |user| {
+            let old = std::mem::replace(&mut user.name, new_name.clone());
+            user.previous_names.push(PreviousName {
+                name: old,
+                until: Utc::now(),
+            });
+        });
+        if new_key {
+            // Frees whichever name isn't in use
+            match renamed {
+                Ok(()) => self.names.remove(name_key(&old_name)),
+                Err(_) => self.names.remove(name_key(&new_name)),
+            };
+        }
+        renamed
+    }
+
+    /// Id of the user who confirmed `email`
+    pub fn find_by_email(&self, email: &str) -> Option<Uuid> {
+        let id = self.emails.get_value(email_key(email))?;
+        Uuid::from_slice(id.as_ref()).ok()
+    }
+
+    /// Sets the address to confirm. The confirmed one is kept until then
+    pub fn set_email(&self, id: Uuid, email: &str) -> Result<String> {
+        let email = self.check_email(email, Some(id))?;
+        self.update_user(id, |user| user.unverified_email = Some(email.clone()))?;
+        Ok(email)
+    }
+
+    /// Confirms `email` if it is still the one the user wants
+    pub fn verify_email(&self, id: Uuid, email: &str) -> Result<()> {
+        let user = self.get_user(id)?;
+        if user.unverified_email.as_deref() != Some(email) {
+            bail!("That address was changed since the link was sent");
+        }
+        let email = self.check_email(email, Some(id))?;
+        if let Some(old) = &user.email {
+            self.emails.remove(email_key(old));
+        }
+        self.emails.insert(email_key(&email), id);
+        self.update_user(id, |user| {
+            user.email = Some(email.clone());
+            user.unverified_email = None;
+        })
+    }
+
+    /// Sets a new password, signs the user out everywhere and revokes their API tokens
+    pub fn reset_password(&self, id: Uuid, pswd: &str) -> Result<()> {
+        let name = self.get_name(id)?;
+        validate::password(pswd, &name)?;
+        let hash = self.hasher.hash(pswd)?;
+        self.update_user(id, |user| user.pswd = hash.clone())?;
+        self.sessions.revoke_all(id);
+        self.tokens.revoke_all(id);
+        Ok(())
+    }
+
+    /// Checks a new address, which `user` may already have confirmed
+    fn check_email(&self, email: &str, user: Option<Uuid>) -> Result<String> {
+        let email = validate::email(email)?;
+        if self
+            .find_by_email(&email)
+            .is_some_and(|owner| Some(owner) != user)
+        {
+            bail!("That email address belongs to another account");
         }
+        Ok(email)
+    }
+
+    /// Every user, sorted by name
+    pub fn all_users(&self) -> Vec<(Uuid, UserData)> {
+        let mut users: Vec<_> = self
+            .users
+            .iter()
+            .filter_map(|(key, value)| {
+                let id = Uuid::from_slice(key.as_ref()).ok()?;
+                let user: UserData = decode_bincode(value.as_ref()).ok()?;
+                Some((id, user))
+            })
+            .collect();
+        users.sort_by_key(|(_, user)| name_key(&user.name));
+        users
+    }
+
+    pub fn get_role(&self, id: Uuid) -> Result<Role> {
+        Ok(self.get_user(id)?.role)
+    }
+
+    pub fn set_role(&self, id: Uuid, role: Role) -> Result<()> {
+        self.update_user(id, |user| user.role = role)
+    }
+
+    pub fn get_library(&self, id: Uuid) -> Result<UserLibrary> {
+        Ok(self.get_user(id)?.lib)
     }
 
-    fn generate_session(&self, name: String) -> Result<Uuid> {
-        let session = Uuid::now_v7();
-        let data = self.users.get_value(name.as_str()).unwrap();
-        let mut user: UserData = decode_bincode(data.as_ref())?;
+    /// Decodes the user's library, applies `f` and writes it back
+    pub fn update_library<T>(
+        &self,
+        id: Uuid,
+        mut f: impl FnMut(&mut UserLibrary) -> T,
+    ) -> Result<T> {
+        self.update_user(id, |user| f(&mut user.lib))
+    }
 
-        // Borrow checker isn't smart enough
-        std::mem::drop(data);
+    pub fn get_settings(&self, id: Uuid) -> Result<ReaderSettings> {
+        Ok(self.get_user(id)?.settings)
+    }
 
-        user.sessions.push(session.clone());
-        self.users.insert(name, encode_bincode(&user)?);
-        Ok(session)
+    pub fn set_settings(&self, id: Uuid, settings: ReaderSettings) -> Result<()> {
+        self.update_user(id, |user| user.settings = settings.clone())
     }
 
-    pub fn get_user_for_sid(&self, sid: Uuid) -> Option<String> {
-        let row = self.users.iter().find(|(_, data)| {
-            let UserData { sessions, .. } = decode_bincode(data.as_ref()).unwrap();
-            if sessions.contains(&sid) {
-                true
-            } else {
-                false
+    /// Decodes the user, applies `f` and writes it back. If the user changed in the meantime, `f`
+    /// is applied again to the new data, so checks made in `f` hold when it is written
+    fn update_user<T>(&self, id: Uuid, mut f: impl FnMut(&mut UserData) -> T) -> Result<T> {
+        loop {
+            let Some(old) = self.users.get_value(id) else {
+                bail!("User doesn't exist!");
+            };
+            let old = old.as_ref();
+            let mut user = decode_bincode(old)?;
+            let res = f(&mut user);
+            let new = encode_bincode(&user)?;
+            if self.users.compare_and_swap(id, Some(old), Some(&new)) {
+                return Ok(res);
             }
+        }
+    }
+}
+
+pub enum Login {
+    Session(Uuid),
+    /// The password was right, the challenge id has to be sent back with a code
+    SecondFactor(String),
+    /// The user has to confirm their email address first
+    Unverified(Uuid),
+}
+
+/// Addresses are unique regardless of case
+fn email_key(email: &str) -> String {
+    email.to_lowercase()
+}
+
+/// Names are unique regardless of case and characters that look alike
+fn name_key(name: &str) -> String {
+    validate::skeleton(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn members() -> MemberCollection<sled::Db> {
+        let mut config = Config::default();
+        config.password.memory_cost = 8;
+        config.password.time_cost = 1;
+        MemberCollection::new(&crate::db::temporary(), &config).unwrap()
+    }
+
+    #[test]
+    fn roles() {
+        assert_eq!(Config::default().accounts.default_role, Role::Reader);
+        assert!(!Role::Reader.can(Capability::WriteWorks));
+        assert!(Role::Author.can(Capability::WriteWorks));
+        assert!(!Role::Author.can(Capability::Moderate));
+        assert!(Role::Moderator.can(Capability::ManageTrash));
+        assert!(!Role::Moderator.can(Capability::ManageUsers));
+        for capability in [
+            Capability::WriteWorks,
+            Capability::ManageTrash,
+            Capability::Moderate,
+            Capability::ManageUsers,
+        ] {
+            assert!(Role::Admin.can(capability));
+        }
+    }
+
+    #[test]
+    fn names_are_reserved() {
+        let members = members();
+        let pswd = || "correct horse".to_string();
+        let alice = members
+            .create_user("Alice".into(), pswd(), Role::Reader)
+            .unwrap();
+        assert!(members
+            .create_user("ALlCE".into(), pswd(), Role::Reader)
+            .is_err());
+        assert!(members
+            .create_user("Ａｌｉｃｅ".into(), pswd(), Role::Reader)
+            .is_err());
+        assert_eq!(members.find_by_name("alice"), Some(alice));
+
+        // Only one of the users signing up with the same name at once gets it
+        let created = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| s.spawn(|| members.create_user("bob".into(), pswd(), Role::Reader)))
+                .collect();
+            threads
+                .into_iter()
+                .filter_map(|t| t.join().unwrap().ok())
+                .collect::<Vec<_>>()
         });
-        row.map(|(name, _)| std::str::from_utf8(name.as_ref()).unwrap().to_string())
+        assert_eq!(created.len(), 1);
+        assert_eq!(members.find_by_name("bob"), Some(created[0]));
     }
 
-    pub fn get_library(&self, name: &str) -> Result<UserLibrary> {
-        let Some(data) = self.users.get_value(&name) else {
-            bail!("User doesn't exist!");
-        };
-        let user: UserData = decode_bincode(data.as_ref())?;
-        Ok(user.lib)
+    #[test]
+    fn renames() {
+        let members = members();
+        let pswd = || "correct horse".to_string();
+        let alice = members
+            .create_user("alice".into(), pswd(), Role::Reader)
+            .unwrap();
+        let bob = members
+            .create_user("bob".into(), pswd(), Role::Reader)
+            .unwrap();
+        assert!(members.rename(bob, "ALICE".into()).is_err());
+        assert_eq!(members.get_name(bob).unwrap(), "bob");
+
+        // Changing the case keeps the name reserved
+        members.rename(alice, "Alice".into()).unwrap();
+        assert_eq!(members.find_by_name("alice"), Some(alice));
+        members.rename(alice, "alicia".into()).unwrap();
+        assert_eq!(members.find_by_name("alice"), None);
+        assert_eq!(members.find_by_name("alicia"), Some(alice));
+        let user = members.get_user(alice).unwrap();
+        let previous: Vec<_> = user.previous_names.iter().map(|n| &n.name).collect();
+        assert_eq!(previous, ["alice", "Alice"]);
+
+        // The old name is free again
+        members.rename(bob, "alice".into()).unwrap();
+        assert_eq!(members.find_by_name("Alice"), Some(bob));
+    }
+
+    #[test]
+    fn progress() {
+        let (work, first, second) = (Uuid::now_v7(), Uuid::now_v7(), Uuid::now_v7());
+        let mut lib = UserLibrary::default();
+        lib.open_chapter(work, first);
+        lib.set_position(work, first, 0.4);
+        // Reopening keeps the position, moving on resets it
+        lib.open_chapter(work, first);
+        assert_eq!(lib.progress(work).unwrap().position, 0.4);
+        lib.open_chapter(work, second);
+        let aw = lib.progress(work).unwrap();
+        assert_eq!((aw.last_chapter, aw.position), (Some(second), 0.0));
+        assert_eq!(aw.read_chapters, [first, second]);
+
+        lib.set_read(work, first, false);
+        lib.set_position(work, second, f32::NAN);
+        let aw = lib.progress(work).unwrap();
+        assert_eq!(
+            (aw.read_chapters.as_slice(), aw.position),
+            ([second].as_slice(), 0.0)
+        );
+
+        let other = Uuid::now_v7();
+        lib.open_chapter(other, first);
+        lib.active[0].updated = DateTime::UNIX_EPOCH;
+        assert_eq!(lib.recently_read()[0].id, other);
     }
 }
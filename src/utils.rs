@@ -21,3 +21,30 @@ pub fn b64_decode_uuid(s: &str) -> Vec<u8> {
 pub fn b64_encode_uuid(bytes: &[u8]) -> String {
     URL_SAFE_NO_PAD.encode(bytes)
 }
+
+/// Guesses the media type of an image from its magic bytes
+pub fn image_mime(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Percent-encodes a single path segment (e.g. a title) for use in a URL or header
+pub fn url_encode_segment(s: &str) -> String {
+    url::form_urlencoded::byte_serialize(s.as_bytes())
+        .collect::<String>()
+        .replace('+', "%20")
+}
+
+/// Absolute path of a work's page
+pub fn work_path(title: &str, id: uuid::Uuid) -> String {
+    format!(
+        "/works/{}/{}",
+        url_encode_segment(title),
+        b64_encode_uuid(id.as_bytes())
+    )
+}
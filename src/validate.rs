@@ -0,0 +1,147 @@
+//! Rules for user names, passwords and email addresses
+
+use anyhow::{bail, Result};
+use unicode_normalization::UnicodeNormalization;
+
+pub const NAME_MAX_LEN: usize = 32;
+pub const PASSWORD_MIN_LEN: usize = 8;
+
+/// Passwords that are too common to allow, whatever their length
+const COMMON_PASSWORDS: &[&str] = &[
+    "password",
+    "password1",
+    "12345678",
+    "123456789",
+    "1234567890",
+    "qwertyuiop",
+    "iloveyou",
+    "sunshine",
+    "princess",
+    "football",
+    "baseball",
+    "welcome1",
+    "abc12345",
+    "11111111",
+    "00000000",
+    "letmein1",
+    "trustno1",
+];
+
+/// Checks a new name, returning it trimmed. Letters and digits of any script are allowed, as
+/// well as `_`, `-` and `.` between them
+pub fn name(name: &str) -> Result<String> {
+    let name: String = name.trim().nfc().collect();
+    let len = name.chars().count();
+    if len == 0 {
+        bail!("The name can't be empty");
+    }
+    if len > NAME_MAX_LEN {
+        bail!("The name can't be longer than {NAME_MAX_LEN} characters");
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|&c| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')))
+    {
+        bail!("The name can't contain {c:?}");
+    }
+    let is_symbol = |c: Option<char>| c.is_some_and(|c| !c.is_alphanumeric());
+    if is_symbol(name.chars().next()) || is_symbol(name.chars().last()) {
+        bail!("The name must start and end with a letter or digit");
+    }
+    Ok(name)
+}
+
+/// Checks an email address, returning it trimmed
+pub fn email(email: &str) -> Result<String> {
+    let email = email.trim();
+    if email.parse::<lettre::Address>().is_err() {
+        bail!("{email} isn't an email address");
+    }
+    Ok(email.to_string())
+}
+
+/// Checks the strength of a new password
+pub fn password(pswd: &str, name: &str) -> Result<()> {
+    if pswd.chars().count() < PASSWORD_MIN_LEN {
+        bail!("The password must be at least {PASSWORD_MIN_LEN} characters long");
+    }
+    let lower = pswd.to_lowercase();
+    if lower.contains(&name.to_lowercase()) {
+        bail!("The password can't contain the name");
+    }
+    if COMMON_PASSWORDS.contains(&lower.as_str()) {
+        bail!("That password is too common");
+    }
+    let mut chars = pswd.chars();
+    let first = chars.next();
+    if chars.all(|c| Some(c) == first) {
+        bail!("The password can't be a single repeated character");
+    }
+    Ok(())
+}
+
+/// A form of the name that is the same for names which look alike, e.g. `Alice`, `ALICE`,
+/// `Αlice` (with a Greek alpha) and `Ａｌｉｃｅ`. Two users can't have the same skeleton
+pub fn skeleton(name: &str) -> String {
+    name.trim()
+        .nfkc()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            // Latin look-alikes
+            '0' => 'o',
+            '1' | 'i' | '|' => 'l',
+            '5' => 's',
+            // Cyrillic
+            'а' => 'a',
+            'в' => 'b',
+            'е' | 'ё' => 'e',
+            'к' => 'k',
+            'м' => 'm',
+            'н' => 'h',
+            'о' => 'o',
+            'р' => 'p',
+            'с' => 'c',
+            'т' => 't',
+            'у' => 'y',
+            'х' => 'x',
+            'і' => 'l',
+            // Greek
+            'α' => 'a',
+            'β' => 'b',
+            'ε' => 'e',
+            'η' => 'n',
+            'ι' => 'l',
+            'κ' => 'k',
+            'ν' => 'v',
+            'ο' => 'o',
+            'ρ' => 'p',
+            'τ' => 't',
+            'υ' => 'u',
+            'χ' => 'x',
+            // Separators are easy to miss
+            '-' | '.' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn confusables() {
+        assert_eq!(skeleton("Alice"), skeleton("ALlCE"));
+        assert_eq!(skeleton("alice"), skeleton("Αlice"));
+        assert_eq!(skeleton("alice"), skeleton("Ａｌｉｃｅ"));
+        assert_eq!(skeleton("a.b"), skeleton("a_b"));
+        assert_ne!(skeleton("alice"), skeleton("alicia"));
+
+        assert!(name(" 太郎_2 ").is_ok_and(|n| n == "太郎_2"));
+        assert!(name("_bob").is_err());
+        assert!(name("bob smith").is_err());
+        assert!(password("aaaaaaaaa", "bob").is_err());
+        assert!(password("bob12345", "bob").is_err());
+        assert!(password("correct horse", "bob").is_ok());
+    }
+}
@@ -1,4 +1,4 @@
-mod source;
+pub mod source;
 
 // List of sources
 mod syosetu;
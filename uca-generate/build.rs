@@ -26,7 +26,7 @@ impl<R: Read> Parser<R> {
                 Kind::Version(_) => {}
                 Kind::ImplicitWeight(weight) => self.implicit_weights.push(weight),
                 Kind::Entry(entry) => self.entries.push(entry),
-                Kind::EOF => break,
+                Kind::Eof => break,
             }
         }
         self.implicit_weights
@@ -50,6 +50,7 @@ impl Table {
         let mut f = std::fs::OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(p)?;
         let max_size = self
             .entries
@@ -83,7 +84,6 @@ impl Table {
                             .collect::<String>(),
                         e.collate_elements
                             .iter()
-                            .cloned()
                             .map(|a| format!("[{}, {}, {}],", a[0], a[1], a[2]))
                             .collect::<String>()
                     )
@@ -27,7 +27,7 @@ pub fn lookup(s: &[u32]) -> Option<Vec<CollateElement>> {
     ENTRIES
         .binary_search_by(|entry| (*entry.0).cmp(s))
         .ok()
-        .map(|idx| ENTRIES[idx].1.iter().map(|ce| *ce).collect())
+        .map(|idx| ENTRIES[idx].1.to_vec())
         .or_else(|| {
             if s.len() == 1 {
                 Some(derive_weight(s[0]))
@@ -3,8 +3,8 @@ use std::{
     ops::Range,
 };
 
-const VERSION: &'static str = "@version";
-const IMPLICIT_WEIGHTS: &'static str = "@implicitweights";
+const VERSION: &str = "@version";
+const IMPLICIT_WEIGHTS: &str = "@implicitweights";
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -35,7 +35,7 @@ pub enum Kind {
     Version(Version),
     ImplicitWeight(ImplicitWeight),
     Entry(Entry),
-    EOF,
+    Eof,
 }
 
 pub struct LineParser<R: Read>(BufReader<R>);
@@ -49,10 +49,7 @@ impl<R: Read> LineParser<R> {
         }
 
         // Remove comments
-        if let Some(trimmed) = s
-            .split_once(|ch| matches!(ch, '%' | '#'))
-            .map(|(res, _)| res)
-        {
+        if let Some(trimmed) = s.split_once(['%', '#']).map(|(res, _)| res) {
             s.truncate(trimmed.len());
         }
 
@@ -64,7 +61,7 @@ impl<R: Read> LineParser<R> {
         loop {
             // EOF
             if self.read_line(&mut line)? == 0 {
-                return Ok(Kind::EOF);
+                return Ok(Kind::Eof);
             }
 
             // Empty/Comment line
@@ -37,12 +37,8 @@ fn find_next_longest_seq(norm: &mut Vec<u32>) -> Vec<CollateElement> {
     norm.drain(..s.len());
 
     let mut i = 0;
-    loop {
-        let Some(ch) = norm.get(i) else {
-            // String finished
-            break;
-        };
-
+    // Stops when the string is finished
+    while let Some(ch) = norm.get(i) {
         // U+00AD SOFT HYPHEN and U+034F COMBINING GRAPHEME JOINER create a blocking context
         // And break if ccc == 0
         // TODO: Not correct; check spec
@@ -90,10 +86,10 @@ pub fn get_collate_elements(s: &str) -> [Vec<u16>; 3] {
     weights
 }
 
-fn build_sort_key(mut weights: [Vec<u16>; 3]) -> Vec<u16> {
+fn build_sort_key(weights: [Vec<u16>; 3]) -> Vec<u16> {
     let mut sort_key = Vec::new();
-    for i in 0..=2 {
-        for w in &weights[i] {
+    for (i, level) in weights.iter().enumerate() {
+        for w in level {
             if *w != 0 {
                 sort_key.push(*w);
             }